@@ -0,0 +1,134 @@
+use codecrafters_grep::Regex;
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Compiles a string literal pattern while the crate using it builds,
+/// expanding to a `&'static codecrafters_grep::Regex`. A pattern that doesn't
+/// parse is a compile error pointing at the literal, and at runtime the
+/// regex is only loaded, on first use, from the compiled form the build left
+/// in the binary, the one `Regex::to_bytes` gives. Patterns are taken with
+/// the default options; inline flags like `(?i:...)` still apply.
+///
+/// ```
+/// use codecrafters_grep_macros::regex;
+///
+/// let date = regex!(r"(\d+)-(\d+)");
+/// let captures = date.captures("due 2024-10").unwrap();
+/// assert_eq!(captures.get(0).unwrap().as_str(), "2024-10");
+/// assert_eq!(captures.get(2).unwrap().as_str(), "10");
+/// ```
+///
+/// A pattern that doesn't parse doesn't build:
+///
+/// ```compile_fail
+/// let regex = codecrafters_grep_macros::regex!("(unclosed");
+/// ```
+///
+/// Nor does anything but a string literal:
+///
+/// ```compile_fail
+/// let regex = codecrafters_grep_macros::regex!(42);
+/// ```
+#[proc_macro]
+pub fn regex(input: TokenStream) -> TokenStream {
+    let literal = match single_literal(input) {
+        Ok(literal) => literal,
+        Err(span) => return compile_error("expected a string literal", span),
+    };
+    let Some(pattern) = unescape(&literal.to_string()) else {
+        return compile_error("expected a string literal", literal.span());
+    };
+    let regex = match Regex::new(&pattern) {
+        Ok(regex) => regex,
+        Err(err) => return compile_error(&err.to_string(), literal.span()),
+    };
+
+    format!(
+        "{{
+            static REGEX: ::std::sync::LazyLock<::codecrafters_grep::Regex> =
+                ::std::sync::LazyLock::new(|| {{
+                    ::codecrafters_grep::Regex::from_bytes({})
+                        .expect(\"regex! was compiled against a different codecrafters-grep\")
+                }});
+            &*REGEX
+        }}",
+        Literal::byte_string(&regex.to_bytes()),
+    )
+    .parse()
+    .unwrap()
+}
+
+/// Returns the only token of `input` if it's a literal, looking through the
+/// invisible groups `macro_rules!` wraps its fragments in, or else where the
+/// error should point.
+fn single_literal(input: TokenStream) -> Result<Literal, Span> {
+    let mut tokens = input.into_iter();
+    match (tokens.next(), tokens.next()) {
+        (Some(TokenTree::Literal(literal)), None) => Ok(literal),
+        (Some(TokenTree::Group(group)), None) if group.delimiter() == Delimiter::None => {
+            single_literal(group.stream())
+        }
+        (Some(token), _) => Err(token.span()),
+        (None, _) => Err(Span::call_site()),
+    }
+}
+
+/// Returns the value of a string literal as written in source, or `None`
+/// for any other kind of literal.
+fn unescape(source: &str) -> Option<String> {
+    if let Some(raw) = source.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let raw = &raw[hashes..raw.len().checked_sub(hashes)?];
+        return Some(raw.strip_prefix('"')?.strip_suffix('"')?.to_string());
+    }
+
+    let mut chars = source.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            '0' => value.push('\0'),
+            c @ ('\\' | '\'' | '"') => value.push(c),
+            'x' => {
+                let hex = chars.as_str().get(..2)?;
+                value.push(char::from(u8::from_str_radix(hex, 16).ok()?));
+                chars.nth(1);
+            }
+            'u' => {
+                let rest = chars.as_str().strip_prefix('{')?;
+                let (hex, rest) = rest.split_once('}')?;
+                let hex = hex.replace('_', "");
+                value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                chars = rest.chars();
+            }
+            '\n' => {
+                chars = chars
+                    .as_str()
+                    .trim_start_matches([' ', '\t', '\n', '\r'])
+                    .chars();
+            }
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let mut message = Literal::string(message);
+    message.set_span(span);
+    let mut bang = Punct::new('!', Spacing::Alone);
+    bang.set_span(span);
+    let mut group = Group::new(Delimiter::Parenthesis, TokenTree::from(message).into());
+    group.set_span(span);
+    let tokens: [TokenTree; 3] = [
+        Ident::new("compile_error", span).into(),
+        bang.into(),
+        group.into(),
+    ];
+    tokens.into_iter().collect()
+}
@@ -0,0 +1,353 @@
+use crate::pattern::{fold, Budget, State};
+use crate::prefilter::Prefilter;
+use crate::program::{Inst, Program};
+use crate::TooComplexError;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+
+enum Frame {
+    Explore {
+        pc: usize,
+        i: usize,
+    },
+    RestoreSlot {
+        slot: usize,
+        value: Option<usize>,
+    },
+    RestoreClosed {
+        id: usize,
+        value: Option<Range<usize>>,
+    },
+    PopHistory,
+}
+
+/// Where each referenced group starts and what it holds.
+type References = Vec<(Option<usize>, Option<Range<usize>>)>;
+
+/// The pc, the position, how many loops started their iteration there, and
+/// the referenced groups by their index in `reference_ids`.
+type Visit = (usize, usize, usize, usize);
+
+/// Backtracks over a `Program`, for the patterns with back references the
+/// Pike VM can't run. Each `Split` remembers the states it was reached in:
+/// the position, how many loops around it started their iteration there and
+/// where the referenced groups start and what they hold. Reaching one again
+/// can't turn out better than the first time, so it's dropped, which keeps
+/// the work polynomial in the input length. The groups are numbered the
+/// first time they're seen, so a visit allocates only for ones that are
+/// new.
+struct Backtracker<'p, 'a, 'c> {
+    program: &'p Program,
+    input: &'a str,
+    search_start: usize,
+    longest: bool,
+    budget: Budget,
+    trace: bool,
+    slots: &'c mut Vec<Option<usize>>,
+    closed: &'c mut Vec<Option<Range<usize>>>,
+    history: &'c mut Vec<(usize, Range<usize>)>,
+    stack: &'c mut Vec<Frame>,
+    visited: &'c mut HashSet<Visit>,
+    references: &'c mut References,
+    reference_ids: &'c mut HashMap<References, usize>,
+    folded: &'c mut Vec<char>,
+}
+
+/// The buffers of a search, kept between searches so that they only grow
+/// until they fit.
+#[derive(Default)]
+pub(crate) struct Cache {
+    slots: Vec<Option<usize>>,
+    closed: Vec<Option<Range<usize>>>,
+    history: Vec<(usize, Range<usize>)>,
+    stack: Vec<Frame>,
+    visited: HashSet<Visit>,
+    references: References,
+    reference_ids: HashMap<References, usize>,
+    folded: Vec<char>,
+}
+
+/// Leaves the match found in `state`. With `trace` each step is written to
+/// stderr.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn find_at(
+    program: &Program,
+    input: &str,
+    start: usize,
+    anchored: bool,
+    longest: bool,
+    prefilter: Option<&Prefilter>,
+    budget: Budget,
+    trace: bool,
+    cache: &mut Cache,
+    state: &mut State,
+) -> Result<bool, TooComplexError> {
+    cache.slots.clear();
+    cache.slots.resize(program.slot_count, None);
+    cache.closed.clear();
+    cache.closed.resize(program.group_count, None);
+    cache.visited.clear();
+    cache.reference_ids.clear();
+    let mut backtracker = Backtracker {
+        program,
+        input,
+        search_start: start,
+        longest,
+        budget,
+        trace,
+        slots: &mut cache.slots,
+        closed: &mut cache.closed,
+        history: &mut cache.history,
+        stack: &mut cache.stack,
+        visited: &mut cache.visited,
+        references: &mut cache.references,
+        reference_ids: &mut cache.reference_ids,
+        folded: &mut cache.folded,
+    };
+
+    let mut i = start;
+    loop {
+        if let Some(prefilter) = prefilter {
+            let Some(next) = prefilter.find(input, i) else {
+                return Ok(false);
+            };
+            i = next;
+        }
+
+        if input.len() - i < program.min_len {
+            return Ok(false);
+        }
+
+        if trace {
+            eprintln!("trace: searching from {i}");
+        }
+        if backtracker.run(i, state)? {
+            return Ok(true);
+        }
+
+        let Some(c) = input[i..].chars().next().filter(|_| !anchored) else {
+            return Ok(false);
+        };
+        i += c.len_utf8();
+    }
+}
+
+impl<'p, 'a, 'c> Backtracker<'p, 'a, 'c> {
+    fn run(&mut self, start: usize, state: &mut State) -> Result<bool, TooComplexError> {
+        self.slots.fill(None);
+        self.closed.fill(None);
+        self.history.clear();
+        self.stack.clear();
+        self.stack.push(Frame::Explore { pc: 0, i: start });
+
+        let mut best = None;
+        let mut resumed = false;
+        while let Some(frame) = self.stack.pop() {
+            match frame {
+                Frame::Explore { pc, i } => match self.explore(pc, i, resumed) {
+                    Some(end) => {
+                        if !self.longest {
+                            self.save(state);
+                            return Ok(true);
+                        }
+                        if best.map_or(true, |best| end > best) {
+                            best = Some(end);
+                            self.save(state);
+                        }
+                        resumed = true;
+                    }
+                    None => {
+                        resumed = true;
+                        if let Some(err) = self.budget.exceeded() {
+                            return Err(err);
+                        }
+                    }
+                },
+                Frame::RestoreSlot { slot, value } => self.slots[slot] = value,
+                Frame::RestoreClosed { id, value } => self.closed[id] = value,
+                Frame::PopHistory => {
+                    self.history.pop();
+                }
+            }
+        }
+
+        Ok(best.is_some())
+    }
+
+    /// Follows the preferred path from `pc`, leaving the others on the
+    /// stack, and returns where it reached `Match`. `resumed` says it's a
+    /// path left on the stack, for tracing.
+    fn explore(&mut self, mut pc: usize, mut i: usize, resumed: bool) -> Option<usize> {
+        if self.trace && resumed {
+            eprintln!("trace: backtracking to {pc} at {i}");
+        }
+        loop {
+            if !self.budget.step() {
+                return None;
+            }
+
+            match &self.program.insts[pc] {
+                Inst::Match => {
+                    self.trace(pc, i, "a match");
+                    return Some(i);
+                }
+                Inst::Jump(target) => pc = *target,
+                Inst::Split(first, second) => {
+                    if !self.visit(pc, i) {
+                        self.trace(pc, i, "already tried from here");
+                        return None;
+                    }
+                    self.trace(pc, i, format_args!("trying {first}, then {second}"));
+                    self.stack.push(Frame::Explore { pc: *second, i });
+                    pc = *first;
+                }
+                Inst::Save(slot) => {
+                    self.stack.push(Frame::RestoreSlot {
+                        slot: *slot,
+                        value: self.slots[*slot],
+                    });
+                    self.slots[*slot] = Some(i);
+
+                    if slot % 2 == 1 && *slot < self.program.group_count * 2 {
+                        if let Some(start) = self.slots[slot - 1] {
+                            let id = slot / 2;
+                            self.stack.push(Frame::RestoreClosed {
+                                id,
+                                value: self.closed[id].replace(start..i),
+                            });
+                            self.history.push((id, start..i));
+                            self.stack.push(Frame::PopHistory);
+                        }
+                    }
+                    pc += 1;
+                }
+                Inst::Progress { slot, exit } => {
+                    if self.slots[*slot] == Some(i) {
+                        self.trace(pc, i, "an empty iteration, leaving the loop");
+                        pc = *exit;
+                    } else {
+                        pc += 1;
+                    }
+                }
+                Inst::Assert(assertion) => {
+                    if !assertion.holds(self.input, i, self.search_start) {
+                        self.trace(pc, i, "fails");
+                        return None;
+                    }
+                    self.trace(pc, i, "holds");
+                    pc += 1;
+                }
+                Inst::Reference(id) => {
+                    let Some(group) = self.closed[*id].clone() else {
+                        self.trace(pc, i, "fails on an unset group");
+                        return None;
+                    };
+                    let content = &self.input[group];
+                    if !self.input[i..].starts_with(content) {
+                        self.trace(pc, i, format_args!("fails to find {content:?}"));
+                        return None;
+                    }
+                    self.trace(pc, i, format_args!("consumes {content:?}"));
+                    i += content.len();
+                    pc += 1;
+                }
+                Inst::FoldedReference(id) => {
+                    let Some(group) = self.closed[*id].clone() else {
+                        self.trace(pc, i, "fails on an unset group");
+                        return None;
+                    };
+                    self.folded.clear();
+                    self.folded
+                        .extend(self.input[group.clone()].chars().flat_map(fold));
+
+                    let start = i;
+                    let mut matched = 0;
+                    let mut chars = self.input[i..].chars();
+                    let found = 'compare: {
+                        while matched < self.folded.len() {
+                            let Some(c) = chars.next() else {
+                                break 'compare false;
+                            };
+                            for c in fold(c) {
+                                if self.folded.get(matched) != Some(&c) {
+                                    break 'compare false;
+                                }
+                                matched += 1;
+                            }
+                            i += c.len_utf8();
+                        }
+                        true
+                    };
+                    if !found {
+                        let content = &self.input[group];
+                        self.trace(pc, start, format_args!("fails to find {content:?}"));
+                        return None;
+                    }
+                    self.trace(
+                        pc,
+                        start,
+                        format_args!("consumes {:?}", &self.input[start..i]),
+                    );
+                    pc += 1;
+                }
+                inst => {
+                    let Some(c) = self.input[i..].chars().next() else {
+                        self.trace(pc, i, "fails at the end");
+                        return None;
+                    };
+                    if !inst.matches_char(c) {
+                        self.trace(pc, i, format_args!("fails on {c:?}"));
+                        return None;
+                    }
+                    self.trace(pc, i, format_args!("consumes {c:?}"));
+                    i += c.len_utf8();
+                    pc += 1;
+                }
+            }
+        }
+    }
+
+    /// Under `trace`, writes what came of the instruction at `pc` at `i`.
+    fn trace(&self, pc: usize, i: usize, outcome: impl fmt::Display) {
+        if self.trace {
+            eprintln!(
+                "trace: {i:5} {pc:4}  {:?}: {outcome}",
+                self.program.insts[pc]
+            );
+        }
+    }
+
+    fn visit(&mut self, pc: usize, i: usize) -> bool {
+        let empty_loops = self.program.loops[pc]
+            .iter()
+            .take_while(|&&slot| self.slots[slot] == Some(i))
+            .count();
+        self.references.clear();
+        self.references.extend(
+            self.program
+                .references
+                .iter()
+                .map(|&id| (self.slots[id * 2], self.closed[id].clone())),
+        );
+        let references = match self.reference_ids.get(self.references.as_slice()) {
+            Some(&id) => id,
+            None => {
+                let id = self.reference_ids.len();
+                self.reference_ids.insert(self.references.clone(), id);
+                id
+            }
+        };
+        self.visited.insert((pc, i, empty_loops, references))
+    }
+
+    fn save(&self, state: &mut State) {
+        state.reset(self.program.group_count);
+        for (id, group) in state.groups.iter_mut().enumerate() {
+            if let (Some(start), Some(end)) = (self.slots[id * 2], self.slots[id * 2 + 1]) {
+                *group = Some(start..end);
+            }
+        }
+        state.history.clone_from(self.history);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::walk::{Walk, WalkOptions};
+use anyhow::Context;
+use codecrafters_grep::Regex;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub(crate) const DEFAULT_RUNS: usize = 10;
+
+/// Searches every file under `dir` line by line, `runs` times over, and
+/// prints how long compiling, reading and searching took. The files are
+/// read once up front so the search timings don't include the disk. Like
+/// `-r`, symbolic links are left out, but hidden and ignored files aren't.
+pub(crate) fn run(
+    regex: &Regex,
+    compile_time: Duration,
+    dir: &Path,
+    runs: usize,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let options = WalkOptions {
+        no_ignore: true,
+        hidden: true,
+        ..WalkOptions::default()
+    };
+    let files = Walk::new(dir.to_path_buf(), options)
+        .map(|path| {
+            let path = path?;
+            fs::read(&path).with_context(|| format!("reading {}", path.display()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let read_time = start.elapsed();
+    let bytes = files.iter().map(Vec::len).sum::<usize>();
+
+    let mut times = Vec::with_capacity(runs);
+    let mut matched = 0;
+    for _ in 0..runs {
+        let start = Instant::now();
+        matched = 0;
+        for file in files.iter().filter(|file| !file.is_empty()) {
+            let file = file.strip_suffix(b"\n").unwrap_or(file);
+            for line in file.split(|&b| b == b'\n') {
+                if regex.try_is_match_bytes(line)? {
+                    matched += 1;
+                }
+            }
+        }
+        times.push(start.elapsed());
+    }
+    times.sort();
+
+    println!("compile: {compile_time:?}");
+    println!(
+        "read:    {read_time:?} ({} files, {:.1} MB)",
+        files.len(),
+        megabytes(bytes)
+    );
+    if let Some(&median) = times.get(runs / 2) {
+        println!(
+            "search:  min {:?}, median {median:?} over {runs} runs, {:.1} MB/s, {matched} matching lines",
+            times[0],
+            megabytes(bytes) / median.as_secs_f64(),
+        );
+    }
+    Ok(())
+}
+
+fn megabytes(bytes: usize) -> f64 {
+    bytes as f64 / 1e6
+}
@@ -0,0 +1,297 @@
+use crate::{PoolGuard, Regex, RegexSet, Scratch, SetMatches, TooComplexError};
+use std::borrow::Cow;
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Match<'h> {
+    haystack: &'h [u8],
+    start: usize,
+    end: usize,
+}
+
+impl<'h> Match<'h> {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn as_bytes(&self) -> &'h [u8] {
+        &self.haystack[self.range()]
+    }
+}
+
+pub struct Captures<'h> {
+    haystack: &'h [u8],
+    groups: Vec<Option<Range<usize>>>,
+}
+
+impl<'h> Captures<'h> {
+    pub fn get(&self, i: usize) -> Option<Match<'h>> {
+        self.groups.get(i).cloned().flatten().map(|range| Match {
+            haystack: self.haystack,
+            start: range.start,
+            end: range.end,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+impl Regex {
+    pub fn is_match_bytes(&self, input: &[u8]) -> bool {
+        self.is_match(&Text::decode(input).decoded)
+    }
+
+    pub fn try_is_match_bytes(&self, input: &[u8]) -> Result<bool, TooComplexError> {
+        self.try_is_match(&Text::decode(input).decoded)
+    }
+
+    pub fn find_bytes<'h>(&self, input: &'h [u8]) -> Option<Match<'h>> {
+        self.try_find_bytes(input)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_find_bytes<'h>(
+        &self,
+        input: &'h [u8],
+    ) -> Result<Option<Match<'h>>, TooComplexError> {
+        let text = Text::decode(input);
+        let found = self.try_find_at(&text.decoded, 0, false, &mut self.scratch.get())?;
+        Ok(found.map(|range| Match {
+            haystack: input,
+            start: text.offset(range.start),
+            end: text.offset(range.end),
+        }))
+    }
+
+    /// Like `find_iter`, but a search that gives up is an error, after
+    /// which there are no more matches.
+    pub fn try_find_iter_bytes<'r, 'h>(&'r self, input: &'h [u8]) -> TryFindMatches<'r, 'h> {
+        TryFindMatches {
+            regex: self,
+            haystack: input,
+            text: Text::decode(input),
+            start: Some(0),
+            last_end: None,
+            scratch: self.scratch.get(),
+        }
+    }
+
+    pub fn captures_bytes<'h>(&self, input: &'h [u8]) -> Option<Captures<'h>> {
+        self.try_captures_bytes(input)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_captures_bytes<'h>(
+        &self,
+        input: &'h [u8],
+    ) -> Result<Option<Captures<'h>>, TooComplexError> {
+        let text = Text::decode(input);
+        let mut scratch = self.scratch.get();
+        let found = self.try_find_at(&text.decoded, 0, true, &mut scratch)?;
+        Ok(found.map(|_| text.captures(input, &scratch)))
+    }
+
+    /// Like `captures_iter`, but a search that gives up is an error, after
+    /// which there are no more matches.
+    pub fn try_captures_iter_bytes<'r, 'h>(&'r self, input: &'h [u8]) -> TryCaptureMatches<'r, 'h> {
+        TryCaptureMatches {
+            regex: self,
+            haystack: input,
+            text: Text::decode(input),
+            start: Some(0),
+            last_end: None,
+            scratch: self.scratch.get(),
+        }
+    }
+}
+
+pub struct TryFindMatches<'r, 'h> {
+    regex: &'r Regex,
+    haystack: &'h [u8],
+    text: Text<'h>,
+    start: Option<usize>,
+    last_end: Option<usize>,
+    scratch: PoolGuard<'r, Scratch>,
+}
+
+impl<'r, 'h> Iterator for TryFindMatches<'r, 'h> {
+    type Item = Result<Match<'h>, TooComplexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let found = self.regex.try_find_next(
+            &self.text.decoded,
+            self.start?,
+            self.last_end,
+            false,
+            &mut self.scratch,
+        );
+        match found {
+            Ok(Some(range)) => {
+                self.last_end = Some(range.end);
+                self.start = Some(range.end);
+                Some(Ok(Match {
+                    haystack: self.haystack,
+                    start: self.text.offset(range.start),
+                    end: self.text.offset(range.end),
+                }))
+            }
+            Ok(None) => {
+                self.start = None;
+                None
+            }
+            Err(err) => {
+                self.start = None;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+pub struct TryCaptureMatches<'r, 'h> {
+    regex: &'r Regex,
+    haystack: &'h [u8],
+    text: Text<'h>,
+    start: Option<usize>,
+    last_end: Option<usize>,
+    scratch: PoolGuard<'r, Scratch>,
+}
+
+impl<'r, 'h> Iterator for TryCaptureMatches<'r, 'h> {
+    type Item = Result<Captures<'h>, TooComplexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let found = self.regex.try_find_next(
+            &self.text.decoded,
+            self.start?,
+            self.last_end,
+            true,
+            &mut self.scratch,
+        );
+        match found {
+            Ok(Some(range)) => {
+                self.last_end = Some(range.end);
+                self.start = Some(range.end);
+                Some(Ok(self.text.captures(self.haystack, &self.scratch)))
+            }
+            Ok(None) => {
+                self.start = None;
+                None
+            }
+            Err(err) => {
+                self.start = None;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl RegexSet {
+    pub fn is_match_bytes(&self, input: &[u8]) -> bool {
+        self.is_match(&Text::decode(input).decoded)
+    }
+
+    pub fn try_is_match_bytes(&self, input: &[u8]) -> Result<bool, TooComplexError> {
+        self.try_is_match(&Text::decode(input).decoded)
+    }
+
+    pub fn matches_bytes(&self, input: &[u8]) -> SetMatches {
+        self.matches(&Text::decode(input).decoded)
+    }
+
+    pub fn try_matches_bytes(&self, input: &[u8]) -> Result<SetMatches, TooComplexError> {
+        self.try_matches(&Text::decode(input).decoded)
+    }
+}
+
+struct Text<'h> {
+    decoded: Cow<'h, str>,
+    offsets: Vec<usize>,
+}
+
+impl<'h> Text<'h> {
+    fn decode(input: &'h [u8]) -> Self {
+        if let Ok(decoded) = std::str::from_utf8(input) {
+            return Text {
+                decoded: Cow::Borrowed(decoded),
+                offsets: Vec::new(),
+            };
+        }
+
+        let mut decoded = String::new();
+        let mut offsets = Vec::new();
+
+        let mut rest = input;
+        while !rest.is_empty() {
+            let (valid, invalid) = match std::str::from_utf8(rest) {
+                Ok(valid) => (valid, 0),
+                Err(err) => (
+                    std::str::from_utf8(&rest[..err.valid_up_to()]).unwrap(),
+                    err.error_len().unwrap_or(rest.len() - err.valid_up_to()),
+                ),
+            };
+
+            let base = input.len() - rest.len();
+            for (i, c) in valid.char_indices() {
+                offsets.resize(decoded.len(), 0);
+                offsets.push(base + i);
+                decoded.push(c);
+            }
+
+            for i in 0..invalid {
+                let b = rest[valid.len() + i];
+                offsets.resize(decoded.len(), 0);
+                offsets.push(base + valid.len() + i);
+                decoded.push(char::from(b));
+            }
+
+            rest = &rest[valid.len() + invalid..];
+        }
+
+        offsets.resize(decoded.len(), 0);
+        offsets.push(input.len());
+
+        Text {
+            decoded: Cow::Owned(decoded),
+            offsets,
+        }
+    }
+
+    fn offset(&self, i: usize) -> usize {
+        if self.offsets.is_empty() {
+            i
+        } else {
+            self.offsets[i]
+        }
+    }
+
+    /// The groups of the match just found in `haystack`, which this decodes.
+    fn captures(&self, haystack: &'h [u8], scratch: &Scratch) -> Captures<'h> {
+        Captures {
+            haystack,
+            groups: scratch
+                .state
+                .groups
+                .iter()
+                .map(|range| {
+                    range
+                        .as_ref()
+                        .map(|range| self.offset(range.start)..self.offset(range.end))
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+/// A set of bytes to scan for: some ASCII bytes and, optionally, every byte
+/// that starts a multi-byte char. Membership of an ASCII byte `b` is
+/// `low[b & 0xF] & high[b >> 4] != 0`, which SIMD shuffles look up for a
+/// whole vector of bytes at once.
+pub(crate) struct ByteSet {
+    ascii: [bool; 128],
+    non_ascii: bool,
+    low: [u8; 16],
+    high: [u8; 16],
+}
+
+impl ByteSet {
+    pub(crate) fn new(ascii: [bool; 128], non_ascii: bool) -> Self {
+        let mut low = [0; 16];
+        let mut high = [0; 16];
+        for (b, _) in ascii.iter().enumerate().filter(|(_, &member)| member) {
+            low[b & 0xF] |= 1 << (b >> 4);
+        }
+        for (h, bits) in high.iter_mut().enumerate().take(8) {
+            *bits = 1 << h;
+        }
+
+        ByteSet {
+            ascii,
+            non_ascii,
+            low,
+            high,
+        }
+    }
+
+    pub(crate) fn find(&self, haystack: &[u8]) -> Option<usize> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                // SAFETY: the CPU supports AVX2.
+                return unsafe { self.find_avx2(haystack) };
+            }
+            if is_x86_feature_detected!("ssse3") {
+                // SAFETY: the CPU supports SSSE3.
+                return unsafe { self.find_ssse3(haystack) };
+            }
+        }
+
+        self.find_scalar(haystack)
+    }
+
+    fn find_scalar(&self, haystack: &[u8]) -> Option<usize> {
+        haystack.iter().position(|&b| {
+            if b.is_ascii() {
+                self.ascii[b as usize]
+            } else {
+                self.non_ascii && b >= 0xC0
+            }
+        })
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    unsafe fn find_avx2(&self, haystack: &[u8]) -> Option<usize> {
+        use std::arch::x86_64::*;
+
+        let low = _mm256_broadcastsi128_si256(_mm_loadu_si128(self.low.as_ptr().cast()));
+        let high = _mm256_broadcastsi128_si256(_mm_loadu_si128(self.high.as_ptr().cast()));
+        let nibble = _mm256_set1_epi8(0xF);
+        let zero = _mm256_setzero_si256();
+        let below_lead = _mm256_set1_epi8(0xBFu8 as i8);
+
+        let mut i = 0;
+        while i + 32 <= haystack.len() {
+            let bytes = _mm256_loadu_si256(haystack.as_ptr().add(i).cast());
+            let low_bits = _mm256_shuffle_epi8(low, _mm256_and_si256(bytes, nibble));
+            let high_bits =
+                _mm256_shuffle_epi8(high, _mm256_and_si256(_mm256_srli_epi16(bytes, 4), nibble));
+            let outside = _mm256_cmpeq_epi8(_mm256_and_si256(low_bits, high_bits), zero);
+            let mut found = !(_mm256_movemask_epi8(outside) as u32);
+            if self.non_ascii {
+                // Signed, the bytes from 0xC0 are the ones above 0xBF and
+                // below zero.
+                let lead = _mm256_and_si256(
+                    _mm256_cmpgt_epi8(bytes, below_lead),
+                    _mm256_cmpgt_epi8(zero, bytes),
+                );
+                found |= _mm256_movemask_epi8(lead) as u32;
+            }
+            if found != 0 {
+                return Some(i + found.trailing_zeros() as usize);
+            }
+            i += 32;
+        }
+
+        self.find_scalar(&haystack[i..]).map(|j| i + j)
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "ssse3")]
+    unsafe fn find_ssse3(&self, haystack: &[u8]) -> Option<usize> {
+        use std::arch::x86_64::*;
+
+        let low = _mm_loadu_si128(self.low.as_ptr().cast());
+        let high = _mm_loadu_si128(self.high.as_ptr().cast());
+        let nibble = _mm_set1_epi8(0xF);
+        let zero = _mm_setzero_si128();
+        let below_lead = _mm_set1_epi8(0xBFu8 as i8);
+
+        let mut i = 0;
+        while i + 16 <= haystack.len() {
+            let bytes = _mm_loadu_si128(haystack.as_ptr().add(i).cast());
+            let low_bits = _mm_shuffle_epi8(low, _mm_and_si128(bytes, nibble));
+            let high_bits = _mm_shuffle_epi8(high, _mm_and_si128(_mm_srli_epi16(bytes, 4), nibble));
+            let outside = _mm_cmpeq_epi8(_mm_and_si128(low_bits, high_bits), zero);
+            let mut found = !_mm_movemask_epi8(outside) as u32 & 0xFFFF;
+            if self.non_ascii {
+                let lead = _mm_and_si128(
+                    _mm_cmpgt_epi8(bytes, below_lead),
+                    _mm_cmpgt_epi8(zero, bytes),
+                );
+                found |= _mm_movemask_epi8(lead) as u32;
+            }
+            if found != 0 {
+                return Some(i + found.trailing_zeros() as usize);
+            }
+            i += 16;
+        }
+
+        self.find_scalar(&haystack[i..]).map(|j| i + j)
+    }
+}
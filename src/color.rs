@@ -0,0 +1,108 @@
+use std::io::{self, Write};
+
+/// The SGR parameters `--color` starts each part of the output with, as set
+/// by `GREP_COLORS` the way GNU grep reads it: `name=value` pairs split by
+/// colons, with the same names and defaults. An empty value leaves the part
+/// as it is.
+pub(crate) struct Colors {
+    /// The matches in a selected line.
+    pub(crate) selected_match: String,
+    /// The matches in a context line.
+    pub(crate) context_match: String,
+    /// The rest of a selected line.
+    pub(crate) selected_line: String,
+    /// The rest of a context line.
+    pub(crate) context_line: String,
+    pub(crate) filename: String,
+    pub(crate) line_number: String,
+    pub(crate) byte_offset: String,
+    /// The colons and dashes after names and numbers, and the line between
+    /// groups of context.
+    pub(crate) separator: String,
+    /// Follows each sequence with one to clear the rest of the line, so that
+    /// a background color doesn't run on past the text when the terminal
+    /// scrolls. `ne` turns it off.
+    erase: bool,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Colors {
+            selected_match: String::from("01;31"),
+            context_match: String::from("01;31"),
+            selected_line: String::new(),
+            context_line: String::new(),
+            filename: String::from("35"),
+            line_number: String::from("32"),
+            byte_offset: String::from("32"),
+            separator: String::from("36"),
+            erase: true,
+        }
+    }
+}
+
+impl Colors {
+    /// Reads a `GREP_COLORS` value over the defaults, passing over names it
+    /// doesn't know like GNU grep does. `rv` swaps the colors of selected and
+    /// context lines when `invert` is set, since with `-v` it's the context
+    /// lines that match.
+    pub(crate) fn parse(spec: &str, invert: bool) -> Self {
+        let mut colors = Colors::default();
+        let mut reverse = false;
+        for capability in spec.split(':') {
+            let (name, value) = capability.split_once('=').unwrap_or((capability, ""));
+            let value = value.to_string();
+            match name {
+                "mt" => {
+                    colors.selected_match = value.clone();
+                    colors.context_match = value;
+                }
+                "ms" => colors.selected_match = value,
+                "mc" => colors.context_match = value,
+                "sl" => colors.selected_line = value,
+                "cx" => colors.context_line = value,
+                "fn" => colors.filename = value,
+                "ln" => colors.line_number = value,
+                "bn" => colors.byte_offset = value,
+                "se" => colors.separator = value,
+                "rv" => reverse = true,
+                "ne" => colors.erase = false,
+                _ => {}
+            }
+        }
+        if reverse && invert {
+            std::mem::swap(&mut colors.selected_line, &mut colors.context_line);
+        }
+        colors
+    }
+
+    /// Writes `text` in the color `sgr`.
+    pub(crate) fn paint(&self, sgr: &str, text: &[u8], out: &mut impl Write) -> io::Result<()> {
+        self.start(sgr, out)?;
+        out.write_all(text)?;
+        self.end(sgr, out)
+    }
+
+    pub(crate) fn start(&self, sgr: &str, out: &mut impl Write) -> io::Result<()> {
+        if !sgr.is_empty() {
+            write!(out, "\x1b[{sgr}m")?;
+            self.erase(out)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn end(&self, sgr: &str, out: &mut impl Write) -> io::Result<()> {
+        if !sgr.is_empty() {
+            out.write_all(b"\x1b[m")?;
+            self.erase(out)?;
+        }
+        Ok(())
+    }
+
+    fn erase(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.erase {
+            out.write_all(b"\x1b[K")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,357 @@
+use crate::pattern::is_word_char;
+use crate::pool::{Pool, PoolGuard};
+use crate::prefilter::Prefilter;
+use crate::program::{Assertion, Inst, Program};
+use std::collections::HashMap;
+use std::mem;
+
+/// The default for `RegexBuilder::dfa_size_limit`, which fits a few
+/// thousand states.
+pub(crate) const DEFAULT_SIZE_LIMIT: usize = 4 << 20;
+
+/// How many bytes of input each state has to have been used for, on
+/// average, for clearing a full cache to be worth it over handing the
+/// search to the NFA.
+const MIN_BYTES_PER_STATE: usize = 10;
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+    struct Context: u8 {
+        const START = 1 << 0;
+        const WORD = 1 << 1;
+        const ASCII_WORD = 1 << 2;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Transition {
+    State(u32),
+    Match,
+    Dead,
+}
+
+struct State {
+    pcs: Vec<usize>,
+    context: Context,
+    ascii: [Option<Transition>; 128],
+    other: HashMap<char, Transition>,
+    end: Option<Transition>,
+}
+
+#[derive(Default)]
+struct Cache {
+    states: Vec<State>,
+    ids: HashMap<(Vec<usize>, Context), u32>,
+    /// Roughly how many bytes the states take up.
+    memory: usize,
+    /// How many bytes of input have been searched since the cache was last
+    /// cleared.
+    searched: usize,
+}
+
+/// A DFA built lazily from a `Program` while answering `is_match`. Each
+/// state is the set of instructions the NFA threads wait on, plus what the
+/// assertions need to know about the previous char; the step from a state
+/// on a char is computed the first time it's taken and cached from then on,
+/// in a cache from the pool so that threads searching at the same time each
+/// build their own. A cache that grows past the size limit is cleared and
+/// built up again, unless it only filled up on little input, when the
+/// search falls back to the NFA instead.
+pub(crate) struct Dfa {
+    anchored: bool,
+    size_limit: usize,
+    caches: Pool<Cache>,
+}
+
+impl Dfa {
+    /// Returns `None` if the program has back references or an assertion
+    /// the DFA can't decide from one char of lookahead.
+    pub(crate) fn new(program: &Program, anchored: bool, size_limit: usize) -> Option<Self> {
+        if !program.references.is_empty()
+            || program
+                .insts
+                .contains(&Inst::Assert(Assertion::End { crlf: true }))
+        {
+            return None;
+        }
+
+        Some(Dfa {
+            anchored,
+            size_limit,
+            caches: Pool::default(),
+        })
+    }
+
+    /// Returns `None` if the cache kept filling up, in which case the caller
+    /// should fall back to the NFA.
+    pub(crate) fn is_match(
+        &self,
+        program: &Program,
+        input: &str,
+        prefilter: Option<&Prefilter>,
+    ) -> Option<bool> {
+        self.search(program, &mut self.caches.get(), input, prefilter)
+    }
+
+    fn search(
+        &self,
+        program: &Program,
+        cache: &mut Cache,
+        input: &str,
+        prefilter: Option<&Prefilter>,
+    ) -> Option<bool> {
+        let mut state = cache.intern(vec![], Context::START);
+        let mut i = 0;
+        loop {
+            if let Some(prefilter) = prefilter {
+                if cache.states[state as usize].pcs.is_empty() {
+                    let Some(next) = prefilter.find(input, i) else {
+                        return Some(false);
+                    };
+                    if next > i {
+                        i = next;
+                        let context = input[..i].chars().next_back().map_or(Context::START, after);
+                        state = cache.intern(vec![], context);
+                    }
+                }
+            }
+
+            let Some(c) = input[i..].chars().next() else {
+                break;
+            };
+            i += c.len_utf8();
+            cache.searched += c.len_utf8();
+
+            match self.next(program, cache, state, Some(c)) {
+                Transition::State(next) => state = next,
+                Transition::Match => return Some(true),
+                Transition::Dead => return Some(false),
+            }
+
+            if cache.memory > self.size_limit {
+                if cache.searched < cache.states.len() * MIN_BYTES_PER_STATE {
+                    *cache = Cache::default();
+                    return None;
+                }
+                state = cache.restart(state);
+            }
+        }
+
+        Some(matches!(
+            self.next(program, cache, state, None),
+            Transition::Match
+        ))
+    }
+
+    /// Starts a search that's fed its input in pieces.
+    pub(crate) fn scan(&self) -> Scan<'_> {
+        let mut cache = self.caches.get();
+        let state = cache.intern(vec![], Context::START);
+        Scan {
+            dfa: self,
+            cache,
+            state,
+            matched: None,
+        }
+    }
+
+    /// Follows the transition from `state` on `c`, or on the end of the input
+    /// for `None`, computing it if it hasn't been taken before.
+    fn next(
+        &self,
+        program: &Program,
+        cache: &mut Cache,
+        state: u32,
+        c: Option<char>,
+    ) -> Transition {
+        let known = {
+            let state = &cache.states[state as usize];
+            match c {
+                Some(c) if c.is_ascii() => state.ascii[c as usize],
+                Some(c) => state.other.get(&c).copied(),
+                None => state.end,
+            }
+        };
+        if let Some(transition) = known {
+            return transition;
+        }
+
+        let transition = self.step(program, cache, state, c);
+        let state = &mut cache.states[state as usize];
+        match c {
+            Some(c) if c.is_ascii() => state.ascii[c as usize] = Some(transition),
+            Some(c) => {
+                state.other.insert(c, transition);
+                cache.memory += mem::size_of::<(char, Transition)>();
+            }
+            None => state.end = Some(transition),
+        }
+        transition
+    }
+
+    fn step(&self, program: &Program, cache: &mut Cache, id: u32, c: Option<char>) -> Transition {
+        let state = &cache.states[id as usize];
+        let context = state.context;
+
+        let mut stack = state.pcs.clone();
+        if !self.anchored || context.contains(Context::START) {
+            stack.push(0);
+        }
+
+        let mut seen = vec![false; program.insts.len()];
+        let mut waiting = Vec::new();
+        while let Some(pc) = stack.pop() {
+            if seen[pc] {
+                continue;
+            }
+            seen[pc] = true;
+
+            match &program.insts[pc] {
+                Inst::Jump(target) => stack.push(*target),
+                Inst::Split(first, second) => {
+                    stack.push(*second);
+                    stack.push(*first);
+                }
+                Inst::Save(_) => stack.push(pc + 1),
+                Inst::Progress { exit, .. } => {
+                    stack.push(*exit);
+                    stack.push(pc + 1);
+                }
+                Inst::Assert(assertion) => {
+                    if holds(assertion, context, c) {
+                        stack.push(pc + 1);
+                    }
+                }
+                Inst::Match => return Transition::Match,
+                _ => waiting.push(pc),
+            }
+        }
+
+        let Some(c) = c else {
+            return Transition::Dead;
+        };
+
+        let mut pcs = waiting
+            .into_iter()
+            .filter(|&pc| program.insts[pc].matches_char(c))
+            .map(|pc| pc + 1)
+            .collect::<Vec<_>>();
+        if pcs.is_empty() && self.anchored {
+            return Transition::Dead;
+        }
+        pcs.sort_unstable();
+
+        Transition::State(cache.intern(pcs, after(c)))
+    }
+}
+
+/// A DFA search in progress over input that arrives in pieces. Unlike
+/// `Dfa::is_match` it can't hand the input over to the NFA when the cache
+/// fills up, so it always clears the cache and carries on.
+/// Literal prefilters can't see a literal split between two pieces and
+/// aren't used.
+pub(crate) struct Scan<'d> {
+    dfa: &'d Dfa,
+    cache: PoolGuard<'d, Cache>,
+    state: u32,
+    matched: Option<bool>,
+}
+
+impl Scan<'_> {
+    /// Returns whether the input up to here decides the search.
+    pub(crate) fn feed(&mut self, program: &Program, text: &str) -> Option<bool> {
+        if self.matched.is_some() {
+            return self.matched;
+        }
+
+        for c in text.chars() {
+            match self.dfa.next(program, &mut self.cache, self.state, Some(c)) {
+                Transition::State(next) => self.state = next,
+                Transition::Match => self.matched = Some(true),
+                Transition::Dead => self.matched = Some(false),
+            }
+            if self.matched.is_some() {
+                break;
+            }
+
+            if self.cache.memory > self.dfa.size_limit {
+                self.state = self.cache.restart(self.state);
+            }
+        }
+        self.matched
+    }
+
+    pub(crate) fn finish(&mut self, program: &Program) -> bool {
+        *self.matched.get_or_insert_with(|| {
+            matches!(
+                self.dfa.next(program, &mut self.cache, self.state, None),
+                Transition::Match
+            )
+        })
+    }
+}
+
+impl Cache {
+    fn intern(&mut self, pcs: Vec<usize>, context: Context) -> u32 {
+        if let Some(&id) = self.ids.get(&(pcs.clone(), context)) {
+            return id;
+        }
+
+        let id = self.states.len() as u32;
+        self.states.push(State {
+            pcs: pcs.clone(),
+            context,
+            ascii: [None; 128],
+            other: HashMap::new(),
+            end: None,
+        });
+        self.memory += mem::size_of::<State>()
+            + mem::size_of::<((Vec<usize>, Context), u32)>()
+            + 2 * pcs.len() * mem::size_of::<usize>();
+        self.ids.insert((pcs, context), id);
+        id
+    }
+
+    /// Clears the cache, keeping only `state`, and returns its new id.
+    fn restart(&mut self, state: u32) -> u32 {
+        let state = &self.states[state as usize];
+        let (pcs, context) = (state.pcs.clone(), state.context);
+        *self = Cache::default();
+        self.intern(pcs, context)
+    }
+}
+
+fn after(c: char) -> Context {
+    let mut context = Context::empty();
+    context.set(Context::WORD, is_word_char(c, true));
+    context.set(Context::ASCII_WORD, is_word_char(c, false));
+    context
+}
+
+fn holds(assertion: &Assertion, context: Context, next: Option<char>) -> bool {
+    match assertion {
+        Assertion::Start | Assertion::Continue => context.contains(Context::START),
+        Assertion::End { .. } => next.is_none(),
+        Assertion::WordBoundary { unicode, positive } => {
+            let before = context.contains(if *unicode {
+                Context::WORD
+            } else {
+                Context::ASCII_WORD
+            });
+            let after = next.is_some_and(|c| is_word_char(c, *unicode));
+            !*positive ^ (before != after)
+        }
+        Assertion::NoWordChar {
+            unicode,
+            before: true,
+        } => !context.contains(if *unicode {
+            Context::WORD
+        } else {
+            Context::ASCII_WORD
+        }),
+        Assertion::NoWordChar {
+            unicode,
+            before: false,
+        } => !next.is_some_and(|c| is_word_char(c, *unicode)),
+    }
+}
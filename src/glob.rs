@@ -0,0 +1,185 @@
+/// A shell wildcard pattern for file names, as `--include` and the like
+/// take: `*` matches any run of chars, `?` any one char and `[...]` any char
+/// in the set, which `!` or `^` first negates. A backslash makes the char
+/// after it match only itself, and a `[` with no `]` after it is a char like
+/// any other.
+///
+/// Paths with `/` between their names match too, the way `.gitignore` has
+/// it: none of those match a `/`, but a `**/` at the start or after a `/`
+/// matches any number of directories, and a `**` at the end after a `/`
+/// everything inside.
+#[derive(Clone, Debug)]
+pub(crate) struct Glob {
+    tokens: Vec<Token>,
+}
+
+#[derive(Clone, Debug)]
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    /// `**/`, taking any number of whole names with their `/` after.
+    Dirs,
+    /// `/**` at the end, after the `/`.
+    Rest,
+    Set {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl Token {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Token::Char(expected) => c == *expected,
+            Token::Any => c != '/',
+            Token::Star | Token::Dirs | Token::Rest => false,
+            Token::Set { negated, ranges } => {
+                c != '/' && *negated ^ ranges.iter().any(|&(start, end)| start <= c && c <= end)
+            }
+        }
+    }
+}
+
+impl Glob {
+    pub(crate) fn new(glob: &str) -> Self {
+        let chars = glob.chars().collect::<Vec<_>>();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let after_slash = i == 0 || chars[i - 1] == '/';
+            let token = match chars[i] {
+                '*' if after_slash && chars[i + 1..].starts_with(&['*', '/']) => {
+                    i += 2;
+                    Token::Dirs
+                }
+                '*' if i > 0 && after_slash && chars[i + 1..] == ['*'] => {
+                    i += 1;
+                    Token::Rest
+                }
+                '*' => Token::Star,
+                '?' => Token::Any,
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    Token::Char(chars[i])
+                }
+                '[' => match Self::parse_set(&chars[i + 1..]) {
+                    Some((token, len)) => {
+                        i += len;
+                        token
+                    }
+                    None => Token::Char('['),
+                },
+                c => Token::Char(c),
+            };
+            tokens.push(token);
+            i += 1;
+        }
+        Glob { tokens }
+    }
+
+    /// Reads the set after a `[`, returning it with how many chars it took
+    /// up to and including the `]`.
+    fn parse_set(chars: &[char]) -> Option<(Token, usize)> {
+        let mut i = 0;
+        let negated = matches!(chars.first(), Some('!' | '^'));
+        if negated {
+            i += 1;
+        }
+
+        let mut ranges = Vec::new();
+        // A `]` right at the start is in the set rather than ending it.
+        let first = i;
+        loop {
+            let c = *chars.get(i)?;
+            if c == ']' && i > first {
+                return Some((Token::Set { negated, ranges }, i + 1));
+            }
+            match chars.get(i + 1..i + 3) {
+                Some(&['-', end]) if end != ']' => {
+                    ranges.push((c, end));
+                    i += 3;
+                }
+                _ => {
+                    ranges.push((c, c));
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    pub(crate) fn matches(&self, name: &str) -> bool {
+        let name = name.chars().collect::<Vec<_>>();
+        // Which lengths of the start of the name the tokens so far can match,
+        // a token at a time.
+        let mut matched = vec![false; name.len() + 1];
+        matched[0] = true;
+        for token in &self.tokens {
+            let mut next = vec![false; name.len() + 1];
+            for n in 0..=name.len() {
+                next[n] = match token {
+                    Token::Star => matched[n] || (n > 0 && next[n - 1] && name[n - 1] != '/'),
+                    Token::Dirs => {
+                        matched[n]
+                            || (n > 0 && name[n - 1] == '/' && matched[..n].iter().any(|&m| m))
+                    }
+                    Token::Rest => matched[..=n].iter().any(|&m| m),
+                    token => n > 0 && matched[n - 1] && token.matches(name[n - 1]),
+                };
+            }
+            matched = next;
+        }
+        matched[name.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Glob;
+
+    #[test]
+    fn wildcards_match_names() {
+        let matches = |glob: &str, name: &str| Glob::new(glob).matches(name);
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", ".rs"));
+        assert!(!matches("*.rs", "main.rs.bak"));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", "ab.txt"));
+        assert!(matches("", ""));
+        assert!(matches("**", ""));
+    }
+
+    #[test]
+    fn sets_take_ranges_negation_and_brackets() {
+        let matches = |glob: &str, name: &str| Glob::new(glob).matches(name);
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[a-c]x", "dx"));
+        assert!(matches("[!a-c]x", "dx"));
+        assert!(matches("[^a]", "b"));
+        // A `]` first is in the set, and a `-` last is itself.
+        assert!(matches("[]a]", "]"));
+        assert!(matches("[a-]", "-"));
+        // With no `]` the `[` is a char like any other.
+        assert!(matches("[ab", "[ab"));
+        assert!(matches(r"\*", "*"));
+        assert!(!matches(r"\*", "a"));
+    }
+
+    #[test]
+    fn paths_match_like_gitignore() {
+        let matches = |glob: &str, name: &str| Glob::new(glob).matches(name);
+        assert!(!matches("*.rs", "src/main.rs"));
+        assert!(!matches("a?b", "a/b"));
+        assert!(!matches("a[/]b", "a/b"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(matches("**/main.rs", "main.rs"));
+        assert!(matches("**/main.rs", "src/bin/main.rs"));
+        assert!(matches("a/**/b", "a/b"));
+        assert!(matches("a/**/b", "a/x/y/b"));
+        assert!(!matches("a/**/b", "ab"));
+        assert!(matches("target/**", "target/debug/a.rs"));
+        assert!(!matches("target/**", "target"));
+    }
+}
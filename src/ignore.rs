@@ -0,0 +1,215 @@
+use crate::glob::Glob;
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// The `.gitignore` and `.ignore` files of the directories a walk is in and,
+/// inside a git repository, those above its root up to its top, over the
+/// global git excludes, deciding which of their entries to skip. As with
+/// git, the last line to match a path decides, with a deeper file's lines
+/// after a shallower one's and `.ignore` after `.gitignore`; unreadable
+/// files are taken as empty.
+pub(crate) struct Ignores {
+    global: Vec<Rule>,
+    dirs: Vec<Dir>,
+}
+
+/// The rules from a directory with ignore files.
+struct Dir {
+    /// How deep below the root it is, counting those above as 0.
+    depth: usize,
+    /// The names from it to the root, for those above.
+    prefix: Vec<String>,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    glob: Glob,
+    /// Starts with `!`, to keep what an earlier line skipped.
+    negated: bool,
+    /// Ends with `/`, to match only directories.
+    dir_only: bool,
+    /// Has a `/` before its end, so matches the path from the directory
+    /// with the file and not just the name anywhere below it.
+    anchored: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim_end_matches(['\r', ' ']);
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let line = line.strip_prefix('/').unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        Some(Rule {
+            glob: Glob::new(line),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    /// Whether this line speaks to the path `names` from the directory with
+    /// the file, if so saying to skip it.
+    fn decides(&self, names: &[Cow<str>], is_dir: bool) -> Option<bool> {
+        if self.dir_only && !is_dir {
+            return None;
+        }
+        let matched = if self.anchored {
+            self.glob.matches(&names.join("/"))
+        } else {
+            names.last().is_some_and(|name| self.glob.matches(name))
+        };
+        matched.then_some(!self.negated)
+    }
+}
+
+fn read_rules(path: &Path) -> Vec<Rule> {
+    fs::read_to_string(path)
+        .map(|rules| rules.lines().filter_map(Rule::parse).collect())
+        .unwrap_or_default()
+}
+
+impl Ignores {
+    /// Starts with the global git excludes only: the file `core.excludesFile`
+    /// in `~/.gitconfig` names, or else `git/ignore` in the XDG config
+    /// directory.
+    pub(crate) fn new() -> Self {
+        Ignores {
+            global: global_excludes().map_or_else(Vec::new, |path| read_rules(&path)),
+            dirs: Vec::new(),
+        }
+    }
+
+    /// Takes in the files of `dir`, `depth` directories below the root, and
+    /// for the root those of the directories above it as well.
+    pub(crate) fn enter(&mut self, dir: &Path, depth: usize) {
+        if depth == 0 {
+            self.enter_parents(dir);
+        }
+        self.push(dir, depth, Vec::new());
+    }
+
+    /// Outside a git repository there's nothing above the root to take in,
+    /// as with ripgrep.
+    fn enter_parents(&mut self, root: &Path) {
+        let Ok(root) = fs::canonicalize(root) else {
+            return;
+        };
+        // How many directories up the top of the repository is.
+        let Some(top) = root.ancestors().position(|dir| dir.join(".git").exists()) else {
+            return;
+        };
+        let mut parents = Vec::new();
+        let mut prefix = Vec::new();
+        for (dir, name) in root.ancestors().skip(1).zip(root.ancestors()).take(top) {
+            prefix.insert(
+                0,
+                name.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+            parents.push((dir, prefix.clone()));
+        }
+        for (dir, prefix) in parents.into_iter().rev() {
+            self.push(dir, 0, prefix);
+        }
+    }
+
+    fn push(&mut self, dir: &Path, depth: usize, prefix: Vec<String>) {
+        let mut rules = read_rules(&dir.join(".gitignore"));
+        rules.extend(read_rules(&dir.join(".ignore")));
+        if !rules.is_empty() {
+            self.dirs.push(Dir {
+                depth,
+                prefix,
+                rules,
+            });
+        }
+    }
+
+    /// Drops the files of the directory `depth` below the root, once its
+    /// entries are done with.
+    pub(crate) fn leave(&mut self, depth: usize) {
+        while self.dirs.last().is_some_and(|dir| dir.depth == depth) {
+            self.dirs.pop();
+        }
+    }
+
+    /// Whether to skip `path`, an entry of the directory `depth` below the
+    /// root.
+    pub(crate) fn ignores(&self, path: &Path, depth: usize, is_dir: bool) -> bool {
+        let names = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // The names from the directory `depth` below the root.
+        let below = |depth: usize| &names[names.len().saturating_sub(depth)..];
+        self.dirs
+            .iter()
+            .rev()
+            .map(|dir| {
+                let prefix = dir.prefix.iter().map(|name| Cow::Borrowed(name.as_str()));
+                let names = prefix.chain(below(depth + 1 - dir.depth).iter().cloned());
+                (&dir.rules, names.collect::<Vec<_>>())
+            })
+            .chain([(&self.global, below(depth + 1).to_vec())])
+            .find_map(|(rules, names)| {
+                rules
+                    .iter()
+                    .rev()
+                    .find_map(|rule| rule.decides(&names, is_dir))
+            })
+            .unwrap_or(false)
+    }
+}
+
+fn global_excludes() -> Option<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let configured = home.as_ref().and_then(|home| {
+        let config = fs::read_to_string(home.join(".gitconfig")).ok()?;
+        let mut in_core = false;
+        let mut path = None;
+        for line in config.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_core = line
+                    .trim_matches(['[', ']'])
+                    .trim()
+                    .eq_ignore_ascii_case("core");
+            } else if let Some((key, value)) = line.split_once('=') {
+                if in_core && key.trim().eq_ignore_ascii_case("excludesfile") {
+                    path = Some(value.trim().trim_matches('"').to_string());
+                }
+            }
+        }
+        let path = path?;
+        Some(match path.strip_prefix("~/") {
+            Some(rest) => home.join(rest),
+            None => PathBuf::from(path),
+        })
+    });
+    configured.or_else(|| {
+        let config = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| home.map(|home| home.join(".config")))?;
+        Some(config.join("git").join("ignore"))
+    })
+}
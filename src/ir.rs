@@ -0,0 +1,575 @@
+use crate::pattern::{fold, is_digit_char, is_word_char, Pattern, State};
+use std::fmt;
+use std::ops::Range;
+use std::slice;
+use unicode_script::{Script, UnicodeScript};
+
+pub(crate) type NodeId = usize;
+
+type Continuation<'a> = dyn FnMut(usize, &mut State) -> bool + 'a;
+
+/// A node of the `Ir`. Children, string text and character group ranges
+/// are spans into the tables the `Ir` keeps next to its nodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Node {
+    Empty,
+    Literal(char),
+    String(Range<usize>),
+    FoldedLiteral(char),
+    Digit {
+        unicode: bool,
+        positive: bool,
+    },
+    Alphanumeric {
+        unicode: bool,
+        positive: bool,
+    },
+    WordBoundary {
+        unicode: bool,
+        positive: bool,
+    },
+    NoWordChar {
+        unicode: bool,
+        before: bool,
+    },
+    CharacterGroup {
+        positive: bool,
+        ranges: Range<usize>,
+    },
+    Script {
+        positive: bool,
+        script: Script,
+    },
+    StartAnchor,
+    EndAnchor {
+        crlf: bool,
+    },
+    ContinueAnchor,
+    OneOrMore(NodeId),
+    ZeroOrMore(NodeId),
+    ZeroOrOne(NodeId),
+    Wildcard {
+        crlf: bool,
+    },
+    List(Range<usize>),
+    Either(Range<usize>),
+    LongestEither(Range<usize>),
+    Reference(usize),
+    FoldedReference(usize),
+    CaptureGroup {
+        id: usize,
+        item: NodeId,
+    },
+}
+
+/// The pattern as the matchers see it: every node in one `Vec`, children
+/// before their parents, linked by index rather than by `Box`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Ir {
+    pub(crate) nodes: Vec<Node>,
+    pub(crate) children: Vec<NodeId>,
+    pub(crate) text: String,
+    pub(crate) ranges: Vec<(char, char)>,
+}
+
+impl Ir {
+    pub(crate) fn new(pattern: &Pattern) -> Self {
+        let mut ir = Ir::default();
+        ir.lower(pattern);
+        ir
+    }
+
+    fn lower(&mut self, pattern: &Pattern) -> NodeId {
+        let node = match pattern {
+            Pattern::Empty => Node::Empty,
+            Pattern::Literal(c) => Node::Literal(*c),
+            Pattern::String(s) => {
+                let start = self.text.len();
+                self.text.push_str(s);
+                Node::String(start..self.text.len())
+            }
+            Pattern::FoldedLiteral(c) => Node::FoldedLiteral(*c),
+            Pattern::Digit { unicode, positive } => Node::Digit {
+                unicode: *unicode,
+                positive: *positive,
+            },
+            Pattern::Alphanumeric { unicode, positive } => Node::Alphanumeric {
+                unicode: *unicode,
+                positive: *positive,
+            },
+            Pattern::WordBoundary { unicode, positive } => Node::WordBoundary {
+                unicode: *unicode,
+                positive: *positive,
+            },
+            Pattern::NoWordChar { unicode, before } => Node::NoWordChar {
+                unicode: *unicode,
+                before: *before,
+            },
+            Pattern::CharacterGroup { positive, ranges } => {
+                let start = self.ranges.len();
+                self.ranges.extend_from_slice(ranges);
+                Node::CharacterGroup {
+                    positive: *positive,
+                    ranges: start..self.ranges.len(),
+                }
+            }
+            Pattern::Script { positive, script } => Node::Script {
+                positive: *positive,
+                script: *script,
+            },
+            Pattern::StartAnchor => Node::StartAnchor,
+            Pattern::EndAnchor { crlf } => Node::EndAnchor { crlf: *crlf },
+            Pattern::ContinueAnchor => Node::ContinueAnchor,
+            Pattern::OneOrMore(item) => Node::OneOrMore(self.lower(item)),
+            Pattern::ZeroOrMore(item) => Node::ZeroOrMore(self.lower(item)),
+            Pattern::ZeroOrOne(item) => Node::ZeroOrOne(self.lower(item)),
+            Pattern::Wildcard { crlf } => Node::Wildcard { crlf: *crlf },
+            Pattern::List(items) => Node::List(self.lower_all(items)),
+            Pattern::Either(items) => Node::Either(self.lower_all(items)),
+            Pattern::LongestEither(items) => Node::LongestEither(self.lower_all(items)),
+            Pattern::Reference(id) => Node::Reference(*id),
+            Pattern::FoldedReference(id) => Node::FoldedReference(*id),
+            Pattern::CaptureGroup { id, item, .. } => Node::CaptureGroup {
+                id: *id,
+                item: self.lower(item),
+            },
+        };
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn lower_all(&mut self, items: &[Pattern]) -> Range<usize> {
+        let ids = items
+            .iter()
+            .map(|item| self.lower(item))
+            .collect::<Vec<_>>();
+        let start = self.children.len();
+        self.children.extend(ids);
+        start..self.children.len()
+    }
+
+    /// The node for the whole pattern, which is lowered last.
+    pub(crate) fn root(&self) -> NodeId {
+        self.nodes.len() - 1
+    }
+
+    pub(crate) fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id]
+    }
+
+    pub(crate) fn children(&self, span: &Range<usize>) -> &[NodeId] {
+        &self.children[span.clone()]
+    }
+
+    pub(crate) fn text(&self, span: &Range<usize>) -> &str {
+        &self.text[span.clone()]
+    }
+
+    pub(crate) fn ranges(&self, span: &Range<usize>) -> &[(char, char)] {
+        &self.ranges[span.clone()]
+    }
+
+    /// Writes `node` and the nodes below it, one a line indented by how
+    /// deep it is, with the spans into the tables filled in.
+    pub(crate) fn dump(
+        &self,
+        node: NodeId,
+        depth: usize,
+        out: &mut impl fmt::Write,
+    ) -> fmt::Result {
+        write!(out, "{:1$}", "", depth * 2)?;
+        let children = match self.node(node) {
+            Node::String(span) => {
+                writeln!(out, "String({:?})", self.text(span))?;
+                &[]
+            }
+            Node::CharacterGroup { positive, ranges } => {
+                writeln!(
+                    out,
+                    "CharacterGroup {{ positive: {positive}, ranges: {:?} }}",
+                    self.ranges(ranges)
+                )?;
+                &[]
+            }
+            Node::OneOrMore(item) => {
+                writeln!(out, "OneOrMore")?;
+                slice::from_ref(item)
+            }
+            Node::ZeroOrMore(item) => {
+                writeln!(out, "ZeroOrMore")?;
+                slice::from_ref(item)
+            }
+            Node::ZeroOrOne(item) => {
+                writeln!(out, "ZeroOrOne")?;
+                slice::from_ref(item)
+            }
+            Node::List(items) => {
+                writeln!(out, "List")?;
+                self.children(items)
+            }
+            Node::Either(items) => {
+                writeln!(out, "Either")?;
+                self.children(items)
+            }
+            Node::LongestEither(items) => {
+                writeln!(out, "LongestEither")?;
+                self.children(items)
+            }
+            Node::CaptureGroup { id, item } => {
+                writeln!(out, "CaptureGroup({id})")?;
+                slice::from_ref(item)
+            }
+            node => {
+                writeln!(out, "{node:?}")?;
+                &[]
+            }
+        };
+        for &child in children {
+            self.dump(child, depth + 1, out)?;
+        }
+        Ok(())
+    }
+
+    /// Returns literals one of which every match of `node` starts with, in
+    /// the order the alternatives are tried, and whether they are all it
+    /// can match.
+    pub(crate) fn literal_prefixes(&self, node: NodeId) -> (Vec<String>, bool) {
+        match self.node(node) {
+            Node::Empty => (vec![String::new()], true),
+            Node::Literal(c) => (vec![c.to_string()], true),
+            Node::String(span) => (vec![self.text(span).to_string()], true),
+            Node::OneOrMore(item) => (self.literal_prefixes(*item).0, false),
+            Node::List(items) => {
+                let mut prefixes = vec![String::new()];
+                for &item in self.children(items) {
+                    let (suffixes, complete) = self.literal_prefixes(item);
+                    if prefixes.len() * suffixes.len() > MAX_LITERAL_PREFIXES {
+                        return (prefixes, false);
+                    }
+
+                    prefixes = prefixes
+                        .iter()
+                        .flat_map(|prefix| {
+                            suffixes.iter().map(move |suffix| prefix.clone() + suffix)
+                        })
+                        .collect();
+                    if !complete {
+                        return (prefixes, false);
+                    }
+                }
+                (prefixes, true)
+            }
+            Node::Either(items) | Node::LongestEither(items) => {
+                let mut prefixes = Vec::new();
+                let mut complete = true;
+                for &item in self.children(items) {
+                    let (item_prefixes, item_complete) = self.literal_prefixes(item);
+                    prefixes.extend(item_prefixes);
+                    complete &= item_complete;
+                }
+                if prefixes.len() > MAX_LITERAL_PREFIXES {
+                    return (vec![String::new()], false);
+                }
+                (
+                    prefixes,
+                    complete && matches!(self.node(node), Node::Either(_)),
+                )
+            }
+            Node::CaptureGroup { item, .. } => self.literal_prefixes(*item),
+            _ => (vec![String::new()], false),
+        }
+    }
+
+    /// Returns a literal every match of `node` ends with, and whether it's
+    /// the only thing `node` can match.
+    pub(crate) fn literal_suffix(&self, node: NodeId) -> (String, bool) {
+        match self.node(node) {
+            Node::Empty
+            | Node::WordBoundary { .. }
+            | Node::NoWordChar { .. }
+            | Node::StartAnchor
+            | Node::EndAnchor { .. }
+            | Node::ContinueAnchor => (String::new(), true),
+            Node::Literal(c) => (c.to_string(), true),
+            Node::String(span) => (self.text(span).to_string(), true),
+            Node::OneOrMore(item) => (self.literal_suffix(*item).0, false),
+            Node::List(items) => {
+                let mut suffix = String::new();
+                for &item in self.children(items).iter().rev() {
+                    let (prefix, complete) = self.literal_suffix(item);
+                    suffix.insert_str(0, &prefix);
+                    if !complete {
+                        return (suffix, false);
+                    }
+                }
+                (suffix, true)
+            }
+            Node::Either(items) | Node::LongestEither(items) => {
+                let mut suffixes = self
+                    .children(items)
+                    .iter()
+                    .map(|&item| self.literal_suffix(item));
+                let Some((mut suffix, mut complete)) = suffixes.next() else {
+                    return (String::new(), true);
+                };
+                for (other, other_complete) in suffixes {
+                    let common = suffix
+                        .chars()
+                        .rev()
+                        .zip(other.chars().rev())
+                        .take_while(|(a, b)| a == b)
+                        .map(|(c, _)| c.len_utf8())
+                        .sum::<usize>();
+                    complete &= other_complete && common == suffix.len() && common == other.len();
+                    suffix.drain(..suffix.len() - common);
+                }
+                (suffix, complete)
+            }
+            Node::CaptureGroup { item, .. } => self.literal_suffix(*item),
+            _ => (String::new(), false),
+        }
+    }
+
+    /// The fewest bytes a match of `node` can span.
+    pub(crate) fn min_len(&self, node: NodeId) -> usize {
+        match self.node(node) {
+            Node::Literal(c) => c.len_utf8(),
+            Node::String(span) => span.len(),
+            Node::FoldedLiteral(_)
+            | Node::Digit { .. }
+            | Node::Alphanumeric { .. }
+            | Node::CharacterGroup { .. }
+            | Node::Script { .. }
+            | Node::Wildcard { .. } => 1,
+            Node::OneOrMore(item) | Node::CaptureGroup { item, .. } => self.min_len(*item),
+            Node::List(items) => self
+                .children(items)
+                .iter()
+                .map(|&item| self.min_len(item))
+                .sum(),
+            Node::Either(items) | Node::LongestEither(items) => self
+                .children(items)
+                .iter()
+                .map(|&item| self.min_len(item))
+                .min()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn is_anchored(&self, node: NodeId) -> bool {
+        match self.node(node) {
+            Node::StartAnchor => true,
+            Node::OneOrMore(item) => self.is_anchored(*item),
+            Node::List(items) => self
+                .children(items)
+                .first()
+                .is_some_and(|&item| self.is_anchored(item)),
+            Node::Either(items) | Node::LongestEither(items) => self
+                .children(items)
+                .iter()
+                .all(|&item| self.is_anchored(item)),
+            Node::CaptureGroup { item, .. } => self.is_anchored(*item),
+            _ => false,
+        }
+    }
+
+    /// Matches `node` at byte offset `i` and calls `next` with where each
+    /// way of matching it ends, in order of preference, until one returns
+    /// true.
+    pub(crate) fn matches(
+        &self,
+        node: NodeId,
+        input: &str,
+        search_start: usize,
+        i: usize,
+        state: &mut State,
+        next: &mut Continuation<'_>,
+    ) -> bool {
+        if !state.budget.step() {
+            return true;
+        }
+
+        match self.node(node) {
+            Node::Empty => next(i, state),
+            Node::Literal(expected) => {
+                Self::matches_char(input, i, state, next, |c| c == *expected)
+            }
+            Node::String(span) => {
+                let text = self.text(span);
+                input[i..].starts_with(text) && next(i + text.len(), state)
+            }
+            Node::FoldedLiteral(expected) => {
+                Self::matches_char(input, i, state, next, |c| fold(c).eq(fold(*expected)))
+            }
+            Node::Digit { unicode, positive } => Self::matches_char(input, i, state, next, |c| {
+                !*positive ^ is_digit_char(c, *unicode)
+            }),
+            Node::Alphanumeric { unicode, positive } => {
+                Self::matches_char(input, i, state, next, |c| {
+                    !*positive ^ is_word_char(c, *unicode)
+                })
+            }
+            Node::WordBoundary { unicode, positive } => {
+                let before = input[..i]
+                    .chars()
+                    .next_back()
+                    .is_some_and(|c| is_word_char(c, *unicode));
+                let after = input[i..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| is_word_char(c, *unicode));
+                !*positive ^ (before != after) && next(i, state)
+            }
+            Node::NoWordChar { unicode, before } => {
+                let c = if *before {
+                    input[..i].chars().next_back()
+                } else {
+                    input[i..].chars().next()
+                };
+                !c.is_some_and(|c| is_word_char(c, *unicode)) && next(i, state)
+            }
+            Node::CharacterGroup { positive, ranges } => {
+                Self::matches_char(input, i, state, next, |c| {
+                    !*positive
+                        ^ self
+                            .ranges(ranges)
+                            .iter()
+                            .any(|&(start, end)| start <= c && c <= end)
+                })
+            }
+            Node::Script { positive, script } => Self::matches_char(input, i, state, next, |c| {
+                !*positive ^ (c.script() == *script)
+            }),
+            Node::StartAnchor => i == 0 && next(i, state),
+            Node::EndAnchor { crlf } => {
+                (i == input.len() || *crlf && matches!(&input[i..], "\r" | "\r\n"))
+                    && next(i, state)
+            }
+            Node::ContinueAnchor => i == search_start && next(i, state),
+            Node::OneOrMore(inner) => {
+                self.matches(*inner, input, search_start, i, state, &mut |j, state| {
+                    j > i && self.matches(node, input, search_start, j, state, next)
+                        || next(j, state)
+                })
+            }
+            Node::ZeroOrMore(inner) => {
+                self.matches(*inner, input, search_start, i, state, &mut |j, state| {
+                    j > i && self.matches(node, input, search_start, j, state, next)
+                        || next(j, state)
+                }) || next(i, state)
+            }
+            Node::ZeroOrOne(inner) => {
+                self.matches(*inner, input, search_start, i, state, next) || next(i, state)
+            }
+            Node::Wildcard { crlf } => {
+                Self::matches_char(input, i, state, next, |c| !*crlf || c != '\r' && c != '\n')
+            }
+            Node::Either(items) => self
+                .children(items)
+                .iter()
+                .any(|&item| self.matches(item, input, search_start, i, state, next)),
+            Node::LongestEither(items) => {
+                let items = self.children(items);
+                let mut ends = Vec::new();
+                for (k, &item) in items.iter().enumerate() {
+                    self.matches(item, input, search_start, i, state, &mut |end, _| {
+                        if !ends.contains(&(end, k)) {
+                            ends.push((end, k));
+                        }
+                        false
+                    });
+                }
+                ends.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+                ends.into_iter().any(|(end, k)| {
+                    self.matches(items[k], input, search_start, i, state, &mut |j, state| {
+                        j == end && next(j, state)
+                    })
+                })
+            }
+            Node::List(items) => {
+                self.matches_list(self.children(items), input, search_start, i, state, next)
+            }
+            Node::Reference(id) => {
+                if let Some(range) = state.groups[*id].clone() {
+                    let content = &input[range];
+                    input[i..].starts_with(content) && next(i + content.len(), state)
+                } else {
+                    false
+                }
+            }
+            Node::FoldedReference(id) => {
+                if let Some(range) = state.groups[*id].clone() {
+                    let expected = input[range].chars().flat_map(fold).collect::<Vec<_>>();
+
+                    let mut matched = 0;
+                    let mut j = i;
+                    while matched < expected.len() {
+                        let Some(c) = input[j..].chars().next() else {
+                            return false;
+                        };
+                        for c in fold(c) {
+                            if expected.get(matched) != Some(&c) {
+                                return false;
+                            }
+                            matched += 1;
+                        }
+                        j += c.len_utf8();
+                    }
+
+                    next(j, state)
+                } else {
+                    false
+                }
+            }
+            Node::CaptureGroup { id, item } => {
+                self.matches(*item, input, search_start, i, state, &mut |j, state| {
+                    let range = i..j;
+                    let saved = state.groups[*id].replace(range.clone());
+                    state.history.push((*id, range));
+                    if next(j, state) {
+                        true
+                    } else {
+                        state.history.pop();
+                        state.groups[*id] = saved;
+                        false
+                    }
+                })
+            }
+        }
+    }
+
+    fn matches_list(
+        &self,
+        items: &[NodeId],
+        input: &str,
+        search_start: usize,
+        i: usize,
+        state: &mut State,
+        next: &mut Continuation<'_>,
+    ) -> bool {
+        if let Some((&first, rest)) = items.split_first() {
+            self.matches(first, input, search_start, i, state, &mut |j, state| {
+                self.matches_list(rest, input, search_start, j, state, next)
+            })
+        } else {
+            next(i, state)
+        }
+    }
+
+    fn matches_char(
+        input: &str,
+        i: usize,
+        state: &mut State,
+        next: &mut Continuation<'_>,
+        predicate: impl FnOnce(char) -> bool,
+    ) -> bool {
+        match input[i..].chars().next() {
+            Some(c) => predicate(c) && next(i + c.len_utf8(), state),
+            None => false,
+        }
+    }
+}
+
+const MAX_LITERAL_PREFIXES: usize = 64;
@@ -0,0 +1,2132 @@
+mod backtrack;
+pub mod bytes;
+mod byteset;
+mod dfa;
+mod ir;
+mod onepass;
+mod optimize;
+mod pattern;
+mod pikevm;
+mod pool;
+mod prefilter;
+mod program;
+mod serialize;
+mod set;
+mod shiftor;
+mod stream;
+
+pub use pattern::QuantifierError;
+pub use set::{RegexSet, RegexSetBuilder, SetMatches};
+pub use stream::{Stream, StreamError};
+
+use dfa::Dfa;
+use ir::Ir;
+use onepass::OnePass;
+use pattern::{Budget, EndFlags, Flags, Pattern, State};
+use pool::{Pool, PoolGuard};
+use prefilter::{Prefilter, Suffix};
+use program::Program;
+use shiftor::ShiftOr;
+use std::fmt;
+use std::ops::Range;
+use std::time::Duration;
+
+/// The regex syntax a pattern is written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Syntax {
+    /// POSIX basic syntax, as `grep -G` takes it: `+`, `?`, `|`, `(` and `)`
+    /// match themselves unless a backslash comes before them, and `^`, `$`
+    /// and `*` do where they can't be operators.
+    Basic,
+    /// POSIX extended syntax with this crate's extensions.
+    #[default]
+    Extended,
+    /// Extended syntax with escapes more like PCRE's: `\s`, `\S`, `\D` and
+    /// `\W` on top of the rest, and `\n`, `\t`, `\r`, `\f`, `\v` and `\e`
+    /// for those chars. Like PCRE's, `\d`, `\w` and `\b` only know ASCII
+    /// unless `(?u)` turns Unicode on.
+    Perl,
+}
+
+/// A search gave up after the number of backtracking steps set with
+/// `RegexBuilder::backtrack_limit`, or once the time set with
+/// `RegexBuilder::timeout` was up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TooComplexError {
+    reason: Reason,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Reason {
+    Steps(usize),
+    Timeout(Duration),
+}
+
+impl TooComplexError {
+    /// The step limit the search ran into, if that's why it gave up.
+    pub fn limit(&self) -> Option<usize> {
+        match self.reason {
+            Reason::Steps(limit) => Some(limit),
+            Reason::Timeout(_) => None,
+        }
+    }
+
+    /// The timeout the search ran into, if that's why it gave up.
+    pub fn timeout(&self) -> Option<Duration> {
+        match self.reason {
+            Reason::Steps(_) => None,
+            Reason::Timeout(timeout) => Some(timeout),
+        }
+    }
+}
+
+impl fmt::Display for TooComplexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Reason::Steps(limit) => write!(
+                f,
+                "regex too complex for this input (gave up after {limit} backtracking steps)"
+            ),
+            Reason::Timeout(timeout) => write!(
+                f,
+                "regex too complex for this input (gave up after {timeout:?})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TooComplexError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Match<'h> {
+    haystack: &'h str,
+    start: usize,
+    end: usize,
+}
+
+impl<'h> Match<'h> {
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn as_str(&self) -> &'h str {
+        &self.haystack[self.range()]
+    }
+}
+
+pub struct Captures<'h> {
+    haystack: &'h str,
+    state: State,
+}
+
+impl<'h> Captures<'h> {
+    /// Returns group `i`, where group 0 is the whole match; a repeated group
+    /// holds its last iteration.
+    pub fn get(&self, i: usize) -> Option<Match<'h>> {
+        self.state
+            .groups
+            .get(i)
+            .cloned()
+            .flatten()
+            .map(|range| Match {
+                haystack: self.haystack,
+                start: range.start,
+                end: range.end,
+            })
+    }
+
+    /// Returns every span group `i` matched, in input order.
+    pub fn get_all(&self, i: usize) -> Vec<Match<'h>> {
+        self.state
+            .history
+            .iter()
+            .filter(|(id, _)| *id == i)
+            .map(|(_, range)| Match {
+                haystack: self.haystack,
+                start: range.start,
+                end: range.end,
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.groups.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.groups.is_empty()
+    }
+}
+
+/// What a search needs besides the regex itself, taken from the regex's
+/// pool for the length of a search so that searching doesn't allocate once
+/// the buffers have grown to fit.
+#[derive(Default)]
+struct Scratch {
+    state: State,
+    pikevm: pikevm::Cache,
+    onepass: onepass::Cache,
+    backtrack: backtrack::Cache,
+}
+
+pub struct Regex {
+    ir: Ir,
+    program: Option<Program>,
+    dfa: Option<Dfa>,
+    onepass: Option<OnePass>,
+    shiftor: Option<ShiftOr>,
+    prefilter: Option<Prefilter>,
+    suffix: Option<Suffix>,
+    capture_group_count: usize,
+    /// The name of each group, by number, for those given one.
+    group_names: Vec<Option<String>>,
+    anchored: bool,
+    longest: bool,
+    backtrack_limit: Option<usize>,
+    timeout: Option<Duration>,
+    dfa_size_limit: usize,
+    min_len: usize,
+    /// Runs searches on the backtracker alone, reporting each step of it.
+    trace: bool,
+    scratch: Pool<Scratch>,
+}
+
+// Searches share a regex across threads, which whatever goes in `Scratch`
+// has to allow.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Regex>();
+    assert_send_sync::<RegexSet>();
+};
+
+impl Regex {
+    pub fn new(pattern: &str) -> anyhow::Result<Self> {
+        RegexBuilder::new(pattern).build()
+    }
+
+    /// Panics if the search gives up; see `RegexBuilder::backtrack_limit`.
+    pub fn is_match(&self, input: &str) -> bool {
+        self.try_is_match(input)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_is_match(&self, input: &str) -> Result<bool, TooComplexError> {
+        if self.trace {
+            return Ok(self
+                .try_find_at(input, 0, false, &mut self.scratch.get())?
+                .is_some());
+        }
+        if input.len() < self.min_len || self.last_start(input, 0).is_none() {
+            return Ok(false);
+        }
+
+        if let Some(found) = self
+            .prefilter
+            .as_ref()
+            .and_then(|prefilter| prefilter.find_exact(input, 0))
+        {
+            return Ok(found.is_some());
+        }
+
+        if let Some(shiftor) = &self.shiftor {
+            return Ok(shiftor.find_at(input, 0, self.prefilter.as_ref()).is_some());
+        }
+
+        if let (Some(program), Some(dfa)) = (&self.program, &self.dfa) {
+            if let Some(matched) = dfa.is_match(program, input, self.prefilter.as_ref()) {
+                return Ok(matched);
+            }
+        }
+
+        if let Some(program) = &self.program {
+            if program.references.is_empty() {
+                return Ok(pikevm::is_match(
+                    program,
+                    input,
+                    self.anchored,
+                    self.prefilter.as_ref(),
+                    &mut self.scratch.get().pikevm,
+                ));
+            }
+        }
+
+        Ok(self
+            .try_find_at(input, 0, false, &mut self.scratch.get())?
+            .is_some())
+    }
+
+    /// Panics if the search gives up; see `RegexBuilder::backtrack_limit`.
+    pub fn find<'h>(&self, input: &'h str) -> Option<Match<'h>> {
+        self.try_find(input).unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_find<'h>(&self, input: &'h str) -> Result<Option<Match<'h>>, TooComplexError> {
+        let found = self.try_find_at(input, 0, false, &mut self.scratch.get())?;
+        Ok(found.map(|range| Match {
+            haystack: input,
+            start: range.start,
+            end: range.end,
+        }))
+    }
+
+    /// Panics if the search gives up; see `RegexBuilder::backtrack_limit`.
+    pub fn captures<'h>(&self, input: &'h str) -> Option<Captures<'h>> {
+        self.try_captures(input)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_captures<'h>(
+        &self,
+        input: &'h str,
+    ) -> Result<Option<Captures<'h>>, TooComplexError> {
+        let mut scratch = self.scratch.get();
+        let found = self.try_find_at(input, 0, true, &mut scratch)?;
+        Ok(found.map(|_| Captures {
+            haystack: input,
+            state: scratch.state.clone(),
+        }))
+    }
+
+    pub fn find_iter<'r, 'h>(&'r self, input: &'h str) -> FindMatches<'r, 'h> {
+        FindMatches {
+            regex: self,
+            haystack: input,
+            start: Some(0),
+            last_end: None,
+            overlapping: false,
+            scratch: self.scratch.get(),
+        }
+    }
+
+    pub fn find_overlapping_iter<'r, 'h>(&'r self, input: &'h str) -> FindMatches<'r, 'h> {
+        FindMatches {
+            regex: self,
+            haystack: input,
+            start: Some(0),
+            last_end: None,
+            overlapping: true,
+            scratch: self.scratch.get(),
+        }
+    }
+
+    pub fn captures_iter<'r, 'h>(&'r self, input: &'h str) -> CaptureMatches<'r, 'h> {
+        CaptureMatches {
+            regex: self,
+            haystack: input,
+            start: Some(0),
+            last_end: None,
+            scratch: self.scratch.get(),
+        }
+    }
+
+    pub fn captures_len(&self) -> usize {
+        self.capture_group_count
+    }
+
+    /// The names of the groups in order, from the whole match's, which has
+    /// none, with `None` for those not named with `(?<name>...)`.
+    pub fn capture_names(&self) -> impl Iterator<Item = Option<&str>> {
+        self.group_names.iter().map(Option::as_deref)
+    }
+
+    /// A listing of what the pattern was compiled to, for seeing how it was
+    /// read: the tree, a node a line, then the bytecode, which patterns with
+    /// longest alternatives have none of.
+    pub fn dump(&self) -> String {
+        let mut dump = String::from("tree:\n");
+        // Writing to a `String` doesn't fail.
+        let _ = self.ir.dump(self.ir.root(), 1, &mut dump);
+        match &self.program {
+            Some(program) => {
+                dump.push_str("program:\n");
+                let _ = program.dump(&mut dump);
+            }
+            None => dump.push_str("program: none\n"),
+        }
+        dump
+    }
+
+    fn find_next(
+        &self,
+        input: &str,
+        start: usize,
+        last_end: Option<usize>,
+        captures: bool,
+        scratch: &mut Scratch,
+    ) -> Option<Range<usize>> {
+        self.try_find_next(input, start, last_end, captures, scratch)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    /// Returns the next match from `start` for an iterator, passing over an
+    /// empty one where the last match ended.
+    fn try_find_next(
+        &self,
+        input: &str,
+        mut start: usize,
+        last_end: Option<usize>,
+        captures: bool,
+        scratch: &mut Scratch,
+    ) -> Result<Option<Range<usize>>, TooComplexError> {
+        loop {
+            let Some(range) = self.try_find_at(input, start, captures, scratch)? else {
+                return Ok(None);
+            };
+            if range.is_empty() && Some(range.end) == last_end {
+                let Some(c) = input[range.start..].chars().next() else {
+                    return Ok(None);
+                };
+                start = range.start + c.len_utf8();
+            } else {
+                return Ok(Some(range));
+            }
+        }
+    }
+
+    fn last_start(&self, input: &str, start: usize) -> Option<usize> {
+        match &self.suffix {
+            Some(suffix) => suffix.last_start(input, start),
+            None => Some(input.len()),
+        }
+    }
+
+    /// Fills in the groups for a match found by an engine that only reports
+    /// where it is, for patterns with no groups.
+    fn whole_match(&self, range: Range<usize>, state: &mut State) -> Range<usize> {
+        state.reset(self.capture_group_count);
+        state.groups[0] = Some(range.clone());
+        state.history.push((0, range.clone()));
+        range
+    }
+
+    /// Returns where the leftmost match from `start` is and leaves its groups
+    /// in `scratch.state`, all of them if `captures` is set and at least the
+    /// whole match otherwise.
+    fn try_find_at(
+        &self,
+        input: &str,
+        start: usize,
+        captures: bool,
+        scratch: &mut Scratch,
+    ) -> Result<Option<Range<usize>>, TooComplexError> {
+        if let Some(program) = self.program.as_ref().filter(|_| self.trace) {
+            let state = &mut scratch.state;
+            let found = backtrack::find_at(
+                program,
+                input,
+                start,
+                self.anchored,
+                self.longest,
+                None,
+                Budget::new(self.backtrack_limit, self.timeout),
+                true,
+                &mut scratch.backtrack,
+                state,
+            )?;
+            return Ok(found.then(|| state.groups[0].clone().unwrap()));
+        }
+
+        if input.len() - start < self.min_len {
+            return Ok(None);
+        }
+        let Some(last_start) = self.last_start(input, start) else {
+            return Ok(None);
+        };
+
+        if let Some(found) = self
+            .prefilter
+            .as_ref()
+            .and_then(|prefilter| prefilter.find_exact(input, start))
+        {
+            return Ok(found.map(|range| self.whole_match(range, &mut scratch.state)));
+        }
+
+        if let Some(shiftor) = &self.shiftor {
+            return Ok(shiftor
+                .find_at(input, start, self.prefilter.as_ref())
+                .map(|range| self.whole_match(range, &mut scratch.state)));
+        }
+
+        if let Some(program) = &self.program {
+            let state = &mut scratch.state;
+            let found = if let Some(onepass) = &self.onepass {
+                onepass.find_at(program, input, start, &mut scratch.onepass, state)
+            } else if program.references.is_empty() {
+                pikevm::find_at(
+                    program,
+                    input,
+                    start,
+                    self.anchored,
+                    self.longest,
+                    self.prefilter.as_ref(),
+                    captures,
+                    &mut scratch.pikevm,
+                    state,
+                )
+            } else {
+                backtrack::find_at(
+                    program,
+                    input,
+                    start,
+                    self.anchored,
+                    self.longest,
+                    self.prefilter.as_ref(),
+                    Budget::new(self.backtrack_limit, self.timeout),
+                    false,
+                    &mut scratch.backtrack,
+                    state,
+                )?
+            };
+            return Ok(found.then(|| state.groups[0].clone().unwrap()));
+        }
+
+        let state = &mut scratch.state;
+        state.budget = Budget::new(self.backtrack_limit, self.timeout);
+
+        let mut i = start;
+        loop {
+            if let Some(prefilter) = &self.prefilter {
+                let Some(next) = prefilter.find(input, i) else {
+                    return Ok(None);
+                };
+                i = next;
+            }
+
+            if input.len() - i < self.min_len || i > last_start {
+                return Ok(None);
+            }
+
+            state.reset(self.capture_group_count);
+
+            let mut found = None;
+            self.ir
+                .matches(self.ir.root(), input, start, i, state, &mut |end, state| {
+                    if self.longest {
+                        if found.as_ref().map_or(true, |(best, _)| end > *best) {
+                            found = Some((end, state.clone()));
+                        }
+                        false
+                    } else {
+                        found = Some((end, state.clone()));
+                        true
+                    }
+                });
+
+            if let Some(err) = state
+                .budget
+                .exceeded()
+                .filter(|_| self.longest || found.is_none())
+            {
+                return Err(err);
+            }
+
+            if let Some((end, found)) = found {
+                *state = found;
+                state.groups[0] = Some(i..end);
+                state.history.push((0, i..end));
+                return Ok(Some(i..end));
+            }
+
+            let Some(c) = input[i..].chars().next().filter(|_| !self.anchored) else {
+                return Ok(None);
+            };
+            i += c.len_utf8();
+        }
+    }
+}
+
+pub struct FindMatches<'r, 'h> {
+    regex: &'r Regex,
+    haystack: &'h str,
+    start: Option<usize>,
+    last_end: Option<usize>,
+    overlapping: bool,
+    scratch: PoolGuard<'r, Scratch>,
+}
+
+impl<'r, 'h> Iterator for FindMatches<'r, 'h> {
+    type Item = Match<'h>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(range) = self.regex.find_next(
+            self.haystack,
+            self.start?,
+            self.last_end,
+            false,
+            &mut self.scratch,
+        ) {
+            self.last_end = Some(range.end);
+            self.start = if self.overlapping {
+                self.haystack[range.start..]
+                    .chars()
+                    .next()
+                    .map(|c| range.start + c.len_utf8())
+            } else {
+                Some(range.end)
+            };
+            Some(Match {
+                haystack: self.haystack,
+                start: range.start,
+                end: range.end,
+            })
+        } else {
+            self.start = None;
+            None
+        }
+    }
+}
+
+pub struct CaptureMatches<'r, 'h> {
+    regex: &'r Regex,
+    haystack: &'h str,
+    start: Option<usize>,
+    last_end: Option<usize>,
+    scratch: PoolGuard<'r, Scratch>,
+}
+
+impl<'r, 'h> Iterator for CaptureMatches<'r, 'h> {
+    type Item = Captures<'h>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(range) = self.regex.find_next(
+            self.haystack,
+            self.start?,
+            self.last_end,
+            true,
+            &mut self.scratch,
+        ) {
+            self.last_end = Some(range.end);
+            self.start = Some(range.end);
+            Some(Captures {
+                haystack: self.haystack,
+                state: self.scratch.state.clone(),
+            })
+        } else {
+            self.start = None;
+            None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RegexBuilder {
+    patterns: Vec<String>,
+    syntax: Syntax,
+    posix: bool,
+    longest: bool,
+    case_insensitive: bool,
+    crlf: bool,
+    longest_alternative: bool,
+    literal_misplaced_anchors: bool,
+    fixed_string: bool,
+    word: bool,
+    line: bool,
+    backtrack_limit: Option<usize>,
+    timeout: Option<Duration>,
+    dfa_size_limit: usize,
+    trace: bool,
+}
+
+impl RegexBuilder {
+    pub fn new(pattern: &str) -> Self {
+        RegexBuilder::new_any([pattern])
+    }
+
+    /// Builds a regex matching where any of `patterns` does, preferring the
+    /// earlier ones like the alternatives of `|`. Each pattern numbers its
+    /// groups on its own, so its back references mean what they would alone,
+    /// but all of its groups come after those of the patterns before it.
+    /// With no patterns nothing matches.
+    pub fn new_any<I, S>(patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        RegexBuilder {
+            patterns: patterns
+                .into_iter()
+                .map(|pattern| pattern.as_ref().to_string())
+                .collect(),
+            syntax: Syntax::default(),
+            posix: false,
+            longest: false,
+            case_insensitive: false,
+            crlf: false,
+            longest_alternative: false,
+            literal_misplaced_anchors: false,
+            fixed_string: false,
+            word: false,
+            line: false,
+            backtrack_limit: None,
+            timeout: None,
+            dfa_size_limit: dfa::DEFAULT_SIZE_LIMIT,
+            trace: false,
+        }
+    }
+
+    pub fn syntax(&mut self, syntax: Syntax) -> &mut Self {
+        self.syntax = syntax;
+        self
+    }
+
+    /// Takes only what POSIX has of the syntax, so none of the escapes for
+    /// classes and assertions, groups starting `(?` or, in extended syntax,
+    /// back references, and matches leftmost-longest as POSIX says to. Perl
+    /// syntax is taken as extended.
+    pub fn posix(&mut self, yes: bool) -> &mut Self {
+        self.posix = yes;
+        self
+    }
+
+    pub fn longest(&mut self, yes: bool) -> &mut Self {
+        self.longest = yes;
+        self
+    }
+
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+        self.case_insensitive = yes;
+        self
+    }
+
+    pub fn crlf(&mut self, yes: bool) -> &mut Self {
+        self.crlf = yes;
+        self
+    }
+
+    pub fn longest_alternative(&mut self, yes: bool) -> &mut Self {
+        self.longest_alternative = yes;
+        self
+    }
+
+    pub fn literal_misplaced_anchors(&mut self, yes: bool) -> &mut Self {
+        self.literal_misplaced_anchors = yes;
+        self
+    }
+
+    /// Matches the pattern as a plain string, with no special chars.
+    pub fn fixed_string(&mut self, yes: bool) -> &mut Self {
+        self.fixed_string = yes;
+        self
+    }
+
+    /// Only matches where the pattern has no word chars right before or
+    /// after it, like GNU grep's `-w`. Unlike wrapping it in `\b(?:...)\b`
+    /// that lets matches start or end with other chars, so `@` matches
+    /// in `a @ b`, while `foo ` doesn't in `foo bar`.
+    pub fn word(&mut self, yes: bool) -> &mut Self {
+        self.word = yes;
+        self
+    }
+
+    /// Only matches the whole input, as if the pattern were wrapped in
+    /// `^(?:...)$`, which makes `word` moot.
+    pub fn line(&mut self, yes: bool) -> &mut Self {
+        self.line = yes;
+        self
+    }
+
+    /// Gives up on a search once the backtracking matchers have taken `limit`
+    /// steps, which only patterns with back references or longest
+    /// alternatives use. The `try_` methods return an error then and the
+    /// others panic.
+    pub fn backtrack_limit(&mut self, limit: usize) -> &mut Self {
+        self.backtrack_limit = Some(limit);
+        self
+    }
+
+    /// Gives up on a search in the backtracking matchers, like
+    /// `backtrack_limit`, once it has taken `timeout`, counted from the
+    /// start of each call; `find_iter` and friends give each match the
+    /// whole time.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Caps the memory, in bytes, the DFA may use for the states it builds
+    /// in each thread searching with the regex. Past it the states are
+    /// thrown away and built again as needed, or, if that keeps happening
+    /// within little input, the search carries on with the slower NFA.
+    pub fn dfa_size_limit(&mut self, limit: usize) -> &mut Self {
+        self.dfa_size_limit = limit;
+        self
+    }
+
+    /// Writes each step a search takes to stderr: where in the input it is,
+    /// the instruction it's at and what came of it, and where it backtracks
+    /// to. Searches then run on the backtracker alone, skipping the faster
+    /// engines, which leaves patterns with longest alternatives, having no
+    /// bytecode, searched as usual with nothing written.
+    pub fn trace(&mut self, yes: bool) -> &mut Self {
+        self.trace = yes;
+        self
+    }
+
+    pub fn build(&self) -> anyhow::Result<Regex> {
+        let mut flags = Flags::default();
+        flags.set(Flags::CASE_INSENSITIVE, self.case_insensitive);
+        flags.set(Flags::CRLF, self.crlf);
+        flags.set(Flags::LONGEST_ALTERNATIVE, self.longest_alternative);
+        flags.set(Flags::LITERAL_ANCHORS, self.literal_misplaced_anchors);
+        match self.syntax {
+            Syntax::Basic => flags.insert(Flags::BASIC | Flags::LITERAL_ANCHORS),
+            Syntax::Extended => {}
+            Syntax::Perl if self.posix => {}
+            Syntax::Perl => {
+                flags.insert(Flags::PERL);
+                flags.remove(Flags::UNICODE);
+            }
+        }
+        flags.set(Flags::POSIX, self.posix);
+
+        let no_word_char = |before| Pattern::NoWordChar {
+            unicode: flags.contains(Flags::UNICODE),
+            before,
+        };
+        let mut capture_group_count = 1;
+        let mut alternatives = Vec::with_capacity(self.patterns.len());
+        for pattern in &self.patterns {
+            let alternative = if self.fixed_string {
+                Pattern::literal(pattern, flags)
+            } else {
+                let mut group_count = 1;
+                let mut alternative = Pattern::parse_either(
+                    &mut pattern.chars().peekable(),
+                    EndFlags::empty(),
+                    &mut flags.clone(),
+                    &mut group_count,
+                    None,
+                )?;
+                alternative.shift_groups(capture_group_count - 1);
+                capture_group_count += group_count - 1;
+                alternative
+            };
+            alternatives.push(alternative);
+        }
+        let pattern = match alternatives.len() {
+            // A group of no chars, which nothing matches.
+            0 => Pattern::CharacterGroup {
+                positive: true,
+                ranges: Vec::new(),
+            },
+            1 => alternatives.pop().unwrap(),
+            _ if flags.contains(Flags::LONGEST_ALTERNATIVE) => Pattern::LongestEither(alternatives),
+            _ => Pattern::Either(alternatives),
+        };
+        let pattern = if self.line {
+            Pattern::List(vec![
+                Pattern::StartAnchor,
+                pattern,
+                Pattern::EndAnchor { crlf: self.crlf },
+            ])
+        } else if self.word {
+            Pattern::List(vec![no_word_char(true), pattern, no_word_char(false)])
+        } else {
+            pattern
+        };
+
+        let mut group_names = vec![None; capture_group_count];
+        pattern.group_names(&mut group_names)?;
+
+        let ir = Ir::new(&pattern.optimize());
+        let program = Program::compile(&ir, capture_group_count);
+        let mut regex = Regex::from_parts(
+            ir,
+            program,
+            group_names,
+            self.longest || self.posix,
+            self.backtrack_limit,
+            self.timeout,
+            self.dfa_size_limit,
+        );
+        regex.trace = self.trace;
+        Ok(regex)
+    }
+}
+
+impl Regex {
+    /// Sets up the engines that are worked out from the tree and the
+    /// bytecode. There's a name or `None` for every group.
+    fn from_parts(
+        ir: Ir,
+        program: Option<Program>,
+        group_names: Vec<Option<String>>,
+        longest: bool,
+        backtrack_limit: Option<usize>,
+        timeout: Option<Duration>,
+        dfa_size_limit: usize,
+    ) -> Regex {
+        let anchored = ir.is_anchored(ir.root());
+        let capture_group_count = group_names.len();
+        Regex {
+            anchored,
+            dfa: program
+                .as_ref()
+                .and_then(|program| Dfa::new(program, anchored, dfa_size_limit)),
+            suffix: Suffix::new(&ir),
+            prefilter: Prefilter::new(&ir, capture_group_count, longest)
+                .or_else(|| program.as_ref().and_then(Prefilter::first_bytes))
+                .filter(|_| !anchored),
+            shiftor: program.as_ref().and_then(ShiftOr::new),
+            onepass: program
+                .as_ref()
+                .filter(|_| anchored && !longest)
+                .and_then(OnePass::new),
+            program,
+            capture_group_count,
+            group_names,
+            longest,
+            backtrack_limit,
+            timeout,
+            dfa_size_limit,
+            min_len: ir.min_len(ir.root()),
+            trace: false,
+            scratch: Pool::default(),
+            ir,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{QuantifierError, Regex, RegexBuilder, RegexSet, RegexSetBuilder, Syntax};
+    use std::ops::Range;
+    use std::time::Duration;
+
+    fn overlapping(pattern: &str, haystack: &str) -> Vec<Range<usize>> {
+        let regex = Regex::new(pattern).unwrap();
+        regex
+            .find_overlapping_iter(haystack)
+            .map(|m| m.range())
+            .collect()
+    }
+
+    fn longest(pattern: &str) -> Regex {
+        RegexBuilder::new(pattern).longest(true).build().unwrap()
+    }
+
+    fn ranges(regex: &Regex, haystack: &str) -> Vec<Range<usize>> {
+        regex.find_iter(haystack).map(|m| m.range()).collect()
+    }
+
+    #[test]
+    fn overlapping_matches_start_a_char_apart() {
+        assert_eq!(overlapping("aa", "aaaa"), [0..2, 1..3, 2..4]);
+        assert_eq!(overlapping("aba", "ababa"), [0..3, 2..5]);
+        // A char, not a byte, past the last start.
+        assert_eq!(overlapping("ββ", "βββ"), [0..4, 2..6]);
+        assert!(overlapping("aa", "a").is_empty());
+    }
+
+    #[test]
+    fn empty_matches_move_the_search_on() {
+        let ranges = |pattern: &str, haystack: &str| {
+            let regex = Regex::new(pattern).unwrap();
+            regex
+                .find_iter(haystack)
+                .map(|m| m.range())
+                .collect::<Vec<_>>()
+        };
+        // No empty match right where the one before ended.
+        assert_eq!(ranges("a*", "baaa"), [0..0, 1..4]);
+        assert_eq!(ranges("a*", "βa"), [0..0, 2..3]);
+        assert_eq!(ranges("x*", "ab"), [0..0, 1..1, 2..2]);
+        // Loops whose body can match empty still end.
+        assert_eq!(ranges("(b?)+", "ab"), [0..0, 1..2]);
+        assert_eq!(ranges("(a*)*", "aab"), [0..2, 3..3]);
+        assert_eq!(overlapping("a*", "aa"), [0..2, 1..2]);
+    }
+
+    #[test]
+    fn failed_branches_leave_no_groups_behind() {
+        let regex = Regex::new("(a)x|ay").unwrap();
+        let captures = regex.captures("ay").unwrap();
+        assert_eq!(captures.get(1).map(|m| m.range()), None);
+        // Group 2 was only set by the branch that failed, so there's nothing
+        // for `\2` to match.
+        let regex = Regex::new(r"((a)b|ac)\2").unwrap();
+        assert!(!regex.is_match("aca"));
+        assert!(regex.is_match("aba"));
+    }
+
+    #[test]
+    fn repeated_groups_keep_every_iteration() {
+        let regex = Regex::new("(ab)+").unwrap();
+        let captures = regex.captures("xababab").unwrap();
+        assert_eq!(captures.get(1).unwrap().range(), 5..7);
+        let all = captures.get_all(1);
+        assert_eq!(
+            all.iter().map(|m| m.range()).collect::<Vec<_>>(),
+            [1..3, 3..5, 5..7]
+        );
+        // The iteration given back for the last `a` isn't one.
+        let regex = Regex::new("(a)+a").unwrap();
+        let captures = regex.captures("aaa").unwrap();
+        assert_eq!(captures.get(1).unwrap().range(), 1..2);
+        assert_eq!(captures.get_all(1).len(), 2);
+    }
+
+    #[test]
+    fn case_insensitive_back_references_fold() {
+        let regex = RegexBuilder::new(r"(\w+) \1")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            regex.find("say Hello HELLO").unwrap().as_str(),
+            "Hello HELLO"
+        );
+        // Final sigma and sigma fold to the same char, as do K and the
+        // Kelvin sign.
+        assert!(regex.is_match("ΟΔΟΣ οδος"));
+        assert!(regex.is_match("k \u{212a}"));
+        assert!(!regex.is_match("Hello Help"));
+        assert!(!Regex::new(r"(\w+) \1").unwrap().is_match("Hello HELLO"));
+    }
+
+    #[test]
+    fn invalid_utf8_matches_byte_by_byte() {
+        let latin1 = b"caf\xe9 na\xefve";
+        let regex = Regex::new(r"caf\xe9").unwrap();
+        assert_eq!(regex.find_bytes(latin1).unwrap().range(), 0..4);
+        // Each byte that isn't UTF-8 is one char, whatever follows it.
+        let regex = Regex::new(r"na.ve").unwrap();
+        assert_eq!(regex.find_bytes(latin1).unwrap().as_bytes(), b"na\xefve");
+        // Taken as latin-1, ÿ and þ are word chars.
+        let regex = Regex::new(r"(\w+) (\w+)").unwrap();
+        let captures = regex.captures_bytes(b"\xff\xfe ok \xe2\x82").unwrap();
+        assert_eq!(captures.get(1).unwrap().as_bytes(), b"\xff\xfe");
+        assert_eq!(captures.get(2).unwrap().range(), 3..5);
+        // Valid UTF-8 is still taken a char at a time.
+        let regex = Regex::new("^.$").unwrap();
+        assert!(regex.is_match_bytes("é".as_bytes()));
+        assert!(regex.is_match_bytes(b"\xc3"));
+        assert!(!regex.is_match_bytes(b"\xc3\xa9\xff"));
+        assert!(Regex::new(r"\xg0").is_err());
+    }
+
+    #[test]
+    fn case_insensitive_groups_take_either_case() {
+        let regex = RegexBuilder::new("^[a-c]+[^x-z]$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert!(regex.is_match("aBc1"));
+        assert!(regex.is_match("CBAb"));
+        assert!(!regex.is_match("abX"));
+        assert!(!regex.is_match("abx"));
+        // Ranges that only overlap once folded, and chars beyond ASCII.
+        let regex = RegexBuilder::new("^[Q-c]+$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert!(regex.is_match("qrAZ_`"));
+        let regex = RegexBuilder::new("^[^ä]$")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert!(!regex.is_match("Ä"));
+    }
+
+    #[test]
+    fn word_matches_only_whole_words() {
+        let word = |pattern: &str| RegexBuilder::new(pattern).word(true).build().unwrap();
+        assert!(word("cat").is_match("a cat sat"));
+        assert!(word("cat").is_match("cat"));
+        assert!(!word("cat").is_match("cats"));
+        assert!(!word("cat").is_match("concat"));
+        assert!(word("cat|dog").is_match("hotdog dog"));
+        assert_eq!(word("a+").find("baa aa").unwrap().range(), 4..6);
+        assert_eq!(word("ab|a").find("abc a").unwrap().range(), 4..5);
+        // What's at either end of the match needn't be a word char itself.
+        assert!(word("@").is_match("a @ b"));
+        assert!(!word("@").is_match("a@b"));
+        assert!(!word("foo ").is_match("foo bar"));
+        assert!(word("foo ").is_match("foo  bar"));
+    }
+
+    #[test]
+    fn line_matches_only_whole_lines() {
+        let line = |pattern: &str| RegexBuilder::new(pattern).line(true).build().unwrap();
+        assert!(line("a|ab").is_match("ab"));
+        assert!(!line("ab").is_match("abc"));
+        assert!(!line("ab").is_match("cab"));
+        let regex = RegexBuilder::new("ab")
+            .line(true)
+            .crlf(true)
+            .build()
+            .unwrap();
+        assert!(regex.is_match("ab\r"));
+        // It makes `word` moot.
+        let regex = RegexBuilder::new("a ")
+            .line(true)
+            .word(true)
+            .build()
+            .unwrap();
+        assert!(regex.is_match("a "));
+    }
+
+    #[test]
+    fn syntaxes_differ_in_what_takes_a_backslash() {
+        let find = |syntax, pattern: &str, haystack: &str| {
+            let regex = RegexBuilder::new(pattern).syntax(syntax).build().unwrap();
+            regex.find(haystack).map(|found| found.as_str().to_string())
+        };
+        assert_eq!(
+            find(Syntax::Basic, "a+(b|c)?", "xa+(b|c)?"),
+            Some("a+(b|c)?".into())
+        );
+        assert_eq!(
+            find(Syntax::Basic, r"\(a\|b\)\+", "xabba"),
+            Some("abba".into())
+        );
+        assert_eq!(find(Syntax::Basic, "*a", "x*a"), Some("*a".into()));
+        assert!(RegexBuilder::new(r"a\{2\}")
+            .syntax(Syntax::Basic)
+            .build()
+            .is_err());
+        assert_eq!(
+            find(Syntax::Extended, r"\(a+\)", "(aa)"),
+            Some("(aa)".into())
+        );
+        assert_eq!(
+            find(Syntax::Extended, r"a\.b", "axb a.b"),
+            Some("a.b".into())
+        );
+
+        assert_eq!(find(Syntax::Perl, r"\s\S", "ab\tc"), Some("\tc".into()));
+        assert_eq!(
+            find(Syntax::Perl, r"\t\e", "a\t\x1b"),
+            Some("\t\x1b".into())
+        );
+        assert_eq!(find(Syntax::Perl, r"\D+", "12ab3"), Some("ab".into()));
+        // `\d` only knows ASCII digits unless `(?u)` says otherwise.
+        assert_eq!(find(Syntax::Perl, r"\d+", "٣4"), Some("4".into()));
+        assert_eq!(find(Syntax::Perl, r"(?u)\d+", "٣4"), Some("٣4".into()));
+        // Their capitals match the rest, so with it they leave those out.
+        assert_eq!(find(Syntax::Perl, r"\D+", "a٣4"), Some("a٣".into()));
+        assert_eq!(find(Syntax::Perl, r"(?u)\D+", "a٣4"), Some("a".into()));
+        assert_eq!(find(Syntax::Perl, r"\W+", "é!a"), Some("é!".into()));
+        assert_eq!(find(Syntax::Perl, r"(?u)\W+", "é!a"), Some("!".into()));
+        assert!(RegexBuilder::new(r"\s").build().is_err());
+    }
+
+    #[test]
+    fn perl_escapes_work_in_brackets_too() {
+        let find = |pattern: &str, haystack: &str| {
+            let regex = RegexBuilder::new(pattern)
+                .syntax(Syntax::Perl)
+                .build()
+                .unwrap();
+            regex.find(haystack).map(|found| found.as_str().to_string())
+        };
+        assert_eq!(find(r"[\d]+", "x5"), Some("5".into()));
+        assert_eq!(find(r"[\w.]+", "!a.b_1!"), Some("a.b_1".into()));
+        assert_eq!(find(r"[\s,]+", "a, \tb"), Some(", \t".into()));
+        assert_eq!(find(r"[\D]+", "12ab3"), Some("ab".into()));
+        assert_eq!(find(r"[^\W]+", "!ab!"), Some("ab".into()));
+        assert_eq!(find(r"[\S]+", " ab "), Some("ab".into()));
+        assert_eq!(find(r"[^\d\s]+", "1 ab 2"), Some("ab".into()));
+        assert_eq!(find(r"[\n\t]", "a\tb"), Some("\t".into()));
+        assert_eq!(
+            find(r"[\e\f\v\r]+", "a\x1b\x0c\x0b\rb"),
+            Some("\x1b\x0c\x0b\r".into())
+        );
+        assert_eq!(find(r"[\x41-\x43]+", "xABCD"), Some("ABC".into()));
+        assert_eq!(find(r"[\]\\\-]+", "a]\\-b"), Some("]\\-".into()));
+        // After a class a hyphen is just a hyphen.
+        assert_eq!(find(r"[\d-z]+", "a-z1b"), Some("-z1".into()));
+        // The classes follow `(?u)` as they do outside brackets.
+        assert_eq!(find(r"[\d]+", "٣4"), Some("4".into()));
+        assert_eq!(find(r"(?u)[\d]+", "٣4"), Some("٣4".into()));
+        assert_eq!(find(r"(?u)[^\w!]+", "é!a?"), Some("?".into()));
+        assert_eq!(find(r"(?u)[\W]+", "é!a"), Some("!".into()));
+        for pattern in [r"[a-\d]", r"[\q]"] {
+            let built = RegexBuilder::new(pattern).syntax(Syntax::Perl).build();
+            assert!(built.is_err(), "{pattern}");
+        }
+        // Elsewhere a backslash in brackets is only a backslash.
+        assert_eq!(
+            RegexBuilder::new(r"[\d]+")
+                .build()
+                .unwrap()
+                .find("d\\5")
+                .unwrap()
+                .as_str(),
+            "d\\"
+        );
+    }
+
+    #[test]
+    fn posix_takes_only_what_posix_has() {
+        let build = |syntax, pattern: &str| {
+            RegexBuilder::new(pattern)
+                .syntax(syntax)
+                .posix(true)
+                .build()
+        };
+        for pattern in [r"\d", r"\w+", r"a\b", "(?i)a", "(?<x>a)", r"(a)\1", "a{2}"] {
+            assert!(build(Syntax::Extended, pattern).is_err(), "{pattern:?}");
+        }
+        assert!(build(Syntax::Basic, r"\(a\)\1").unwrap().is_match("aa"));
+        assert!(build(Syntax::Extended, r"\.\(").unwrap().is_match(".("));
+        // The longest alternative wins, not the first.
+        let regex = build(Syntax::Extended, "a|ab").unwrap();
+        assert_eq!(regex.find("ab").unwrap().as_str(), "ab");
+    }
+
+    #[test]
+    fn dump_lists_the_tree_then_the_bytecode() {
+        let dump = Regex::new("a(b|c)").unwrap().dump();
+        assert!(
+            dump.starts_with("tree:\n  List\n    Literal('a')\n    CaptureGroup(1)\n"),
+            "{dump}"
+        );
+        assert!(dump.contains("program:\n   0  Save(0)\n"), "{dump}");
+        assert!(dump.ends_with("Match\n"), "{dump}");
+    }
+
+    #[test]
+    fn any_pattern_can_match() {
+        let regex = RegexBuilder::new_any(["cat", "d(o)g"]).build().unwrap();
+        assert!(regex.is_match("hotdog"));
+        assert!(regex.is_match("a cat"));
+        assert!(!regex.is_match("cow"));
+        // Each pattern keeps its own group numbering.
+        let regex = RegexBuilder::new_any([r"(a)\1", r"(b)\1"]).build().unwrap();
+        assert!(regex.is_match("xbb"));
+        assert!(!regex.is_match("ba"));
+        let captures = regex.captures("bb").unwrap();
+        assert_eq!(
+            (captures.len(), captures.get(2).unwrap().as_str()),
+            (3, "b")
+        );
+        let none = RegexBuilder::new_any(Vec::<String>::new()).build().unwrap();
+        assert!(!none.is_match("") && !none.is_match("a"));
+        let regex = RegexBuilder::new_any(["a.", "[b]"])
+            .fixed_string(true)
+            .build()
+            .unwrap();
+        assert!(regex.is_match("x[b]") && regex.is_match("a.") && !regex.is_match("ab"));
+    }
+
+    #[test]
+    fn crlf_ends_lines_before_the_carriage_return() {
+        let crlf = |pattern: &str| RegexBuilder::new(pattern).crlf(true).build().unwrap();
+        assert!(crlf("cat$").is_match("a cat\r"));
+        assert!(!Regex::new("cat$").unwrap().is_match("a cat\r"));
+        assert!(Regex::new("(?R)cat$").unwrap().is_match("a cat\r"));
+        assert!(!crlf("cat$").is_match("a cat\rx"));
+        // The `\r` is never part of what matched.
+        assert_eq!(crlf(".+").find("ab\r").unwrap().as_str(), "ab");
+        assert_eq!(crlf(".+$").find("ab\r").unwrap().as_str(), "ab");
+        assert_eq!(
+            Regex::new(".+").unwrap().find("ab\r").unwrap().as_str(),
+            "ab\r"
+        );
+    }
+
+    #[test]
+    fn empty_patterns_match_the_empty_string() {
+        let find = |pattern: &str, haystack: &str| {
+            let regex = Regex::new(pattern).unwrap();
+            regex.find(haystack).map(|m| m.range())
+        };
+        assert_eq!(find("", ""), Some(0..0));
+        assert_eq!(find("", "abc"), Some(0..0));
+        assert_eq!(find("x|", "abc"), Some(0..0));
+        assert_eq!(find("|b", "abc"), Some(0..0));
+        assert_eq!(find("a()c", "abc ac"), Some(4..6));
+        assert_eq!(find("a(|b)c", "abc"), Some(0..3));
+    }
+
+    #[test]
+    fn group_0_is_the_whole_match() {
+        let regex = Regex::new(r"(\d+)-(\d+)").unwrap();
+        assert_eq!(regex.captures_len(), 3);
+        let captures = regex.captures("on 2024-10").unwrap();
+        assert_eq!(captures.len(), 3);
+        assert_eq!(captures.get(0).unwrap().as_str(), "2024-10");
+        assert_eq!(captures.get(1).unwrap().as_str(), "2024");
+        assert_eq!(captures.get_all(0).len(), 1);
+        let captures = regex.captures_bytes(b"on 2024-10").unwrap();
+        assert_eq!(captures.get(0).unwrap().range(), 3..10);
+        // Even with no groups of its own.
+        let captures = Regex::new("b").unwrap().captures("abc").unwrap();
+        assert_eq!(captures.len(), 1);
+        assert_eq!(captures.get(0).unwrap().range(), 1..2);
+        assert!(Regex::new(r"(a)\0").is_err());
+    }
+
+    #[test]
+    fn start_anchors_only_match_at_the_start() {
+        let starts = |pattern: &str, haystack: &str| {
+            let regex = Regex::new(pattern).unwrap();
+            regex
+                .find_iter(haystack)
+                .map(|m| m.start())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(starts("^a", "aaa"), [0]);
+        assert_eq!(starts(r"\Aa", "aaa"), [0]);
+        assert_eq!(starts(r"\Aa", "baa"), []);
+        assert_eq!(starts("^a|^b", "ba"), [0]);
+        assert_eq!(starts("(^a)+", "aab"), [0]);
+        // Only some of the alternatives are anchored, so the others are
+        // still tried everywhere.
+        assert_eq!(starts("^a|b", "cbab"), [1, 3]);
+        assert_eq!(starts("b^a", "ba"), []);
+    }
+
+    #[test]
+    fn misplaced_quantifiers_are_typed_errors() {
+        let error = |pattern: &str| {
+            let Err(error) = Regex::new(pattern) else {
+                panic!("{pattern} compiled");
+            };
+            error.downcast::<QuantifierError>().unwrap()
+        };
+        assert_eq!(
+            error("*a"),
+            QuantifierError::NothingToRepeat { quantifier: '*' }
+        );
+        assert_eq!(
+            error("a|?"),
+            QuantifierError::NothingToRepeat { quantifier: '?' }
+        );
+        assert_eq!(
+            error("^+a"),
+            QuantifierError::NotQuantifiable {
+                quantifier: '+',
+                item: "a start anchor"
+            }
+        );
+        assert_eq!(
+            error(r"a\b*"),
+            QuantifierError::NotQuantifiable {
+                quantifier: '*',
+                item: "a word boundary"
+            }
+        );
+        assert_eq!(
+            error("a+?"),
+            QuantifierError::Stacked {
+                quantifier: '?',
+                previous: '+'
+            }
+        );
+        assert!(Regex::new("(a+)?").is_ok());
+    }
+
+    #[test]
+    fn longest_alternative_prefers_the_longest_branch() {
+        let find = |pattern: &str, haystack: &str, longest: bool| {
+            let regex = RegexBuilder::new(pattern)
+                .longest_alternative(longest)
+                .build()
+                .unwrap();
+            regex.find(haystack).unwrap().as_str().to_string()
+        };
+        assert_eq!(find("a|ab", "abc", false), "a");
+        assert_eq!(find("a|ab", "abc", true), "ab");
+        // Branches still have to leave the rest of the pattern a match.
+        assert_eq!(find("(a|ab)c", "abc", true), "abc");
+        assert_eq!(find("(ab|a)bc", "abc", true), "abc");
+        // Ties go to the earlier branch.
+        let regex = RegexBuilder::new("(a.)|(.b)")
+            .longest_alternative(true)
+            .build()
+            .unwrap();
+        let captures = regex.captures("ab").unwrap();
+        assert!(captures.get(1).is_some());
+        assert!(captures.get(2).is_none());
+    }
+
+    #[test]
+    fn misplaced_anchors_can_be_literal() {
+        let is_match = |pattern: &str, haystack: &str| {
+            let regex = RegexBuilder::new(pattern)
+                .literal_misplaced_anchors(true)
+                .build()
+                .unwrap();
+            regex.is_match(haystack)
+        };
+        assert!(is_match("a^b", "a^b"));
+        assert!(is_match("a$b", "a$b"));
+        assert!(is_match("^a$", "a"));
+        assert!(!is_match("^a$", "ab"));
+        assert!(is_match("(^a|b$)", "cb"));
+        assert!(is_match("*a", "*a"));
+        assert!(is_match("^*", "*"));
+        assert!(!Regex::new("a^b").unwrap().is_match("a^b"));
+    }
+
+    /// Patterns meant to land on each engine: back references for the
+    /// backtracker and the rest for the Pike VM.
+    const PATTERNS: &[&str] = &[
+        "foo",
+        "foo|foobar",
+        "[a-c]x[0-9]",
+        "[abc]+x",
+        r"\d+-\d+",
+        r"(\d+)-(\d+)",
+        "^(a|b)c",
+        "^(a+)(b*)$",
+        "(a|aa)+b",
+        r"(a+)\1",
+        r"(\w)\1",
+        r"\bfoo\b",
+        r"\w+",
+        "a*",
+        "x?",
+        "(a*)*b",
+        "o$",
+        ".β",
+        "[α-γ]+",
+    ];
+
+    const HAYSTACKS: &[&str] = &[
+        "",
+        "a",
+        "abc",
+        "aab",
+        "aaaab",
+        "bc",
+        "xx a1x bbx 12-34 foo foobar",
+        "food fool, foo.",
+        "aa bb cc dd",
+        "ααβγ δ",
+        "on 2024-10 and 1999-01",
+        "no match here",
+    ];
+
+    /// `pattern` run on the backtracker alone with no prefilter, which every other engine
+    /// has to agree with.
+    fn reference(pattern: &str) -> Regex {
+        let mut regex = Regex::new(pattern).unwrap();
+        regex.program = None;
+        regex.prefilter = None;
+        regex
+    }
+
+    type Groups = Vec<Option<Range<usize>>>;
+
+    type ByHand = &'static [(
+        &'static str,
+        &'static str,
+        &'static [&'static [Option<Range<usize>>]],
+    )];
+
+    fn all_groups(regex: &Regex, haystack: &str) -> Vec<Groups> {
+        regex
+            .captures_iter(haystack)
+            .map(|captures| {
+                (0..captures.len())
+                    .map(|i| captures.get(i).map(|m| m.range()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn engines_agree_with_the_backtracker() {
+        for pattern in PATTERNS {
+            let regex = Regex::new(pattern).unwrap();
+            let expected = reference(pattern);
+            for haystack in HAYSTACKS {
+                let context = format!("{pattern:?} on {haystack:?}");
+                assert_eq!(
+                    regex.is_match(haystack),
+                    expected.is_match(haystack),
+                    "is_match, {context}"
+                );
+                assert_eq!(
+                    regex.find(haystack).map(|m| m.range()),
+                    expected.find(haystack).map(|m| m.range()),
+                    "find, {context}"
+                );
+                assert_eq!(
+                    all_groups(&regex, haystack),
+                    all_groups(&expected, haystack),
+                    "captures_iter, {context}"
+                );
+
+                let bytes = haystack.as_bytes();
+                assert_eq!(
+                    regex.is_match_bytes(bytes),
+                    expected.is_match(haystack),
+                    "is_match_bytes, {context}"
+                );
+                let captures = regex.captures_bytes(bytes).map(|captures| {
+                    (0..captures.len())
+                        .map(|i| captures.get(i).map(|m| m.range()))
+                        .collect::<Groups>()
+                });
+                assert_eq!(
+                    captures,
+                    all_groups(&expected, haystack).into_iter().next(),
+                    "captures_bytes, {context}"
+                );
+            }
+        }
+    }
+
+    /// The groups of every match, worked out by hand, for patterns where
+    /// the reference itself is the likeliest to be wrong: back references,
+    /// which only the backtracker runs, and a loop whose body can match
+    /// empty, which as in Perl ends on one empty iteration.
+    #[test]
+    fn spans_worked_out_by_hand() {
+        let by_hand: ByHand = &[
+            (r"(a+)\1", "aaaab", &[&[Some(0..4), Some(0..2)]]),
+            (r"(a+)\1", "aab", &[&[Some(0..2), Some(0..1)]]),
+            (r"(a+)\1", "abc", &[]),
+            (
+                r"(\w)\1",
+                "food fool, foo.",
+                &[
+                    &[Some(1..3), Some(1..2)],
+                    &[Some(6..8), Some(6..7)],
+                    &[Some(12..14), Some(12..13)],
+                ],
+            ),
+            ("(a*)*b", "aaaab", &[&[Some(0..5), Some(4..4)]]),
+            ("(a*)*b", "bc", &[&[Some(0..1), Some(0..0)]]),
+            (
+                "(a*)*b",
+                "aa bb cc dd",
+                &[&[Some(3..4), Some(3..3)], &[Some(4..5), Some(4..4)]],
+            ),
+        ];
+        for &(pattern, haystack, expected) in by_hand {
+            for (regex, how) in [
+                (reference(pattern), "reference"),
+                (Regex::new(pattern).unwrap(), "dispatch"),
+            ] {
+                assert_eq!(
+                    all_groups(&regex, haystack),
+                    expected,
+                    "{how} {pattern:?} on {haystack:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dfa_falls_back_when_its_cache_fills() {
+        // Knowing whether the char 14 before a c was an a takes a state for
+        // every run of 14 chars seen.
+        let regex = Regex::new(&format!("[ab]*a{}c", "[ab]".repeat(13))).unwrap();
+        let mut seed = 1u32;
+        let haystack = (0..50_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                if seed >> 16 & 1 == 0 {
+                    'a'
+                } else {
+                    'b'
+                }
+            })
+            .collect::<String>();
+        let dfa = regex.dfa.as_ref().unwrap();
+        assert_eq!(
+            dfa.is_match(regex.program.as_ref().unwrap(), &haystack, None),
+            None
+        );
+        let b = "b".repeat(13);
+        assert!(regex.is_match(&format!("{haystack}a{b}c")));
+        assert!(!regex.is_match(&format!("{haystack}b{b}c")));
+    }
+
+    #[test]
+    fn dfa_clears_its_cache_while_it_pays_off() {
+        // Long runs of b keep to one state, and the mix of a and b between
+        // them builds a few new ones each time, so states are used for
+        // plenty of input before the cache fills.
+        let pattern = format!("[ab]*a{}c", "[ab]".repeat(13));
+        let b = "b".repeat(13);
+        let mut seed = 1u32;
+        let haystack = (0..100)
+            .map(|_| {
+                let mix = (0..20)
+                    .map(|_| {
+                        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                        if seed >> 16 & 1 == 0 {
+                            'a'
+                        } else {
+                            'b'
+                        }
+                    })
+                    .collect::<String>();
+                mix + &"b".repeat(1000)
+            })
+            .collect::<String>();
+        for (limit, carries_on) in [(64 << 10, true), (0, false)] {
+            let regex = RegexBuilder::new(&pattern)
+                .dfa_size_limit(limit)
+                .build()
+                .unwrap();
+            let dfa = regex.dfa.as_ref().unwrap();
+            assert_eq!(
+                dfa.is_match(regex.program.as_ref().unwrap(), &haystack, None),
+                carries_on.then_some(false),
+                "limit {limit}"
+            );
+            assert!(regex.is_match(&format!("{haystack}a{b}c")));
+            assert!(!regex.is_match(&format!("{haystack}b{b}c")));
+        }
+    }
+
+    #[test]
+    fn one_pass_runs_only_on_unambiguous_anchored_patterns() {
+        let one_pass = |pattern: &str| Regex::new(pattern).unwrap().onepass.is_some();
+        assert!(one_pass("^(a+)(b*)$"));
+        assert!(one_pass(r"^(\d+)-(\d+)"));
+        // Whether to go round again isn't known from the next char alone.
+        assert!(!one_pass("^(a|b)*b"));
+        assert!(!one_pass("^(a*)(a*)"));
+        // Nor is it run unanchored.
+        assert!(!one_pass("(a+)(b*)"));
+
+        let regex = Regex::new(r"^(\d+)-(\d+)").unwrap();
+        let captures = regex.captures("12-345 6-7").unwrap();
+        assert_eq!(captures.get(1).unwrap().as_str(), "12");
+        assert_eq!(captures.get(2).unwrap().as_str(), "345");
+    }
+
+    #[test]
+    fn prefilter_skips_to_the_literal_prefix() {
+        let regex = Regex::new("foo(bar)+x").unwrap();
+        assert!(regex.prefilter.is_some());
+        let ranges = regex
+            .find_iter("foo foobar foobarbarx foobarx")
+            .map(|m| m.range())
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [11..21, 22..29]);
+        assert!(!regex.is_match("foo foobar fobarx"));
+        // Nothing every match starts with.
+        assert!(Regex::new(".b").unwrap().prefilter.is_none());
+        // Case folding means the literal can't be searched for as it is.
+        let regex = RegexBuilder::new("foo")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert!(regex.is_match("FOO"));
+    }
+
+    #[test]
+    fn literal_alternations_match_through_aho_corasick() {
+        let ranges = |regex: &Regex, haystack: &str| {
+            regex
+                .find_iter(haystack)
+                .map(|m| m.range())
+                .collect::<Vec<_>>()
+        };
+        let regex = Regex::new("foo|foobar|bar").unwrap();
+        assert!(regex.prefilter.is_some());
+        assert_eq!(ranges(&regex, "foobar barfoo"), [0..3, 3..6, 7..10, 10..13]);
+        let regex = RegexBuilder::new("foo|foobar|bar")
+            .longest(true)
+            .build()
+            .unwrap();
+        assert_eq!(ranges(&regex, "foobar barfoo"), [0..6, 7..10, 10..13]);
+        // With a group the match has to come from an engine that sets it.
+        let regex = Regex::new("(foo|bar)x").unwrap();
+        let captures = regex.captures("foo barx").unwrap();
+        assert_eq!(captures.get(0).unwrap().range(), 4..8);
+        assert_eq!(captures.get(1).unwrap().range(), 4..7);
+    }
+
+    #[test]
+    fn fixed_strings_have_no_special_chars() {
+        let fixed = |pattern: &str| {
+            RegexBuilder::new(pattern)
+                .fixed_string(true)
+                .build()
+                .unwrap()
+        };
+        assert_eq!(fixed("a.c").find("abc a.c").unwrap().range(), 4..7);
+        assert!(fixed("(a|b)*").is_match("x(a|b)*"));
+        assert!(!fixed("^a").is_match("a"));
+        assert!(fixed(r"\d").is_match(r"\d"));
+        // Unbalanced groups and stray quantifiers don't fail to parse.
+        assert!(fixed("(*").is_match("(*"));
+        let regex = RegexBuilder::new("abc")
+            .fixed_string(true)
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert!(regex.is_match("xABCx"));
+    }
+
+    #[test]
+    fn back_references_run_on_the_memoized_backtracker() {
+        let regex = Regex::new(r"(a|aa)*(a)\2c").unwrap();
+        assert!(!regex.program.as_ref().unwrap().references.is_empty());
+        // Every way of splitting the as is tried once per position rather
+        // than once per path there.
+        let haystack = "a".repeat(40);
+        assert!(!regex.is_match(&haystack));
+        assert_eq!(regex.find(&format!("{haystack}c")).unwrap().range(), 0..41);
+    }
+
+    #[test]
+    fn backtracking_gives_up_at_the_limit() {
+        let haystack = format!("{}bc", "a".repeat(30));
+        let limited = |limit| {
+            RegexBuilder::new(r"(a|aa)*(a)\2c")
+                .backtrack_limit(limit)
+                .build()
+                .unwrap()
+        };
+        let error = limited(100).try_is_match(&haystack).unwrap_err();
+        assert_eq!((error.limit(), error.timeout()), (Some(100), None));
+        assert_eq!(limited(100).try_find(&haystack), Err(error));
+        assert_eq!(limited(1_000_000).try_is_match(&haystack), Ok(false));
+        // A match found before the limit is still a match.
+        assert_eq!(limited(100).try_is_match("aac"), Ok(true));
+
+        // The tree backtracker counts its steps too.
+        let mut regex = limited(100);
+        regex.program = None;
+        assert!(regex.try_captures(&haystack).is_err());
+    }
+
+    #[test]
+    fn backtracking_gives_up_at_the_timeout() {
+        let timeout = Duration::from_millis(10);
+        let mut regex = RegexBuilder::new(r"(a|aa)*(a)\2c")
+            .timeout(timeout)
+            .build()
+            .unwrap();
+        // Left to the tree backtracker the as split every which way, which
+        // takes far longer than the timeout.
+        regex.program = None;
+        let haystack = format!("{}bc", "a".repeat(60));
+        let Err(error) = regex.try_is_match(&haystack) else {
+            panic!("a search that can't finish in time finished");
+        };
+        assert_eq!((error.limit(), error.timeout()), (None, Some(timeout)));
+        assert!(error.to_string().contains("gave up after 10ms"));
+        assert_eq!(regex.try_is_match("aac"), Ok(true));
+    }
+
+    #[test]
+    fn try_find_iter_stops_at_the_limit() {
+        let regex = RegexBuilder::new(r"(a|aa)*(a)\2c")
+            .backtrack_limit(100)
+            .build()
+            .unwrap();
+        let haystack = format!("aac {}bc", "a".repeat(30));
+        let mut found = regex.try_find_iter_bytes(haystack.as_bytes());
+        assert_eq!(found.next().unwrap().unwrap().as_bytes(), b"aac");
+        assert!(found.next().unwrap().is_err());
+        assert!(found.next().is_none());
+
+        let regex = Regex::new("a*").unwrap();
+        let found = regex
+            .try_find_iter_bytes(b"baa\xffa")
+            .map(|found| found.unwrap().range())
+            .collect::<Vec<_>>();
+        assert_eq!(found, [0..0, 1..3, 4..5]);
+    }
+
+    #[test]
+    fn try_captures_iter_gives_each_match_groups() {
+        let regex = Regex::new("(a)|(b)").unwrap();
+        let found = regex
+            .try_captures_iter_bytes(b"a\xffb")
+            .map(|captures| {
+                let captures = captures.unwrap();
+                (1..captures.len())
+                    .map(|i| captures.get(i).map(|group| group.range()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(found, [[Some(0..1), None], [None, Some(2..3)]]);
+    }
+
+    #[test]
+    fn named_groups_are_listed_and_kept_when_saved() {
+        let regex = Regex::new(r"(?<year>\d+)-(\d+)-(?P<day>\d+)").unwrap();
+        let names = [None, Some("year"), None, Some("day")];
+        assert!(regex.capture_names().eq(names));
+        let captures = regex.captures("on 2024-05-17").unwrap();
+        assert_eq!(captures.get(3).unwrap().as_str(), "17");
+        let loaded = Regex::from_bytes(&regex.to_bytes()).unwrap();
+        assert!(loaded.capture_names().eq(names));
+
+        for pattern in ["(?<a>x)(?<a>y)", "(?<>x)", "(?<a-b>x)", "(?<a"] {
+            assert!(Regex::new(pattern).is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn optimizing_factors_alternatives_and_joins_literals() {
+        use crate::pattern::{
+            EndFlags, Flags,
+            Pattern::{self, *},
+        };
+
+        let optimized = |pattern: &str| {
+            Pattern::parse_either(
+                &mut pattern.chars().peekable(),
+                EndFlags::empty(),
+                &mut Flags::default(),
+                &mut 1,
+                None,
+            )
+            .unwrap()
+            .optimize()
+        };
+        assert_eq!(
+            optimized("abc|abd|x"),
+            Either(vec![
+                List(vec![
+                    String("ab".into()),
+                    Either(vec![Literal('c'), Literal('d')]),
+                ]),
+                Literal('x'),
+            ])
+        );
+        assert_eq!(
+            optimized("((ab))c"),
+            List(vec![
+                CaptureGroup {
+                    id: 1,
+                    name: None,
+                    item: Box::new(CaptureGroup {
+                        id: 2,
+                        name: None,
+                        item: Box::new(String("ab".into())),
+                    }),
+                },
+                Literal('c'),
+            ])
+        );
+        let star = |item: Pattern| ZeroOrMore(Box::new(item));
+        assert_eq!(optimized("(?:a?)+"), star(Literal('a')));
+
+        let ranges = Regex::new("abc|abd|x")
+            .unwrap()
+            .find_iter("abd abc x ab")
+            .map(|m| m.range())
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [0..3, 4..7, 8..9]);
+    }
+
+    #[test]
+    fn is_match_skips_groups_without_the_dfa() {
+        // The DFA can't see past a `\r` to the end of the line.
+        let regex = RegexBuilder::new("(a|b)+(c)$").crlf(true).build().unwrap();
+        assert!(regex.dfa.is_none());
+        for (haystack, matched) in [
+            ("xabc", true),
+            ("abc\r", true),
+            ("abcd", false),
+            ("c", false),
+        ] {
+            assert_eq!(regex.is_match(haystack), matched, "{haystack:?}");
+            assert_eq!(regex.find(haystack).is_some(), matched, "{haystack:?}");
+        }
+    }
+
+    #[test]
+    fn inputs_shorter_than_any_match_are_skipped() {
+        let min_len = |pattern: &str| Regex::new(pattern).unwrap().min_len;
+        assert_eq!(min_len("ab+c"), 3);
+        assert_eq!(min_len("a|bcd"), 1);
+        assert_eq!(min_len("(ab)?x*"), 0);
+        assert_eq!(min_len(r"(ab)\1"), 2);
+        assert_eq!(min_len("é"), 2);
+
+        let regex = Regex::new(r"\w\w\w").unwrap();
+        assert!(!regex.is_match("ab"));
+        assert!(regex.is_match("abc"));
+        let ranges = regex
+            .find_iter("abcdefgh")
+            .map(|m| m.range())
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [0..3, 3..6]);
+    }
+
+    #[test]
+    fn missing_literal_suffixes_reject_the_input() {
+        let suffix = |pattern: &str| {
+            let ir = Regex::new(pattern).unwrap().ir;
+            ir.literal_suffix(ir.root()).0
+        };
+        assert_eq!(suffix(r"\w+ing|\d+ing"), "ing");
+        assert_eq!(suffix("a(bc|dc)"), "c");
+        assert_eq!(suffix(r"x+\b"), "x");
+        assert_eq!(suffix("ab|cd"), "");
+
+        let regex = Regex::new(r"\w+ing").unwrap();
+        assert!(regex.suffix.is_some());
+        assert!(!regex.is_match("walked talked"));
+        let found = regex
+            .find_iter("sing walking talk")
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(found, ["sing", "walking"]);
+    }
+
+    #[test]
+    fn shift_or_runs_fixed_length_class_sequences() {
+        let shiftor = |pattern: &str| Regex::new(pattern).unwrap().shiftor.is_some();
+        assert!(shiftor(r"[a-c]x\d"));
+        assert!(shiftor(&".".repeat(64)));
+        assert!(!shiftor(&".".repeat(65)));
+        assert!(!shiftor("[a-c]+x"));
+        assert!(!shiftor(r"([a-c])x"));
+
+        let regex = Regex::new(r"[a-c]x\d").unwrap();
+        let ranges = regex
+            .find_iter("ax bx1 cx2cx3 dx4")
+            .map(|m| m.range())
+            .collect::<Vec<_>>();
+        assert_eq!(ranges, [3..6, 7..10, 10..13]);
+        // Chars past ASCII go through the same table lookups.
+        let regex = Regex::new("[α-γ].β").unwrap();
+        assert_eq!(regex.find("ααδβ").unwrap().as_str(), "αδβ");
+    }
+
+    #[test]
+    fn byte_sets_agree_with_a_scan_byte_by_byte() {
+        use crate::byteset::ByteSet;
+
+        let mut ascii = [false; 128];
+        for b in [b'A', b'Z', b'_', b'~', 0] {
+            ascii[b as usize] = true;
+        }
+        for non_ascii in [false, true] {
+            let set = ByteSet::new(ascii, non_ascii);
+            // Every position in and around the 16 and 32 byte blocks, for
+            // each kind of byte, including ones only their low or high
+            // nibble puts in the set.
+            for len in 0..80 {
+                for at in 0..len {
+                    for b in [b'A', b'~', 0, b'Q', b'^', b'a', 0xC3, 0xA9] {
+                        let mut haystack = vec![b'.'; len];
+                        haystack[at] = b;
+                        let expected = haystack.iter().position(|&b| {
+                            if b.is_ascii() {
+                                ascii[b as usize]
+                            } else {
+                                non_ascii && b >= 0xC0
+                            }
+                        });
+                        assert_eq!(set.find(&haystack), expected, "{b:#x} at {at} of {len}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn leading_classes_get_a_first_byte_prefilter() {
+        let regex = Regex::new(r"[A-Z_]\w+").unwrap();
+        assert!(regex.prefilter.is_some());
+        let haystack = format!("{}Foo éx _y", "a ".repeat(40));
+        let found = regex
+            .find_iter(&haystack)
+            .map(|m| m.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(found, ["Foo", "_y"]);
+        let regex = Regex::new("[é-ü]x").unwrap();
+        assert_eq!(regex.find(&haystack.replace('é', "ö")).unwrap().start(), 84);
+        assert!(!regex.is_match(&haystack.replace('é', "e")));
+        // Nothing to scan for when a match can be empty.
+        assert!(Regex::new("a*").unwrap().prefilter.is_none());
+    }
+
+    #[test]
+    fn streams_agree_with_the_backtracker() {
+        for pattern in PATTERNS {
+            let regex = Regex::new(pattern).unwrap();
+            let expected = reference(pattern);
+            if regex.stream().is_err() {
+                assert!(regex.dfa.is_none(), "{pattern:?}");
+                continue;
+            }
+            for haystack in HAYSTACKS {
+                let bytes = haystack.as_bytes();
+                // Cut in two at every byte, through chars as well as between.
+                for cut in 0..=bytes.len() {
+                    let mut stream = regex.stream().unwrap();
+                    let fed = stream.feed(&bytes[..cut]) | stream.feed(&bytes[cut..]);
+                    assert_eq!(
+                        fed || stream.finish(),
+                        expected.is_match(haystack),
+                        "stream of {pattern:?} on {haystack:?} cut at {cut}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reused_scratch_starts_clean() {
+        for (pattern, with, without) in [
+            (r"(a)?(\d)", "a1", "c1"),
+            (r"(a)?(\d)\2", "a11", "c11"),
+            ("^(a)?(b|c)", "ab", "c"),
+        ] {
+            let regex = Regex::new(pattern).unwrap();
+            // The groups set by the search before don't show through.
+            assert!(regex.captures(with).unwrap().get(1).is_some());
+            let captures = regex.captures(without).unwrap();
+            assert!(captures.get(1).is_none(), "{pattern:?}");
+        }
+
+        // Iterators that are live at once each have scratch of their own.
+        let regex = Regex::new(r"\d+").unwrap();
+        let mut first = regex.find_iter("1 22 333");
+        let mut second = regex.find_iter("4444 55555");
+        assert_eq!(first.next().unwrap().as_str(), "1");
+        assert_eq!(second.next().unwrap().as_str(), "4444");
+        assert_eq!(first.next().unwrap().as_str(), "22");
+        assert_eq!(second.next().unwrap().as_str(), "55555");
+        assert_eq!(first.next().unwrap().as_str(), "333");
+        assert!(second.next().is_none());
+    }
+
+    #[test]
+    fn sets_agree_with_the_backtracker() {
+        let set = RegexSet::new(PATTERNS).unwrap();
+        let expected = PATTERNS
+            .iter()
+            .map(|pattern| reference(pattern))
+            .collect::<Vec<_>>();
+        for haystack in HAYSTACKS {
+            let matched = expected
+                .iter()
+                .enumerate()
+                .filter_map(|(i, regex)| regex.is_match(haystack).then_some(i))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                set.matches(haystack).iter().collect::<Vec<_>>(),
+                matched,
+                "matches on {haystack:?}"
+            );
+            assert_eq!(
+                set.is_match(haystack),
+                !matched.is_empty(),
+                "is_match on {haystack:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn sets_take_the_builder_options() {
+        let set = RegexSetBuilder::new(["foo", "a.c", r"(b)\1"])
+            .case_insensitive(true)
+            .fixed_string(true)
+            .build()
+            .unwrap();
+        assert_eq!(set.len(), 3);
+        let matches = set.matches("FOO A.C abc");
+        assert!(matches.matched(0) && matches.matched(1) && !matches.matched(2));
+        assert!(set.matches_bytes(b"x(b)\\1").matched(2));
+        assert!(!set.matches_bytes(b"\xff").matched_any());
+    }
+
+    #[test]
+    fn loaded_regexes_agree_with_the_backtracker() {
+        for pattern in PATTERNS {
+            let loaded = Regex::from_bytes(&Regex::new(pattern).unwrap().to_bytes()).unwrap();
+            let expected = reference(pattern);
+            for haystack in HAYSTACKS {
+                let context = format!("{pattern:?} on {haystack:?}");
+                assert_eq!(
+                    loaded.find(haystack).map(|m| m.range()),
+                    expected.find(haystack).map(|m| m.range()),
+                    "find, {context}"
+                );
+                assert_eq!(
+                    all_groups(&loaded, haystack),
+                    all_groups(&expected, haystack),
+                    "captures_iter, {context}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_compiled_regexes_are_rejected() {
+        let bytes = RegexBuilder::new(r"(a+)b\1")
+            .backtrack_limit(100)
+            .build()
+            .unwrap()
+            .to_bytes();
+        let loaded = Regex::from_bytes(&bytes).unwrap();
+        assert!(loaded.is_match("xaaba"));
+        assert_eq!(loaded.to_bytes(), bytes);
+
+        for i in 0..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[i] ^= 0x10;
+            assert!(Regex::from_bytes(&damaged).is_err(), "byte {i} flipped");
+        }
+        for len in 0..bytes.len() {
+            assert!(Regex::from_bytes(&bytes[..len]).is_err(), "cut at {len}");
+        }
+    }
+
+    #[test]
+    fn leftmost_longest_against_leftmost_first() {
+        for (pattern, haystack, first, longest_match) in [
+            ("foo|foobar", "a foobar", "foo", "foobar"),
+            ("a|ab|abc", "abcd", "a", "abc"),
+            ("(a|ab)(c|bcd)", "abcd", "abcd", "abcd"),
+            (r"\w+|\w+ \w+", "two words", "two", "two words"),
+        ] {
+            let find = |regex: Regex| regex.find(haystack).unwrap().as_str().to_string();
+            assert_eq!(find(Regex::new(pattern).unwrap()), first, "{pattern:?}");
+            assert_eq!(find(longest(pattern)), longest_match, "{pattern:?}");
+        }
+        // Still leftmost: the longest match is only picked among those that
+        // start first.
+        assert_eq!(ranges(&longest("b|abc"), "abc b"), [0..3, 4..5]);
+    }
+
+    #[test]
+    fn matches_know_their_span() {
+        let regex = Regex::new("β+").unwrap();
+        let found = regex.find("αββγ").unwrap();
+        assert_eq!((found.start(), found.end()), (2, 6));
+        assert_eq!(found.range(), 2..6);
+        assert_eq!(found.as_str(), "ββ");
+        assert_eq!(regex.find("αγ"), None);
+    }
+
+    #[test]
+    fn captures_give_each_groups_text() {
+        let regex = Regex::new(r"(\d+)-(\d+)( y)?").unwrap();
+        let captures = regex.captures("on 2024-10 and x").unwrap();
+        assert_eq!(captures.len(), 4);
+        let text = |i| captures.get(i).map(|m| m.as_str());
+        assert_eq!(text(0), Some("2024-10"));
+        assert_eq!(text(1), Some("2024"));
+        assert_eq!(captures.get(2).unwrap().range(), 8..10);
+        assert_eq!(text(3), None);
+        assert_eq!(text(4), None);
+    }
+
+    #[test]
+    fn find_iter_carries_on_after_each_match() {
+        let regex = Regex::new("ab").unwrap();
+        assert_eq!(ranges(&regex, "ab xab abab"), [0..2, 4..6, 7..9, 9..11]);
+        let regex = Regex::new("aa").unwrap();
+        assert_eq!(ranges(&regex, "aaaaa"), [0..2, 2..4]);
+        assert!(ranges(&regex, "none").is_empty());
+    }
+}
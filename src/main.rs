@@ -1,399 +1,814 @@
 use anyhow::Context;
+use codecrafters_grep::{Regex, RegexBuilder, Syntax};
+use color::Colors;
+use glob::Glob;
+use mmap::Mmap;
+use replace::Template;
+use search::{BinaryFiles, Output, ReadLines, Searcher, Stats};
+use std::collections::VecDeque;
 use std::env;
-use std::io;
-use std::ops::Range;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, IsTerminal, Write};
+use std::iter;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::process;
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::{Duration, Instant};
+use walk::{DirectoryLoop, Walk, WalkOptions};
+
+mod bench;
+mod color;
+mod glob;
+mod ignore;
+mod replace;
+mod search;
+mod walk;
+
+mod mmap;
+mod parallel;
+
+const DEFAULT_BACKTRACK_LIMIT: usize = 10_000_000;
+const DEFAULT_CONTEXT: usize = 2;
+/// How big a file has to be to be mapped rather than read, unless `--mmap`
+/// or `--no-mmap` says otherwise. Mapping a small file costs more than
+/// copying it.
+const MMAP_MIN_LEN: u64 = 1 << 20;
+
+/// The short options followed by a value, which when given together with
+/// others ends them.
+const SHORT_WITH_VALUE: [char; 6] = ['e', 'f', 'A', 'B', 'm', 'j'];
+/// The long options followed by a value, which can be given after `=` too.
+/// The values of `--context` and `--color` are optional, so those only take
+/// one after `=`.
+const LONG_WITH_VALUE: [&str; 20] = [
+    "--after-context",
+    "--before-context",
+    "--max-count",
+    "--max-depth",
+    "--include",
+    "--exclude",
+    "--exclude-dir",
+    "--exclude-from",
+    "--label",
+    "--binary-files",
+    "--group-separator",
+    "--backtrack-limit",
+    "--dfa-size-limit",
+    "--timeout",
+    "--save-compiled",
+    "--load-compiled",
+    "--bench",
+    "--bench-runs",
+    "--replace",
+    "--threads",
+];
+
+enum Pattern {
+    /// Patterns any of which may match.
+    Source(Vec<String>),
+    /// The path of a regex saved with `--save-compiled`.
+    Compiled(String),
+}
+
+// The exit statuses POSIX gives grep.
+const EXIT_MATCH: i32 = 0;
+const EXIT_NO_MATCH: i32 = 1;
+const EXIT_ERROR: i32 = 2;
 
 fn main() {
-    match run() {
-        Ok(ok) => {
-            if ok {
-                process::exit(0);
-            } else {
-                process::exit(1);
-            }
-        }
+    match run(env::args().skip(1).collect()) {
+        Ok(status) => process::exit(status),
         Err(err) => {
-            eprintln!("{err}");
-            process::exit(1);
+            eprintln!("grep: {err:#}");
+            process::exit(EXIT_ERROR);
         }
     }
 }
 
-fn run() -> anyhow::Result<bool> {
-    if !matches!(env::args().nth(1), Some(flag) if flag == "-E") {
-        anyhow::bail!("Expected -E as the first argument.");
-    }
-
-    if let Some(pattern) = env::args().nth(2) {
-        let mut input_line = String::new();
-
-        io::stdin()
-            .read_line(&mut input_line)
-            .context("reading input")?;
-
-        let mut capture_group_count = 0;
-        if let Some(pattern) = Pattern::parse_either(
-            &mut pattern.chars().peekable(),
-            EndFlags::empty(),
-            &mut capture_group_count,
-            None,
-        )? {
-            let mut input_iter = input_line.char_indices().peekable();
-
-            let mut state = Vec::new();
-
-            while input_iter.peek() != None {
-                state.clear();
-                state.resize(capture_group_count, None);
-
-                let mut iter = input_iter.clone();
-                if pattern.matches(&input_line, &mut iter, &mut state) {
-                    return Ok(true);
+fn run(mut args: VecDeque<String>) -> anyhow::Result<i32> {
+    let mut positional = Vec::new();
+    // Given with `-e` and `-f`, in place of the first positional argument.
+    let mut patterns: Option<Vec<String>> = None;
+    let mut print_captures = false;
+    let mut invert = false;
+    let mut only_matching = false;
+    let mut replace = None;
+    // `-q`, `-l` or `-L`, whichever comes last, `-c` and `--json`, settled
+    // into `output` once all the options are read.
+    let mut quiet = false;
+    let mut list_files = None;
+    let mut count = false;
+    let mut json = false;
+    let mut line_number = false;
+    let mut byte_offset = false;
+    let mut max_count = None;
+    let mut after_context = None;
+    let mut before_context = None;
+    let mut context = None;
+    let mut group_separator = Some(String::from("--"));
+    let mut color = ColorChoice::Never;
+    let mut binary_files = BinaryFiles::default();
+    let mut line_terminator = b'\n';
+    let mut null_after_name = false;
+    let mut with_filename = None;
+    let mut no_messages = false;
+    let mut line_buffered = false;
+    let mut stats = false;
+    let mut recursive = false;
+    let mut threads = None;
+    let mut walk_options = WalkOptions::default();
+    let mut unrestricted = 0;
+    // Basic like POSIX grep unless `-E` or `-P` says otherwise.
+    let mut syntax = Syntax::Basic;
+    let mut posix = false;
+    let mut case_insensitive = false;
+    let mut word = false;
+    let mut line = false;
+    let mut fixed_strings = false;
+    let mut crlf = false;
+    let mut backtrack_limit = DEFAULT_BACKTRACK_LIMIT;
+    let mut dfa_size_limit = None;
+    let mut timeout = None;
+    let mut save_compiled = None;
+    let mut load_compiled = None;
+    let mut debug = false;
+    let mut trace = false;
+    let mut check = false;
+    let mut bench_dir = None;
+    let mut bench_runs = bench::DEFAULT_RUNS;
+    let mut label = String::from("(standard input)");
+    let mut mmap = None;
+    // Bundled short options are split up in place, so there's no iterating
+    // over the arguments.
+    while let Some(arg) = args.pop_front() {
+        match arg.as_str() {
+            "--captures" => print_captures = true,
+            "-e" => patterns
+                .get_or_insert_with(Vec::new)
+                .push(args.pop_front().context("Expected a pattern after '-e'.")?),
+            "-f" => {
+                let path = args.pop_front().context("Expected a path after '-f'.")?;
+                let contents = if path == "-" {
+                    io::read_to_string(io::stdin())
                 } else {
-                    input_iter.next();
+                    fs::read_to_string(&path)
+                }
+                .with_context(|| format!("reading {path}"))?;
+                // One pattern per line, so an empty file has none, and
+                // matches nothing, while an empty line matches everything.
+                let patterns = patterns.get_or_insert_with(Vec::new);
+                if !contents.is_empty() {
+                    let contents = contents.strip_suffix('\n').unwrap_or(&contents);
+                    patterns.extend(contents.split('\n').map(String::from));
                 }
             }
-
-            Ok(false)
-        } else {
-            Ok(true)
-        }
-    } else {
-        anyhow::bail!("No pattern provided.");
-    }
-}
-
-trait CharsIterExt {
-    fn expect(&mut self) -> anyhow::Result<char>;
-}
-
-impl<I> CharsIterExt for I
-where
-    I: Iterator<Item = char>,
-{
-    fn expect(&mut self) -> anyhow::Result<char> {
-        if let Some(c) = self.next() {
-            Ok(c)
-        } else {
-            Err(anyhow::anyhow!("expected a character, but ran out."))
-        }
-    }
-}
-
-bitflags::bitflags! {
-    #[derive(Clone, Copy)]
-    struct EndFlags: u8 {
-        const RPAREN = 1 << 0;
-        const PIPE = 1 << 1;
-    }
-}
-
-type PatternIter<'a> = std::iter::Peekable<std::str::Chars<'a>>;
-type InputIter<'a> = std::iter::Peekable<std::str::CharIndices<'a>>;
-
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum Pattern {
-    Literal(char),
-    Digit,
-    Alphanumeric,
-    CharacterGroup { positive: bool, group: String },
-    StartAnchor,
-    EndAnchor,
-    OneOrMore(Box<Pattern>),
-    ZeroOrOne(Box<Pattern>),
-    Wildcard,
-    List(Vec<Pattern>),
-    Either(Vec<Pattern>),
-    Reference(usize),
-    CaptureGroup { id: usize, item: Box<Pattern> },
-}
-
-impl Pattern {
-    fn parse_either(
-        iter: &mut PatternIter,
-        end: EndFlags,
-        capture_group_count: &mut usize,
-        parent_capture_group: Option<usize>,
-    ) -> anyhow::Result<Option<Self>> {
-        let mut pattern = None;
-
-        while let Some(item) = Self::parse_list(
-            iter,
-            end | EndFlags::PIPE,
-            capture_group_count,
-            parent_capture_group,
-        )? {
-            pattern = if let Some(pattern) = pattern.take() {
-                if let Pattern::Either(mut items) = pattern {
-                    items.push(item);
-                    Some(Pattern::Either(items))
-                } else {
-                    let items = vec![pattern, item];
-                    Some(Pattern::Either(items))
+            "-v" => invert = true,
+            "-o" => only_matching = true,
+            "--replace" => {
+                replace = Some(
+                    args.pop_front()
+                        .context("Expected a template after '--replace'.")?,
+                );
+            }
+            "-c" => count = true,
+            "-l" => list_files = Some(Output::FilesWithMatches),
+            "-L" => list_files = Some(Output::FilesWithoutMatch),
+            "-q" => quiet = true,
+            "--json" => json = true,
+            "--stats" => stats = true,
+            "-n" => line_number = true,
+            "-b" => byte_offset = true,
+            "-A" | "--after-context" => {
+                after_context = Some(
+                    args.pop_front()
+                        .with_context(|| format!("Expected a number after '{arg}'."))?
+                        .parse()
+                        .with_context(|| format!("Invalid value for '{arg}'."))?,
+                );
+            }
+            "-B" | "--before-context" => {
+                before_context = Some(
+                    args.pop_front()
+                        .with_context(|| format!("Expected a number after '{arg}'."))?
+                        .parse()
+                        .with_context(|| format!("Invalid value for '{arg}'."))?,
+                );
+            }
+            // The number is optional here, unlike for `-A` and `-B`.
+            "-C" | "--context" => {
+                let count = args.front().and_then(|arg| arg.parse().ok());
+                if count.is_some() {
+                    args.pop_front();
                 }
-            } else {
-                Some(item)
-            };
-
-            if let Some(c) = iter.peek().copied() {
-                if c == '|' {
-                    iter.next();
+                context = Some(count.unwrap_or(DEFAULT_CONTEXT));
+            }
+            "-m" | "--max-count" => {
+                max_count = Some(
+                    args.pop_front()
+                        .with_context(|| format!("Expected a number after '{arg}'."))?
+                        .parse()
+                        .with_context(|| format!("Invalid value for '{arg}'."))?,
+                );
+            }
+            "-r" => recursive = true,
+            "--no-ignore" => walk_options.no_ignore = true,
+            "--hidden" => walk_options.hidden = true,
+            // Each `-u` lifts one more filter, whether given apart or together.
+            "-u" | "--unrestricted" => {
+                unrestricted += 1;
+                match unrestricted {
+                    1 => walk_options.no_ignore = true,
+                    2 => walk_options.hidden = true,
+                    3 => binary_files = BinaryFiles::Text,
+                    _ => {}
+                }
+            }
+            "-R" => {
+                recursive = true;
+                walk_options.follow_links = true;
+            }
+            "-j" | "--threads" => {
+                threads = Some(
+                    args.pop_front()
+                        .with_context(|| format!("Expected a number after '{arg}'."))?
+                        .parse::<NonZeroUsize>()
+                        .with_context(|| format!("Invalid value for '{arg}'."))?,
+                );
+            }
+            "--max-depth" => {
+                walk_options.max_depth = Some(
+                    args.pop_front()
+                        .context("Expected a number after '--max-depth'.")?
+                        .parse()
+                        .context("Invalid value for '--max-depth'.")?,
+                );
+            }
+            "--include" => {
+                let glob = args
+                    .pop_front()
+                    .context("Expected a glob after '--include'.")?;
+                walk_options.include.push(Glob::new(&glob));
+            }
+            "--exclude" => {
+                let glob = args
+                    .pop_front()
+                    .context("Expected a glob after '--exclude'.")?;
+                walk_options.exclude.push(Glob::new(&glob));
+            }
+            "--exclude-dir" => {
+                let glob = args
+                    .pop_front()
+                    .context("Expected a glob after '--exclude-dir'.")?;
+                walk_options.exclude_dir.push(Glob::new(&glob));
+            }
+            "--exclude-from" => {
+                let path = args
+                    .pop_front()
+                    .context("Expected a path after '--exclude-from'.")?;
+                let globs = fs::read_to_string(&path).with_context(|| format!("reading {path}"))?;
+                walk_options
+                    .exclude
+                    .extend(globs.lines().filter(|glob| !glob.is_empty()).map(Glob::new));
+            }
+            "-G" => syntax = Syntax::Basic,
+            "-E" => syntax = Syntax::Extended,
+            "-P" => syntax = Syntax::Perl,
+            "--posix" => posix = true,
+            "-i" => case_insensitive = true,
+            "-w" => word = true,
+            "-x" => line = true,
+            "-F" => fixed_strings = true,
+            "--crlf" => crlf = true,
+            "--backtrack-limit" => {
+                backtrack_limit = args
+                    .pop_front()
+                    .context("Expected a number after '--backtrack-limit'.")?
+                    .parse()
+                    .context("Invalid value for '--backtrack-limit'.")?;
+            }
+            "--dfa-size-limit" => {
+                dfa_size_limit = Some(
+                    args.pop_front()
+                        .context("Expected a number after '--dfa-size-limit'.")?
+                        .parse()
+                        .context("Invalid value for '--dfa-size-limit'.")?,
+                );
+            }
+            "--timeout" => {
+                let millis = args
+                    .pop_front()
+                    .context("Expected a number of milliseconds after '--timeout'.")?
+                    .parse()
+                    .context("Invalid value for '--timeout'.")?;
+                timeout = Some(Duration::from_millis(millis));
+            }
+            "--save-compiled" => {
+                save_compiled = Some(
+                    args.pop_front()
+                        .context("Expected a path after '--save-compiled'.")?,
+                );
+            }
+            "--load-compiled" => {
+                load_compiled = Some(
+                    args.pop_front()
+                        .context("Expected a path after '--load-compiled'.")?,
+                );
+            }
+            "--debug" => debug = true,
+            "--trace" => trace = true,
+            "--check" => check = true,
+            "--bench" => {
+                bench_dir = Some(PathBuf::from(
+                    args.pop_front()
+                        .context("Expected a directory after '--bench'.")?,
+                ));
+            }
+            "--bench-runs" => {
+                bench_runs = args
+                    .pop_front()
+                    .context("Expected a number after '--bench-runs'.")?
+                    .parse()
+                    .context("Invalid value for '--bench-runs'.")?;
+            }
+            "--no-group-separator" => group_separator = None,
+            "--group-separator" => {
+                group_separator = Some(
+                    args.pop_front()
+                        .context("Expected a separator after '--group-separator'.")?,
+                );
+            }
+            "-z" | "--null-data" => line_terminator = b'\0',
+            "-Z" | "--null" => null_after_name = true,
+            "-H" => with_filename = Some(true),
+            "-h" => with_filename = Some(false),
+            "-s" => no_messages = true,
+            "--line-buffered" => line_buffered = true,
+            "--mmap" => mmap = Some(true),
+            "--no-mmap" => mmap = Some(false),
+            "-a" => binary_files = BinaryFiles::Text,
+            "-I" => binary_files = BinaryFiles::WithoutMatch,
+            "--binary-files" => {
+                let value = args
+                    .pop_front()
+                    .context("Expected a type after '--binary-files'.")?;
+                binary_files = match value.as_str() {
+                    "binary" => BinaryFiles::Binary,
+                    "text" => BinaryFiles::Text,
+                    "without-match" => BinaryFiles::WithoutMatch,
+                    value => anyhow::bail!("Invalid value for '--binary-files': '{value}'."),
+                };
+            }
+            "--color" | "--colour" => color = ColorChoice::Auto,
+            _ if arg.starts_with("--color=") || arg.starts_with("--colour=") => {
+                color = match arg.split_once('=').unwrap().1 {
+                    "always" | "yes" | "force" => ColorChoice::Always,
+                    "never" | "no" | "none" => ColorChoice::Never,
+                    "auto" | "tty" | "if-tty" => ColorChoice::Auto,
+                    value => anyhow::bail!("Invalid value for '--color': '{value}'."),
+                };
+            }
+            _ if arg.starts_with("--context=") => {
+                context = Some(
+                    arg["--context=".len()..]
+                        .parse()
+                        .context("Invalid value for '--context'.")?,
+                );
+            }
+            "--label" => {
+                label = args
+                    .pop_front()
+                    .context("Expected a label after '--label'.")?
+            }
+            // Everything after `--` is the pattern or a path, even if it
+            // starts with `-`.
+            "--" => positional.extend(args.drain(..)),
+            // `--name=value` for the long options that take a value apart, as
+            // with `--max-count=1`.
+            _ if arg.starts_with("--")
+                && arg
+                    .split_once('=')
+                    .is_some_and(|(name, _)| LONG_WITH_VALUE.contains(&name)) =>
+            {
+                let (name, value) = arg.split_once('=').unwrap();
+                args.push_front(value.to_string());
+                args.push_front(name.to_string());
+            }
+            _ if arg.starts_with("--") => anyhow::bail!("Unknown option '{arg}'."),
+            // Short options given together, like `-rn`, are taken one by one,
+            // up to one taking a value, which is the rest, as in `-A1`. `-C`
+            // only takes a number.
+            _ if arg.starts_with('-') && arg.chars().count() > 2 => {
+                let mut flags = arg[1..].chars();
+                let first = flags.next().unwrap();
+                let rest = flags.as_str();
+                if SHORT_WITH_VALUE.contains(&first)
+                    || first == 'C' && rest.parse::<usize>().is_ok()
+                {
+                    args.push_front(rest.to_string());
                 } else {
-                    break;
+                    args.push_front(format!("-{rest}"));
                 }
+                args.push_front(format!("-{first}"));
             }
+            _ if arg.starts_with('-') && arg != "-" => anyhow::bail!("Unknown option '{arg}'."),
+            _ => positional.push(arg),
         }
+    }
 
-        Ok(pattern)
+    // As in GNU grep, printing nothing goes over everything else, and
+    // listing files over counting lines, whatever order they're asked for
+    // in. Either goes over describing the matches too.
+    let output = match (list_files, count) {
+        _ if quiet => Output::Quiet,
+        (Some(list_files), _) => list_files,
+        (None, true) => Output::Count,
+        (None, false) if json => Output::Json,
+        (None, false) => Output::Lines,
+    };
+
+    if posix && syntax == Syntax::Perl {
+        anyhow::bail!("Can't use '--posix' with '-P'.");
+    }
+    // The lines `-v` selects have no matches to describe.
+    if invert && output == Output::Json {
+        anyhow::bail!("Can't use '--json' with '-v'.");
     }
 
-    fn parse_list(
-        iter: &mut PatternIter,
-        end: EndFlags,
-        capture_group_count: &mut usize,
-        parent_capture_group: Option<usize>,
-    ) -> anyhow::Result<Option<Self>> {
-        let mut pattern = None;
+    // With a compiled regex, which stands in for the pattern, or patterns
+    // given with `-e` or `-f`, every positional argument is a path.
+    let mut positional = positional.into_iter();
+    let pattern = match (load_compiled, patterns) {
+        (Some(_), Some(_)) => anyhow::bail!("Can't use '-e' or '-f' with '--load-compiled'."),
+        (Some(_), None) if trace => anyhow::bail!("Can't use '--trace' with '--load-compiled'."),
+        (Some(path), None) => Pattern::Compiled(path),
+        (None, Some(patterns)) => Pattern::Source(patterns),
+        (None, None) => Pattern::Source(vec![positional.next().context("No pattern provided.")?]),
+    };
+    let mut paths = positional.collect::<Vec<_>>();
+    // With no paths `-r` searches the working directory.
+    let current_dir = recursive && paths.is_empty();
+    if paths.is_empty() && !recursive {
+        paths.push("-".to_string());
+    }
 
-        while let Some(item) = Self::parse_one(iter, capture_group_count, parent_capture_group)? {
-            pattern = if let Some(pattern) = pattern.take() {
-                if let Pattern::List(mut items) = pattern {
-                    items.push(item);
-                    Some(Pattern::List(items))
-                } else {
-                    let items = vec![pattern, item];
-                    Some(Pattern::List(items))
+    // Says which regex gave up when a search does.
+    let source = match &pattern {
+        Pattern::Source(patterns) => patterns
+            .iter()
+            .map(|pattern| format!("'{pattern}'"))
+            .collect::<Vec<_>>()
+            .join(" or "),
+        Pattern::Compiled(path) => format!("the regex in {path}"),
+    };
+
+    let start = Instant::now();
+    let regex = match pattern {
+        Pattern::Source(patterns) => {
+            // A line can't have a newline in it anyway, so one splits a
+            // pattern into patterns any of which match, as in GNU grep.
+            let patterns = patterns
+                .iter()
+                .flat_map(|pattern| pattern.split('\n'))
+                .collect::<Vec<_>>();
+            let builder = |patterns: &[&str]| {
+                let mut builder = RegexBuilder::new_any(patterns);
+                builder
+                    .syntax(syntax)
+                    .posix(posix)
+                    .fixed_string(fixed_strings)
+                    .case_insensitive(case_insensitive)
+                    .word(word)
+                    .line(line)
+                    .crlf(crlf)
+                    .backtrack_limit(backtrack_limit)
+                    .trace(trace);
+                if let Some(limit) = dfa_size_limit {
+                    builder.dfa_size_limit(limit);
                 }
-            } else {
-                Some(item)
+                if let Some(timeout) = timeout {
+                    builder.timeout(timeout);
+                }
+                builder
             };
-
-            if let Some(c) = iter.peek().copied() {
-                if c == ')' && end.contains(EndFlags::RPAREN)
-                    || c == '|' && end.contains(EndFlags::PIPE)
-                {
-                    break;
+            if check {
+                // Each pattern on its own first, so that every one that's
+                // wrong is reported, saying which it is.
+                let mut valid = true;
+                for pattern in &patterns {
+                    if let Err(err) = builder(&[pattern]).build() {
+                        eprintln!("grep: '{pattern}': {err:#}");
+                        valid = false;
+                    }
+                }
+                if !valid {
+                    return Ok(EXIT_ERROR);
                 }
             }
+            builder(&patterns).build()?
+        }
+        Pattern::Compiled(path) => {
+            let bytes = fs::read(&path).with_context(|| format!("reading {path}"))?;
+            Regex::from_bytes(&bytes)?
         }
+    };
 
-        Ok(pattern)
-    }
+    let compile_time = start.elapsed();
 
-    fn parse_one(
-        iter: &mut PatternIter,
-        capture_group_count: &mut usize,
-        parent_capture_group: Option<usize>,
-    ) -> anyhow::Result<Option<Self>> {
-        if let Some(c) = iter.next() {
-            let mut item = match c {
-                '\\' => {
-                    let c = iter.expect()?;
-                    match c {
-                        'd' => Pattern::Digit,
-                        'w' => Pattern::Alphanumeric,
-                        c => {
-                            if let Some(d) = c.to_digit(10) {
-                                let mut num = d;
-                                while let Some(c) = iter.peek() {
-                                    if let Some(d) = c.to_digit(10) {
-                                        iter.next();
-                                        num *= 10;
-                                        num += d;
-                                    } else {
-                                        break;
-                                    }
-                                }
+    if debug {
+        eprint!("{}", regex.dump());
+    }
 
-                                anyhow::ensure!(num != 0, "back reference id can't be 0");
-                                let id = num as usize - 1;
-                                anyhow::ensure!(
-                                    id < *capture_group_count,
-                                    "back reference invalid"
-                                );
-                                if let Some(parent) = parent_capture_group {
-                                    anyhow::ensure!(
-                                        parent != id,
-                                        "back reference to current capture group"
-                                    );
-                                }
+    let replace = replace
+        .map(|template| Template::parse(&template, &regex))
+        .transpose()?;
 
-                                Pattern::Reference(id)
-                            } else {
-                                anyhow::bail!("expected 'd', 'w' or number, got '{}'", c);
-                            }
-                        }
-                    }
-                }
-                '(' => {
-                    let id = *capture_group_count;
-                    *capture_group_count += 1;
-                    if let Some(item) =
-                        Self::parse_either(iter, EndFlags::RPAREN, capture_group_count, Some(id))?
-                    {
-                        let c = iter.expect()?;
-                        anyhow::ensure!(c == ')', "expected ')'");
-                        Pattern::CaptureGroup {
-                            id,
-                            item: Box::new(item),
-                        }
-                    } else {
-                        anyhow::bail!("empty group is not allowed");
-                    }
-                }
-                '[' => {
-                    let mut group = String::new();
+    // All there is to check is built by now, with nothing written, run or
+    // read.
+    if check {
+        return Ok(EXIT_MATCH);
+    }
 
-                    let c = iter.expect()?;
-                    let positive = if c == '^' {
-                        false
-                    } else {
-                        group.push(c);
-                        true
-                    };
+    if let Some(path) = save_compiled {
+        fs::write(&path, regex.to_bytes()).with_context(|| format!("writing {path}"))?;
+    }
 
-                    loop {
-                        let c = iter.expect()?;
-                        if c == ']' {
-                            break;
-                        }
-                        group.push(c);
-                    }
+    if let Some(dir) = bench_dir {
+        bench::run(&regex, compile_time, &dir, bench_runs)?;
+        return Ok(EXIT_MATCH);
+    }
 
-                    Pattern::CharacterGroup { positive, group }
+    // Traces from several inputs or runs at once would be mixed up.
+    let threads = match threads {
+        _ if trace => 1,
+        Some(threads) => threads.get(),
+        None => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+    };
+    // One input leaves nothing to do in parallel.
+    let parallel = threads > 1 && (recursive || paths.len() > 1);
+
+    let searcher = Searcher {
+        regex: &regex,
+        source,
+        print_captures,
+        invert,
+        only_matching,
+        with_filename: with_filename.unwrap_or(paths.len() > 1 || recursive),
+        null_after_name,
+        line_number,
+        byte_offset,
+        max_count,
+        // `-A` and `-B` win over `-C`, whichever comes first.
+        after_context: after_context.or(context).unwrap_or(0),
+        before_context: before_context.or(context).unwrap_or(0),
+        group_separator,
+        binary_files,
+        line_buffered,
+        line_terminator,
+        replace,
+        colors: color
+            .enabled()
+            .then(|| Colors::parse(&env::var("GREP_COLORS").unwrap_or_default(), invert)),
+        output,
+        printed_any: AtomicBool::new(false),
+        separate_inputs: !parallel,
+        stats: Stats::default(),
+    };
+
+    // Stdout is line buffered, so without this every line printed would be
+    // a write of its own; it's flushed once everything is written.
+    let mut stdout = BufWriter::new(io::stdout().lock());
+    let input_options = InputOptions {
+        label,
+        mmap,
+        threads,
+    };
+    // A file that can't be read is reported and the others are still
+    // searched.
+    let mut matched = false;
+    let mut failed = false;
+    let search_start = Instant::now();
+    let inputs = inputs(&paths, current_dir, recursive, &walk_options);
+    if parallel {
+        // Each input into a buffer of its own, written in the order given.
+        // The inputs take a thread each, so none is split over them.
+        let options = InputOptions {
+            threads: 1,
+            ..input_options
+        };
+        let mut printed_any = false;
+        let search = |input: anyhow::Result<Input>| {
+            let mut buffer = Vec::new();
+            let mut messages = Vec::new();
+            let result = input.and_then(|input| {
+                search_input(&searcher, input, &options, &mut buffer, &mut messages)
+            });
+            (buffer, messages, result)
+        };
+        parallel::map_in_order(inputs, threads, search, |(buffer, messages, result)| {
+            // Each input's context is separated from the one before's here,
+            // since only here is it known which that is.
+            if !buffer.is_empty() {
+                if printed_any {
+                    searcher.print_group_separator(&mut stdout)?;
                 }
-                '^' => Pattern::StartAnchor,
-                '$' => Pattern::EndAnchor,
-                '+' => anyhow::bail!("can't use '+' at the start of the pattern"),
-                '?' => anyhow::bail!("can't use '+' at the start of the pattern"),
-                '.' => Pattern::Wildcard,
-                c => Pattern::Literal(c),
-            };
-
-            while let Some(c) = iter.peek().copied() {
-                if c == '+' {
-                    item = Pattern::OneOrMore(Box::new(item))
-                } else if c == '?' {
-                    item = Pattern::ZeroOrOne(Box::new(item))
-                } else {
-                    break;
+                printed_any = true;
+                stdout.write_all(&buffer)?;
+                if line_buffered {
+                    stdout.flush()?;
                 }
-                iter.next();
             }
+            if !messages.is_empty() {
+                stdout.flush()?;
+                io::stderr().write_all(&messages)?;
+            }
+            settle(result, &mut matched, &mut failed, no_messages, &mut stdout)?;
+            // With `-q` the first match settles the exit status, so the
+            // rest aren't even read.
+            io::Result::Ok(!(matched && output == Output::Quiet))
+        })?;
+    } else {
+        for input in inputs {
+            let result = input.and_then(|input| {
+                search_input(
+                    &searcher,
+                    input,
+                    &input_options,
+                    &mut stdout,
+                    &mut io::stderr(),
+                )
+            });
+            settle(result, &mut matched, &mut failed, no_messages, &mut stdout)?;
+            if matched && output == Output::Quiet {
+                break;
+            }
+        }
+    }
+    if stats {
+        searcher.stats.print(search_start.elapsed(), &mut stdout)?;
+    }
+    stdout.flush()?;
+    // An input that couldn't be searched makes it an error, unless `-q`
+    // found a match anyway, since it needn't have read that input at all.
+    Ok(if failed && !(matched && output == Output::Quiet) {
+        EXIT_ERROR
+    } else if matched {
+        EXIT_MATCH
+    } else {
+        EXIT_NO_MATCH
+    })
+}
 
-            Ok(Some(item))
-        } else {
-            Ok(None)
+enum ColorChoice {
+    Always,
+    Never,
+    /// Only when writing to a terminal that can show them.
+    Auto,
+}
+
+impl ColorChoice {
+    fn enabled(&self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                io::stdout().is_terminal() && env::var_os("TERM").is_some_and(|term| term != "dumb")
+            }
         }
     }
+}
 
-    fn matches(
-        &self,
-        input: &str,
-        iter: &mut InputIter,
-        state: &mut [Option<Range<usize>>],
-    ) -> bool {
-        if let Some((i, c)) = iter.peek().copied() {
-            match self {
-                Pattern::Literal(expected) => {
-                    iter.next();
-                    *expected == c
-                }
-                Pattern::Digit => {
-                    iter.next();
-                    c.is_digit(10)
-                }
-                Pattern::Alphanumeric => {
-                    iter.next();
-                    c.is_alphanumeric()
-                }
-                Pattern::CharacterGroup {
-                    positive,
-                    group: chars,
-                } => {
-                    iter.next();
-                    c.is_alphabetic() && !*positive ^ chars.contains(c)
-                }
-                Pattern::StartAnchor => i == 0,
-                Pattern::EndAnchor => false,
-                Pattern::OneOrMore(inner) => {
-                    if !inner.matches(input, iter, state) {
-                        false
-                    } else {
-                        let mut tmp_iter = iter.clone();
-                        while inner.matches(input, &mut tmp_iter, state) {
-                            *iter = tmp_iter.clone();
-                        }
-                        true
-                    }
-                }
-                Pattern::ZeroOrOne(inner) => {
-                    let mut tmp_iter = iter.clone();
-                    if inner.matches(input, &mut tmp_iter, state) {
-                        *iter = tmp_iter;
-                    }
-                    true
-                }
-                Pattern::Wildcard => {
-                    iter.next();
-                    true
-                }
-                Pattern::Either(items) => {
-                    for item in items.iter() {
-                        let mut tmp_iter = iter.clone();
-                        if item.matches(input, &mut tmp_iter, state) {
-                            *iter = tmp_iter;
-                            return true;
-                        }
-                    }
+enum Input {
+    Stdin,
+    File(PathBuf),
+}
 
-                    false
-                }
-                Pattern::List(items) => {
-                    for item in items.iter() {
-                        if !item.matches(input, iter, state) {
-                            return false;
-                        }
-                    }
+/// What to search, in order: with `-r` the files under the working
+/// directory if there are no paths, or else those under each path.
+fn inputs<'a>(
+    paths: &'a [String],
+    current_dir: bool,
+    recursive: bool,
+    walk_options: &'a WalkOptions,
+) -> impl Iterator<Item = anyhow::Result<Input>> + Send + 'a {
+    let files = |walk: Walk| walk.map(|file| file.map(Input::File));
+    let current_dir = current_dir.then(|| files(Walk::current_dir(walk_options.clone())));
+    let paths = paths.iter().flat_map(
+        move |path| -> Box<dyn Iterator<Item = anyhow::Result<Input>> + Send> {
+            if path == "-" {
+                Box::new(iter::once(Ok(Input::Stdin)))
+            } else if recursive {
+                Box::new(files(Walk::new(PathBuf::from(path), walk_options.clone())))
+            } else {
+                Box::new(iter::once(Ok(Input::File(PathBuf::from(path)))))
+            }
+        },
+    );
+    current_dir.into_iter().flatten().chain(paths)
+}
 
-                    true
-                }
-                Pattern::Reference(id) => {
-                    if let Some(range) = &state[*id] {
-                        let content = input.get(range.clone()).unwrap();
+/// How `search_input` reads what it searches.
+struct InputOptions {
+    /// The name of standard input, for `--label`.
+    label: String,
+    /// Whether files are mapped into memory rather than read, if `--mmap`
+    /// or `--no-mmap` says; otherwise only those big enough to be worth it
+    /// are.
+    mmap: Option<bool>,
+    /// How many threads a big file can be split over.
+    threads: usize,
+}
 
-                        for exp_c in content.chars() {
-                            if let Some((_, c)) = iter.next() {
-                                if exp_c != c {
-                                    return false;
-                                }
-                            } else {
-                                return false;
-                            }
-                        }
+/// Searches `input`. A file is mapped into memory if it's big enough to be
+/// worth it or `--mmap` says to, and then split over the threads if it's
+/// bigger still.
+fn search_input(
+    searcher: &Searcher,
+    input: Input,
+    options: &InputOptions,
+    out: &mut impl Write,
+    messages: &mut impl Write,
+) -> anyhow::Result<bool> {
+    let path = match input {
+        Input::Stdin => {
+            let lines = ReadLines::new(io::stdin().lock());
+            return searcher.search(&options.label, lines, out, messages);
+        }
+        Input::File(path) => path,
+    };
+    let name = path.display().to_string();
+    let file = File::open(&path).with_context(|| format!("opening {name}"))?;
+    let len = file
+        .metadata()
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .map_or(0, |metadata| metadata.len());
+    let map = options
+        .mmap
+        .unwrap_or(len >= MMAP_MIN_LEN)
+        .then(|| Mmap::map(&file).ok())
+        .flatten();
+    let Some(map) = map else {
+        let lines = ReadLines::new(BufReader::new(file));
+        return searcher.search(&name, lines, out, messages);
+    };
+    if options.threads > 1 && map.len() >= parallel::SPLIT_MIN_LEN && searcher.can_split(&map) {
+        let search = |run: &[u8], number, offset, out: &mut Vec<u8>| {
+            searcher.search_run(&name, run, number, offset, out)
+        };
+        let count =
+            parallel::search_split(&map, searcher.line_terminator, options.threads, search, out)?;
+        searcher.finish(&name, count, map.len(), out)
+    } else {
+        searcher.search(&name, &map[..], out, messages)
+    }
+}
 
-                        true
-                    } else {
-                        false
-                    }
-                }
-                Pattern::CaptureGroup { id, item } => {
-                    let start = i;
-                    if item.matches(input, iter, state) {
-                        if let Some((end, _)) = iter.peek().copied() {
-                            state[*id] = Some(start..end);
-                        } else {
-                            state[*id] = Some(start..input.len());
-                        }
-                        true
-                    } else {
-                        false
-                    }
-                }
+/// Notes whether an input matched, or that it couldn't be searched and why
+/// so that the others still are. With `-s` files that can't be read aren't
+/// reported, though they still count as failures. A directory loop is only
+/// warned about, and doesn't count as one.
+fn settle(
+    result: anyhow::Result<bool>,
+    matched: &mut bool,
+    failed: &mut bool,
+    no_messages: bool,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    match result {
+        Ok(found) => *matched |= found,
+        Err(err) if err.is::<DirectoryLoop>() => {
+            if !no_messages {
+                out.flush()?;
+                eprintln!("grep: {err}");
             }
-        } else {
-            *self == Pattern::EndAnchor
+        }
+        Err(err) => {
+            *failed = true;
+            if !(no_messages && err.root_cause().is::<io::Error>()) {
+                out.flush()?;
+                eprintln!("grep: {err:#}");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_options_with_a_value_take_it_apart() {
+        for name in LONG_WITH_VALUE {
+            // Given last, each one asks for the value it's missing rather
+            // than being unknown without its `=`.
+            let args = VecDeque::from([String::from("-E"), name.to_string()]);
+            let Err(err) = run(args) else {
+                panic!("{name} ran without its value");
+            };
+            assert!(err.to_string().starts_with("Expected"), "{name}: {err:#}");
         }
     }
 }
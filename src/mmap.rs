@@ -0,0 +1,117 @@
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+
+/// A file mapped read-only into memory, which searching borrows its lines
+/// from rather than copying them out of reads. If the file shrinks while it
+/// is mapped, reading past its new end kills the process with `SIGBUS`, as
+/// with any mapping; GNU grep and ripgrep take the same risk.
+pub(crate) struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is only ever read, from whichever thread.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+#[cfg(all(unix, target_pointer_width = "64"))]
+mod sys {
+    use std::ffi::{c_int, c_void};
+
+    // The same on Linux, the BSDs and macOS.
+    pub(super) const PROT_READ: c_int = 1;
+    pub(super) const MAP_PRIVATE: c_int = 2;
+    pub(super) const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub(super) fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        pub(super) fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+impl Mmap {
+    /// Maps all of `file`, failing for empty files, which there's nothing
+    /// to map of, and where mapping isn't supported.
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    pub(crate) fn map(file: &File) -> io::Result<Self> {
+        use std::os::fd::AsRawFd;
+        use std::ptr;
+
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        if len == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        // SAFETY: a fresh private, read-only mapping aliases nothing.
+        let ptr = unsafe {
+            sys::mmap(
+                ptr::null_mut(),
+                len,
+                sys::PROT_READ,
+                sys::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == sys::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    #[cfg(not(all(unix, target_pointer_width = "64")))]
+    pub(crate) fn map(_file: &File) -> io::Result<Self> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as `self`.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: this is the mapping `map` made, and nothing borrows it any
+        // more.
+        #[cfg(all(unix, target_pointer_width = "64"))]
+        unsafe {
+            sys::munmap(self.ptr.cast(), self.len);
+        }
+    }
+}
+
+#[cfg(all(test, unix, target_pointer_width = "64"))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn maps_what_the_file_holds() {
+        let path = env::temp_dir().join(format!("grep-mmap-{}", process::id()));
+        fs::write(&path, b"one\ntwo\n").unwrap();
+        let map = Mmap::map(&File::open(&path).unwrap()).unwrap();
+        assert_eq!(&map[..], b"one\ntwo\n");
+
+        fs::write(&path, b"").unwrap();
+        assert!(Mmap::map(&File::open(&path).unwrap()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use crate::pattern::State;
+use crate::program::{Assertion, Inst, Program};
+use std::ops::Range;
+
+#[derive(Clone, Copy)]
+enum Step {
+    Save(usize),
+    Assert(Assertion),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum End {
+    Consume(usize),
+    Match,
+}
+
+struct Path {
+    steps: Vec<Step>,
+    end: End,
+}
+
+/// A program where, from any position, at most one path of empty
+/// transitions leads to an instruction that can consume the next char.
+/// Captures are then settled in a single forward pass with one set of slots.
+/// The paths of empty transitions out of each instruction that follows a
+/// consuming one (and out of the first one) are worked out here, ordered by
+/// priority.
+pub(crate) struct OnePass {
+    paths: Vec<Vec<Path>>,
+}
+
+impl OnePass {
+    pub(crate) fn new(program: &Program) -> Option<Self> {
+        if !program.references.is_empty() {
+            return None;
+        }
+
+        let mut paths = Vec::new();
+        paths.resize_with(program.insts.len(), Vec::new);
+
+        let mut entries = vec![0];
+        let mut done = vec![false; program.insts.len()];
+        while let Some(entry) = entries.pop() {
+            if done[entry] {
+                continue;
+            }
+            done[entry] = true;
+
+            let mut builder = Builder {
+                program,
+                seen: vec![false; program.insts.len()],
+                paths: Vec::new(),
+            };
+            builder.walk(entry, Vec::new(), Vec::new())?;
+
+            let sets = builder
+                .paths
+                .iter()
+                .filter_map(|path| match path.end {
+                    End::Consume(pc) => Some(char_set(&program.insts[pc])),
+                    End::Match => None,
+                })
+                .collect::<Vec<_>>();
+            for (k, a) in sets.iter().enumerate() {
+                if sets[k + 1..].iter().any(|b| overlaps(a, b)) {
+                    return None;
+                }
+            }
+
+            for path in &builder.paths {
+                if let End::Consume(pc) = path.end {
+                    entries.push(pc + 1);
+                }
+            }
+            paths[entry] = builder.paths;
+        }
+
+        Some(OnePass { paths })
+    }
+
+    /// Leaves the match found in `state`.
+    pub(crate) fn find_at(
+        &self,
+        program: &Program,
+        input: &str,
+        start: usize,
+        cache: &mut Cache,
+        state: &mut State,
+    ) -> bool {
+        state.reset(program.group_count);
+        let Cache {
+            slots,
+            found,
+            found_history,
+        } = cache;
+        slots.clear();
+        slots.resize(program.slot_count, None);
+        let mut matched = None;
+
+        let mut entry = 0;
+        let mut i = start;
+        loop {
+            let c = input[i..].chars().next();
+
+            let mut next = None;
+            for path in &self.paths[entry] {
+                let holds = path.steps.iter().all(|step| match step {
+                    Step::Assert(assertion) => assertion.holds(input, i, start),
+                    Step::Save(_) => true,
+                });
+                if !holds {
+                    continue;
+                }
+
+                match path.end {
+                    End::Match => {
+                        found.clone_from(slots);
+                        found_history.clear();
+                        save(&path.steps, i, found, found_history);
+                        matched = Some(state.history.len());
+                        break;
+                    }
+                    End::Consume(pc) => {
+                        if next.is_none() && c.is_some_and(|c| program.insts[pc].matches_char(c)) {
+                            next = Some((pc, &path.steps));
+                        }
+                    }
+                }
+            }
+
+            if let (Some((pc, steps)), Some(c)) = (next, c) {
+                save(steps, i, slots, &mut state.history);
+                entry = pc + 1;
+                i += c.len_utf8();
+            } else {
+                break;
+            }
+        }
+
+        let Some(len) = matched else {
+            return false;
+        };
+        state.history.truncate(len);
+        state.history.append(found_history);
+        for (id, group) in state.groups.iter_mut().enumerate() {
+            if let (Some(start), Some(end)) = (found[id * 2], found[id * 2 + 1]) {
+                *group = Some(start..end);
+            }
+        }
+        true
+    }
+}
+
+/// The buffers of a search, kept between searches so that they only grow
+/// until they fit.
+#[derive(Default)]
+pub(crate) struct Cache {
+    slots: Vec<Option<usize>>,
+    found: Vec<Option<usize>>,
+    found_history: Vec<(usize, Range<usize>)>,
+}
+
+struct Builder<'p> {
+    program: &'p Program,
+    seen: Vec<bool>,
+    paths: Vec<Path>,
+}
+
+impl<'p> Builder<'p> {
+    /// `entered` holds the loops whose iteration started on this path; the
+    /// others were already iterating when the last char was consumed, so
+    /// their `Progress` always goes on.
+    fn walk(&mut self, pc: usize, mut steps: Vec<Step>, mut entered: Vec<usize>) -> Option<()> {
+        if self.seen[pc] {
+            return None;
+        }
+        self.seen[pc] = true;
+
+        match &self.program.insts[pc] {
+            Inst::Jump(target) => self.walk(*target, steps, entered),
+            Inst::Split(first, second) => {
+                self.walk(*first, steps.clone(), entered.clone())?;
+                self.walk(*second, steps, entered)
+            }
+            Inst::Save(slot) => {
+                if *slot < self.program.group_count * 2 {
+                    steps.push(Step::Save(*slot));
+                } else {
+                    entered.push(*slot);
+                }
+                self.walk(pc + 1, steps, entered)
+            }
+            Inst::Progress { slot, exit } => {
+                if entered.contains(slot) {
+                    self.walk(*exit, steps, entered)
+                } else {
+                    self.walk(pc + 1, steps, entered)
+                }
+            }
+            Inst::Assert(assertion) => {
+                steps.push(Step::Assert(*assertion));
+                self.walk(pc + 1, steps, entered)
+            }
+            Inst::Match => {
+                self.paths.push(Path {
+                    steps,
+                    end: End::Match,
+                });
+                Some(())
+            }
+            _ => {
+                self.paths.push(Path {
+                    steps,
+                    end: End::Consume(pc),
+                });
+                Some(())
+            }
+        }
+    }
+}
+
+fn save(
+    steps: &[Step],
+    i: usize,
+    slots: &mut [Option<usize>],
+    history: &mut Vec<(usize, Range<usize>)>,
+) {
+    for step in steps {
+        if let Step::Save(slot) = step {
+            slots[*slot] = Some(i);
+            if slot % 2 == 1 {
+                if let Some(start) = slots[slot - 1] {
+                    history.push((slot / 2, start..i));
+                }
+            }
+        }
+    }
+}
+
+/// The chars `inst` can consume as sorted ranges, exact for literals, groups
+/// and wildcards and otherwise assumed to include everything past ASCII.
+fn char_set(inst: &Inst) -> Vec<(u32, u32)> {
+    match inst {
+        Inst::Literal(c) => vec![(*c as u32, *c as u32)],
+        Inst::CharacterGroup {
+            positive: true,
+            ranges,
+        } => sorted(ranges),
+        Inst::CharacterGroup {
+            positive: false,
+            ranges,
+        } => complement(&sorted(ranges)),
+        Inst::Wildcard { crlf: false } => vec![(0, char::MAX as u32)],
+        Inst::Wildcard { crlf: true } => complement(&[(0xA, 0xA), (0xD, 0xD)]),
+        _ => {
+            let mut set = (0..128)
+                .filter(|&c| inst.matches_char(char::from(c)))
+                .map(|c| (c as u32, c as u32))
+                .collect::<Vec<_>>();
+            set.push((0x80, char::MAX as u32));
+            set
+        }
+    }
+}
+
+fn sorted(ranges: &[(char, char)]) -> Vec<(u32, u32)> {
+    let mut ranges = ranges
+        .iter()
+        .map(|&(start, end)| (start as u32, end as u32))
+        .collect::<Vec<_>>();
+    ranges.sort_unstable();
+    ranges
+}
+
+fn complement(ranges: &[(u32, u32)]) -> Vec<(u32, u32)> {
+    let mut set = Vec::new();
+    let mut next = 0;
+    for &(start, end) in ranges {
+        if start > next {
+            set.push((next, start - 1));
+        }
+        next = next.max(end + 1);
+    }
+    if next <= char::MAX as u32 {
+        set.push((next, char::MAX as u32));
+    }
+    set
+}
+
+fn overlaps(a: &[(u32, u32)], b: &[(u32, u32)]) -> bool {
+    a.iter()
+        .any(|&(s1, e1)| b.iter().any(|&(s2, e2)| s1 <= e2 && s2 <= e1))
+}
@@ -0,0 +1,205 @@
+use crate::pattern::Pattern;
+use std::slice;
+
+impl Pattern {
+    /// Rewrites the parsed pattern into an equivalent one that's cheaper to
+    /// match: nested lists and alternations are flattened, quantifiers of
+    /// quantifiers collapsed, common prefixes of neighbouring alternatives
+    /// matched once, and runs of literals joined into strings. Matches and
+    /// captures come out the same, in the same order.
+    pub(crate) fn optimize(self) -> Pattern {
+        self.simplify().join_literals()
+    }
+
+    fn simplify(self) -> Pattern {
+        match self {
+            Pattern::OneOrMore(item) => match item.simplify() {
+                Pattern::Empty => Pattern::Empty,
+                Pattern::ZeroOrOne(item) => Pattern::ZeroOrMore(item),
+                item => Pattern::OneOrMore(Box::new(item)),
+            },
+            Pattern::ZeroOrMore(item) => match item.simplify() {
+                Pattern::Empty => Pattern::Empty,
+                Pattern::ZeroOrOne(item) => Pattern::ZeroOrMore(item),
+                item => Pattern::ZeroOrMore(Box::new(item)),
+            },
+            Pattern::ZeroOrOne(item) => match item.simplify() {
+                Pattern::Empty => Pattern::Empty,
+                item @ (Pattern::ZeroOrOne(_) | Pattern::ZeroOrMore(_)) => item,
+                Pattern::OneOrMore(item) => Pattern::ZeroOrMore(item),
+                item => Pattern::ZeroOrOne(Box::new(item)),
+            },
+            Pattern::List(items) => {
+                let mut flat = Vec::new();
+                for item in items {
+                    match item.simplify() {
+                        Pattern::Empty => {}
+                        Pattern::List(items) => flat.extend(items),
+                        item => flat.push(item),
+                    }
+                }
+                list(flat)
+            }
+            Pattern::Either(items) => {
+                let mut flat = Vec::new();
+                for item in items {
+                    match item.simplify() {
+                        Pattern::Either(items) => flat.extend(items),
+                        item => flat.push(item),
+                    }
+                }
+                either(hoist_prefixes(flat))
+            }
+            Pattern::LongestEither(items) => {
+                Pattern::LongestEither(items.into_iter().map(Pattern::simplify).collect())
+            }
+            Pattern::CaptureGroup { id, name, item } => Pattern::CaptureGroup {
+                id,
+                name,
+                item: Box::new(item.simplify()),
+            },
+            pattern => pattern,
+        }
+    }
+
+    fn join_literals(self) -> Pattern {
+        match self {
+            Pattern::OneOrMore(item) => Pattern::OneOrMore(Box::new(item.join_literals())),
+            Pattern::ZeroOrMore(item) => Pattern::ZeroOrMore(Box::new(item.join_literals())),
+            Pattern::ZeroOrOne(item) => Pattern::ZeroOrOne(Box::new(item.join_literals())),
+            Pattern::List(items) => {
+                let mut joined = Vec::new();
+                let mut literal = String::new();
+                for item in items {
+                    match item.join_literals() {
+                        Pattern::Literal(c) => literal.push(c),
+                        item => {
+                            joined.extend(string(std::mem::take(&mut literal)));
+                            joined.push(item);
+                        }
+                    }
+                }
+                joined.extend(string(literal));
+                list(joined)
+            }
+            Pattern::Either(items) => {
+                Pattern::Either(items.into_iter().map(Pattern::join_literals).collect())
+            }
+            Pattern::LongestEither(items) => {
+                Pattern::LongestEither(items.into_iter().map(Pattern::join_literals).collect())
+            }
+            Pattern::CaptureGroup { id, name, item } => Pattern::CaptureGroup {
+                id,
+                name,
+                item: Box::new(item.join_literals()),
+            },
+            pattern => pattern,
+        }
+    }
+
+    /// Whether the pattern matches a single char or checks an assertion,
+    /// in at most one way, so that alternatives starting with it can match
+    /// it once before trying each of their rests.
+    fn is_atom(&self) -> bool {
+        matches!(
+            self,
+            Pattern::Literal(_)
+                | Pattern::FoldedLiteral(_)
+                | Pattern::Digit { .. }
+                | Pattern::Alphanumeric { .. }
+                | Pattern::WordBoundary { .. }
+                | Pattern::NoWordChar { .. }
+                | Pattern::CharacterGroup { .. }
+                | Pattern::Script { .. }
+                | Pattern::StartAnchor
+                | Pattern::EndAnchor { .. }
+                | Pattern::ContinueAnchor
+                | Pattern::Wildcard { .. }
+        )
+    }
+
+    fn items(&self) -> &[Pattern] {
+        match self {
+            Pattern::Empty => &[],
+            Pattern::List(items) => items,
+            pattern => slice::from_ref(pattern),
+        }
+    }
+}
+
+/// Groups neighbouring alternatives that start with the same atom and
+/// matches their longest common run of atoms before the remaining
+/// alternation, as in `abc|abd|x` to `ab(?:c|d)|x`.
+fn hoist_prefixes(items: Vec<Pattern>) -> Vec<Pattern> {
+    let mut hoisted = Vec::new();
+    let mut run: Vec<Pattern> = Vec::new();
+    for item in items {
+        let same_start = run.first().is_some_and(|first| {
+            first
+                .items()
+                .first()
+                .is_some_and(|atom| atom.is_atom() && item.items().first() == Some(atom))
+        });
+        if !same_start && !run.is_empty() {
+            hoisted.push(factor(std::mem::take(&mut run)));
+        }
+        run.push(item);
+    }
+    if !run.is_empty() {
+        hoisted.push(factor(run));
+    }
+    hoisted
+}
+
+fn factor(mut run: Vec<Pattern>) -> Pattern {
+    if run.len() == 1 {
+        return run.pop().unwrap();
+    }
+
+    let first = run[0].items();
+    let len = (0..first.len())
+        .take_while(|&k| {
+            first[k].is_atom()
+                && run[1..]
+                    .iter()
+                    .all(|item| item.items().get(k) == Some(&first[k]))
+        })
+        .count();
+
+    let mut items = first[..len].to_vec();
+    let rests = run
+        .iter()
+        .map(|item| list(item.items()[len..].to_vec()))
+        .collect();
+    match either(hoist_prefixes(rests)) {
+        Pattern::Empty => {}
+        Pattern::List(rest) => items.extend(rest),
+        rest => items.push(rest),
+    }
+    list(items)
+}
+
+fn string(s: String) -> Option<Pattern> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (None, _) => None,
+        (Some(c), None) => Some(Pattern::Literal(c)),
+        _ => Some(Pattern::String(s)),
+    }
+}
+
+fn list(mut items: Vec<Pattern>) -> Pattern {
+    match items.len() {
+        0 => Pattern::Empty,
+        1 => items.pop().unwrap(),
+        _ => Pattern::List(items),
+    }
+}
+
+fn either(mut items: Vec<Pattern>) -> Pattern {
+    if items.len() == 1 {
+        items.pop().unwrap()
+    } else {
+        Pattern::Either(items)
+    }
+}
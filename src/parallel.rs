@@ -0,0 +1,472 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+/// How many jobs can wait for each thread before dealing them out waits for
+/// them to be taken, so that a big tree to walk isn't all queued up at once.
+const QUEUED_PER_THREAD: usize = 4;
+
+/// Runs `work` on each of `jobs` on `threads` threads and hands what it
+/// gives for each to `done`, on this thread and in the order of the jobs,
+/// so that what `done` writes is what doing the jobs one by one would give.
+/// The jobs are dealt out from a thread of their own as `jobs` yields them,
+/// so a slow source like a walk runs alongside the searching and the
+/// writing. Results that are done before the ones ahead of them wait in a
+/// map by job number; the threads never wait on `done` or on each other.
+/// `done` returns whether more results are wanted: once one says no, or
+/// fails, the rest of the jobs aren't dealt out and their results dropped.
+pub(crate) fn map_in_order<T: Send, R: Send, E>(
+    jobs: impl Iterator<Item = T> + Send,
+    threads: usize,
+    work: impl Fn(T) -> R + Sync,
+    mut done: impl FnMut(R) -> Result<bool, E>,
+) -> Result<(), E> {
+    let deques = Deques::new(threads, threads * QUEUED_PER_THREAD);
+    let (sender, results) = mpsc::channel();
+
+    thread::scope(|scope| {
+        let deques = &deques;
+        scope.spawn(move || {
+            for job in jobs.enumerate() {
+                if !deques.push(job) {
+                    break;
+                }
+            }
+            deques.finish();
+        });
+        for worker in 0..threads {
+            let (sender, work) = (sender.clone(), &work);
+            scope.spawn(move || {
+                while let Some((i, job)) = deques.take(worker) {
+                    if sender.send((i, work(job))).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // Only the threads' senders are left, so the results end with them.
+        drop(sender);
+
+        let mut write = || {
+            let mut pending = BTreeMap::new();
+            let mut next = 0;
+            for (i, result) in &results {
+                pending.insert(i, result);
+                while let Some(result) = pending.remove(&next) {
+                    next += 1;
+                    if !done(result)? {
+                        return Ok(());
+                    }
+                }
+            }
+            Ok(())
+        };
+        let written = write();
+        // With `done` finished early the jobs left aren't wanted, and the
+        // threads stop sending once `results` is gone.
+        deques.close();
+        drop(results);
+        written
+    })
+}
+
+/// Jobs dealt out in turn to a deque for each thread. A thread takes jobs
+/// from the front of its own deque and, once that's empty, steals from the
+/// back of the others', so one that drew slow jobs gets the rest of its
+/// share taken off it. Taking a job only locks the deques it's looked for
+/// in; `state` is only locked to wait for a job or for room for one.
+struct Deques<T> {
+    deques: Vec<Mutex<VecDeque<T>>>,
+    /// Which deque the next job goes to.
+    next: AtomicUsize,
+    /// How many jobs are in the deques, which `push` keeps below `limit`.
+    queued: AtomicUsize,
+    limit: usize,
+    state: Mutex<DequesState>,
+    /// Notified whenever a job is pushed, room is made or `state` changes.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct DequesState {
+    /// No more jobs are coming.
+    finished: bool,
+    /// No more jobs are wanted.
+    closed: bool,
+}
+
+impl<T> Deques<T> {
+    fn new(threads: usize, limit: usize) -> Self {
+        Deques {
+            deques: (0..threads).map(|_| Mutex::default()).collect(),
+            next: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            limit,
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Adds `job` to the next deque in turn, once there's room for it.
+    /// Returns false if the deques were closed instead.
+    fn push(&self, job: T) -> bool {
+        let mut state = lock(&self.state);
+        while self.queued.load(Ordering::SeqCst) >= self.limit && !state.closed {
+            state = self.wait(state);
+        }
+        if state.closed {
+            return false;
+        }
+        // Counted before it's in a deque, so the count is never short, and
+        // with `state` held, so that a thread finding nothing to take and
+        // about to wait sees it, or is woken for it.
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.deques.len();
+        lock(&self.deques[i]).push_back(job);
+        self.changed.notify_all();
+        true
+    }
+
+    /// Takes the next job for thread `worker`, waiting for one if there are
+    /// none but more are coming.
+    fn take(&self, worker: usize) -> Option<T> {
+        loop {
+            if let Some(job) = self.steal(worker) {
+                if self.queued.fetch_sub(1, Ordering::SeqCst) == self.limit {
+                    let _state = lock(&self.state);
+                    self.changed.notify_all();
+                }
+                return Some(job);
+            }
+            let state = lock(&self.state);
+            // A job is on its way into a deque, or out of one and about to
+            // be uncounted.
+            if self.queued.load(Ordering::SeqCst) > 0 {
+                continue;
+            }
+            if state.finished || state.closed {
+                return None;
+            }
+            drop(self.wait(state));
+        }
+    }
+
+    fn steal(&self, worker: usize) -> Option<T> {
+        // Popped with nothing else locked, so that two threads stealing from
+        // each other don't each hold the deque the other wants.
+        let own = lock(&self.deques[worker]).pop_front();
+        let n = self.deques.len();
+        own.or_else(|| (1..n).find_map(|i| lock(&self.deques[(worker + i) % n]).pop_back()))
+    }
+
+    /// Says no more jobs are coming, so threads stop once they're taken.
+    fn finish(&self) {
+        lock(&self.state).finished = true;
+        self.changed.notify_all();
+    }
+
+    /// Stops `push`, waiting for room or not, so no more jobs come. Those
+    /// already dealt can still be taken.
+    fn close(&self) {
+        lock(&self.state).closed = true;
+        self.changed.notify_all();
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, DequesState>) -> MutexGuard<'a, DequesState> {
+        self.changed
+            .wait(state)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// How big an input has to be to be split up by `search_split`.
+pub(crate) const SPLIT_MIN_LEN: usize = 8 << 20;
+/// The smallest run of lines `search_split` hands a thread.
+const RUN_MIN_LEN: usize = 1 << 20;
+
+/// Searches `data`, all of one input in memory, on `threads` threads: split
+/// into runs of whole lines, each ending with `terminator`, that `search`
+/// searches into a buffer of its own given the number of the run's first
+/// line and its offset in `data`. The buffers are written to `out` in
+/// order, so the output is what searching the runs one after the other
+/// would give, up to an error, which ends it where it would have. Returns
+/// the sum of what `search` returned for each run.
+pub(crate) fn search_split<E: From<io::Error> + Send>(
+    data: &[u8],
+    terminator: u8,
+    threads: usize,
+    search: impl Fn(&[u8], usize, usize, &mut Vec<u8>) -> Result<usize, E> + Sync,
+    out: &mut impl Write,
+) -> Result<usize, E> {
+    // A few runs a thread, so that one slow run holds up less.
+    let run_len = (data.len() / (threads * 4)).max(RUN_MIN_LEN);
+    let runs = runs(data, terminator, run_len);
+
+    // The number of the line each run starts with, from counting the lines
+    // of each, also in parallel, first.
+    let next = AtomicUsize::new(0);
+    let mut lines = vec![0; runs.len()];
+    thread::scope(|scope| {
+        let (sender, counts) = mpsc::channel();
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let (next, runs) = (&next, &runs);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(run) = runs.get(i) else {
+                    break;
+                };
+                let count = memchr::memchr_iter(terminator, &data[run.clone()]).count();
+                let _ = sender.send((i, count));
+            });
+        }
+        drop(sender);
+        for (i, count) in counts {
+            lines[i] = count;
+        }
+    });
+    let mut number = 1;
+    let numbers = lines
+        .iter()
+        .map(|count| {
+            number += count;
+            number - count
+        })
+        .collect::<Vec<_>>();
+
+    next.store(0, Ordering::Relaxed);
+    thread::scope(|scope| {
+        let (sender, results) = mpsc::channel();
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let (next, runs, numbers, search) = (&next, &runs, &numbers, &search);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(run) = runs.get(i) else {
+                    break;
+                };
+                let mut buffer = Vec::new();
+                let result = search(&data[run.clone()], numbers[i], run.start, &mut buffer);
+                let failed = result.is_err();
+                if sender.send((i, buffer, result)).is_err() || failed {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut total = 0;
+        let mut pending = BTreeMap::new();
+        let mut next_run = 0;
+        for (i, buffer, result) in results {
+            pending.insert(i, (buffer, result));
+            while let Some((buffer, result)) = pending.remove(&next_run) {
+                next_run += 1;
+                out.write_all(&buffer)?;
+                total += result?;
+            }
+        }
+        Ok(total)
+    })
+}
+
+/// Splits `data` into runs of whole lines at least `len` long, but for the
+/// last.
+fn runs(data: &[u8], terminator: u8, len: usize) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = (start + len).min(data.len());
+        let end = memchr::memchr(terminator, &data[end..]).map_or(data.len(), |i| end + i + 1);
+        runs.push(start..end);
+        start = end;
+    }
+    runs
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codecrafters_grep::Regex;
+    use std::iter;
+    use std::time::Duration;
+
+    #[test]
+    fn every_job_is_taken_once() {
+        let deques = Deques::new(4, 3);
+        let taken = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for worker in 0..4 {
+                let (deques, taken) = (&deques, &taken);
+                scope.spawn(move || {
+                    while let Some(job) = deques.take(worker) {
+                        lock(taken).push(job);
+                    }
+                });
+            }
+            for job in 0..1000 {
+                assert!(deques.push(job));
+            }
+            deques.finish();
+        });
+        let mut taken = taken.into_inner().unwrap();
+        taken.sort();
+        assert_eq!(taken, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn idle_threads_steal() {
+        // Everything is dealt before anyone takes anything, and only one
+        // thread ever takes, so all but a quarter is stolen.
+        let deques = Deques::new(4, 100);
+        for job in 0..100 {
+            assert!(deques.push(job));
+        }
+        deques.finish();
+        let taken = iter::from_fn(|| deques.take(2)).collect::<Vec<_>>();
+        assert_eq!(taken.len(), 100);
+        // Its own deque first, from the front.
+        assert_eq!(taken[..3], [2, 6, 10]);
+    }
+
+    #[test]
+    fn closing_stops_push() {
+        let deques = Deques::new(2, 1);
+        assert!(deques.push(1));
+        thread::scope(|scope| {
+            // Waits for room that never comes, until closed.
+            let pushing = scope.spawn(|| deques.push(2));
+            deques.close();
+            assert!(!pushing.join().unwrap());
+        });
+        assert_eq!(deques.take(1), Some(1));
+        assert_eq!(deques.take(0), None);
+    }
+
+    #[test]
+    fn threads_share_a_regex() {
+        let regex = Regex::new(r"(\d+)-(\d+)").unwrap();
+        let mut found = Vec::new();
+        let lines = (0..200).map(|i| format!("line {i}-{}", i * 2));
+        let work = |line: String| {
+            let captures = regex.captures(&line).unwrap();
+            captures.get(1).unwrap().as_str().parse::<usize>().unwrap()
+        };
+        map_in_order(lines, 4, work, |n| {
+            found.push(n);
+            Ok::<_, ()>(true)
+        })
+        .unwrap();
+        assert_eq!(found, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn results_come_in_the_order_of_the_jobs() {
+        // The first jobs take longest, so they're done last.
+        let mut done = Vec::new();
+        let work = |job: u64| {
+            thread::sleep(Duration::from_millis(20u64.saturating_sub(job)));
+            job
+        };
+        map_in_order(0..40, 4, work, |job| {
+            done.push(job);
+            Ok::<_, ()>(true)
+        })
+        .unwrap();
+        assert_eq!(done, (0..40).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn saying_no_stops_the_jobs() {
+        let worked = AtomicUsize::new(0);
+        let mut done = Vec::new();
+        // Jobs that never run out, until the results say no more are
+        // wanted.
+        let work = |job| {
+            worked.fetch_add(1, Ordering::Relaxed);
+            job
+        };
+        map_in_order(0.., 3, work, |job| {
+            done.push(job);
+            Ok::<_, ()>(job != 50)
+        })
+        .unwrap();
+        assert_eq!(done, (0..=50).collect::<Vec<_>>());
+        assert!(worked.into_inner() > 50);
+
+        let failed = map_in_order(
+            0..,
+            3,
+            |job| job,
+            |job| match job {
+                10 => Err(job),
+                _ => Ok(true),
+            },
+        );
+        assert_eq!(failed, Err(10));
+    }
+
+    #[test]
+    fn runs_end_at_line_ends() {
+        assert_eq!(runs(b"ab\ncd\nef\n", b'\n', 2), [0..3, 3..6, 6..9]);
+        assert_eq!(runs(b"ab\ncd\nef\n", b'\n', 4), [0..6, 6..9]);
+        assert_eq!(runs(b"abc\0de", b'\0', 1), [0..4, 4..6]);
+        assert!(runs(b"", b'\n', 1).is_empty());
+    }
+
+    #[test]
+    fn split_searches_give_the_output_in_order() {
+        let line = |i: usize| format!("{i} {}\n", if i % 7 == 0 { "x" } else { "y" });
+        let data = (0..400_000).map(line).collect::<String>();
+        assert!(data.len() > 3 * RUN_MIN_LEN);
+        // Each line with an x, numbered, and where it starts.
+        let search = |run: &[u8], mut number: usize, mut offset: usize, out: &mut Vec<u8>| {
+            let mut count = 0;
+            for line in run.split_inclusive(|&b| b == b'\n') {
+                if line.contains(&b'x') {
+                    write!(out, "{number}:{offset}:")?;
+                    out.write_all(line)?;
+                    count += 1;
+                }
+                number += 1;
+                offset += line.len();
+            }
+            Ok::<_, io::Error>(count)
+        };
+
+        let mut expected = Vec::new();
+        let count = search(data.as_bytes(), 1, 0, &mut expected).unwrap();
+        let mut out = Vec::new();
+        let split = search_split(data.as_bytes(), b'\n', 4, search, &mut out).unwrap();
+        assert_eq!(split, count);
+        assert!(out == expected);
+    }
+
+    #[test]
+    fn a_failed_run_ends_the_output() {
+        let data = "line\n".repeat(1_000_000);
+        let mut out = Vec::new();
+        let result = search_split(
+            data.as_bytes(),
+            b'\n',
+            4,
+            |run, number, _, out| {
+                if number > 1 {
+                    return Err(io::Error::other("failed"));
+                }
+                out.extend_from_slice(&run[..5]);
+                Ok(1)
+            },
+            &mut out,
+        );
+        assert!(result.is_err());
+        assert_eq!(out, b"line\n");
+    }
+}
@@ -0,0 +1,1066 @@
+use crate::{Reason, TooComplexError};
+use std::fmt;
+use std::ops::Range;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use unicode_script::Script;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QuantifierError {
+    NothingToRepeat {
+        quantifier: char,
+    },
+    NotQuantifiable {
+        quantifier: char,
+        item: &'static str,
+    },
+    Stacked {
+        quantifier: char,
+        previous: char,
+    },
+}
+
+impl fmt::Display for QuantifierError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuantifierError::NothingToRepeat { quantifier } => {
+                write!(f, "nothing to repeat before '{}'", quantifier)
+            }
+            QuantifierError::NotQuantifiable { quantifier, item } => {
+                write!(f, "can't apply '{}' to {}", quantifier, item)
+            }
+            QuantifierError::Stacked {
+                quantifier,
+                previous,
+            } => write!(
+                f,
+                "can't apply '{}' directly after '{}'; possessive and lazy quantifiers are not supported",
+                quantifier, previous
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QuantifierError {}
+
+trait CharsIterExt {
+    fn expect(&mut self) -> anyhow::Result<char>;
+}
+
+impl<I> CharsIterExt for I
+where
+    I: Iterator<Item = char>,
+{
+    fn expect(&mut self) -> anyhow::Result<char> {
+        if let Some(c) = self.next() {
+            Ok(c)
+        } else {
+            Err(anyhow::anyhow!("expected a character, but ran out."))
+        }
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy)]
+    pub(crate) struct EndFlags: u8 {
+        const RPAREN = 1 << 0;
+        const PIPE = 1 << 1;
+    }
+}
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy)]
+    pub(crate) struct Flags: u8 {
+        const UNICODE = 1 << 0;
+        const CASE_INSENSITIVE = 1 << 1;
+        const CRLF = 1 << 2;
+        const LONGEST_ALTERNATIVE = 1 << 3;
+        const LITERAL_ANCHORS = 1 << 4;
+        /// POSIX basic syntax, where `BASIC_OPERATORS` take a backslash.
+        const BASIC = 1 << 5;
+        /// PCRE-like escapes on top of the usual syntax.
+        const PERL = 1 << 6;
+        /// Only what POSIX has, for the syntax in use.
+        const POSIX = 1 << 7;
+    }
+}
+
+/// The chars that are operators in basic syntax only with a backslash
+/// before them, and otherwise match themselves, the other way round from
+/// extended syntax.
+const BASIC_OPERATORS: [char; 5] = ['(', ')', '|', '+', '?'];
+
+/// The whitespace `\s` matches with `Flags::PERL`: tab, newline, vertical
+/// tab, form feed, carriage return and space.
+const PERL_SPACE: [(char, char); 2] = [('\t', '\r'), (' ', ' ')];
+
+/// The chars `\w` matches without `Flags::UNICODE`.
+const ASCII_WORD: [(char, char); 4] = [('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+
+/// What a backslash inside brackets stands for with `Flags::PERL`: a char,
+/// which can start or end a range, or the sorted ranges of a class.
+enum BracketEscape {
+    Char(char),
+    Class(Vec<(char, char)>),
+}
+
+/// The char of a control escape with `Flags::PERL`: `\n`, `\t`, `\r`, `\f`,
+/// `\v` or `\e`.
+fn perl_control(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        'f' => '\x0c',
+        'v' => '\x0b',
+        _ => '\x1b',
+    }
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags::UNICODE
+    }
+}
+
+type PatternIter<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct State {
+    pub(crate) groups: Vec<Option<Range<usize>>>,
+    pub(crate) history: Vec<(usize, Range<usize>)>,
+    /// Once this runs out, `matches` stops the search as if it succeeded and
+    /// the caller reports that it gave up.
+    pub(crate) budget: Budget,
+}
+
+/// How long a backtracking search may go on: up to a number of steps, a
+/// point in time or both. The clock is only read every so many steps, so a
+/// search can run a little past its deadline.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Budget {
+    limit: Option<usize>,
+    deadline: Option<(Instant, Duration)>,
+    steps: usize,
+    exceeded: Option<TooComplexError>,
+}
+
+const STEPS_PER_CLOCK_CHECK: usize = 1024;
+
+impl Budget {
+    pub(crate) fn new(limit: Option<usize>, timeout: Option<Duration>) -> Self {
+        Budget {
+            limit,
+            deadline: timeout.map(|timeout| (Instant::now() + timeout, timeout)),
+            steps: 0,
+            exceeded: None,
+        }
+    }
+
+    /// Takes a step, returning `false` once the search has to give up.
+    pub(crate) fn step(&mut self) -> bool {
+        if self.exceeded.is_some() {
+            return false;
+        }
+        if let Some(limit) = self.limit.filter(|&limit| self.steps == limit) {
+            self.exceeded = Some(TooComplexError {
+                reason: Reason::Steps(limit),
+            });
+            return false;
+        }
+        self.steps += 1;
+        if let Some((deadline, timeout)) = self.deadline {
+            if self.steps % STEPS_PER_CLOCK_CHECK == 0 && Instant::now() >= deadline {
+                self.exceeded = Some(TooComplexError {
+                    reason: Reason::Timeout(timeout),
+                });
+                return false;
+            }
+        }
+        true
+    }
+
+    pub(crate) fn exceeded(&self) -> Option<TooComplexError> {
+        self.exceeded
+    }
+}
+
+impl State {
+    pub(crate) fn reset(&mut self, capture_group_count: usize) {
+        self.groups.clear();
+        self.groups.resize(capture_group_count, None);
+        self.history.clear();
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Pattern {
+    Empty,
+    Literal(char),
+    String(String),
+    FoldedLiteral(char),
+    Digit {
+        unicode: bool,
+        positive: bool,
+    },
+    Alphanumeric {
+        unicode: bool,
+        positive: bool,
+    },
+    WordBoundary {
+        unicode: bool,
+        positive: bool,
+    },
+    /// No word char right before the position, if `before`, or else right
+    /// after it, which `-w` wraps the pattern in.
+    NoWordChar {
+        unicode: bool,
+        before: bool,
+    },
+    CharacterGroup {
+        positive: bool,
+        ranges: Vec<(char, char)>,
+    },
+    Script {
+        positive: bool,
+        script: Script,
+    },
+    StartAnchor,
+    EndAnchor {
+        crlf: bool,
+    },
+    ContinueAnchor,
+    OneOrMore(Box<Pattern>),
+    ZeroOrMore(Box<Pattern>),
+    ZeroOrOne(Box<Pattern>),
+    Wildcard {
+        crlf: bool,
+    },
+    List(Vec<Pattern>),
+    Either(Vec<Pattern>),
+    LongestEither(Vec<Pattern>),
+    Reference(usize),
+    FoldedReference(usize),
+    CaptureGroup {
+        id: usize,
+        name: Option<String>,
+        item: Box<Pattern>,
+    },
+}
+
+impl Pattern {
+    pub(crate) fn literal(s: &str, flags: Flags) -> Self {
+        let mut items = s
+            .chars()
+            .map(|c| {
+                if flags.contains(Flags::CASE_INSENSITIVE) && has_case(c) {
+                    Pattern::FoldedLiteral(c)
+                } else {
+                    Pattern::Literal(c)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        match items.len() {
+            0 => Pattern::Empty,
+            1 => items.pop().unwrap(),
+            _ => Pattern::List(items),
+        }
+    }
+
+    /// Renumbers the groups in the pattern, and the references to them, as
+    /// if `offset` more groups came before it.
+    pub(crate) fn shift_groups(&mut self, offset: usize) {
+        match self {
+            Pattern::Reference(id) | Pattern::FoldedReference(id) => *id += offset,
+            Pattern::CaptureGroup { id, item, .. } => {
+                *id += offset;
+                item.shift_groups(offset);
+            }
+            Pattern::OneOrMore(item) | Pattern::ZeroOrMore(item) | Pattern::ZeroOrOne(item) => {
+                item.shift_groups(offset);
+            }
+            Pattern::List(items) | Pattern::Either(items) | Pattern::LongestEither(items) => {
+                for item in items {
+                    item.shift_groups(offset);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Fills in the names of the groups in the pattern, by id, failing if
+    /// two have the same one.
+    pub(crate) fn group_names(&self, names: &mut [Option<String>]) -> anyhow::Result<()> {
+        match self {
+            Pattern::CaptureGroup { id, name, item } => {
+                if let Some(name) = name {
+                    anyhow::ensure!(
+                        !names.iter().flatten().any(|other| other == name),
+                        "duplicate group name '{}'",
+                        name
+                    );
+                    names[*id] = Some(name.clone());
+                }
+                item.group_names(names)
+            }
+            Pattern::OneOrMore(item) | Pattern::ZeroOrMore(item) | Pattern::ZeroOrOne(item) => {
+                item.group_names(names)
+            }
+            Pattern::List(items) | Pattern::Either(items) | Pattern::LongestEither(items) => {
+                items.iter().try_for_each(|item| item.group_names(names))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn parse_either(
+        iter: &mut PatternIter,
+        end: EndFlags,
+        flags: &mut Flags,
+        capture_group_count: &mut usize,
+        parent_capture_group: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        let mut items = Vec::new();
+
+        loop {
+            items.push(Self::parse_list(
+                iter,
+                end | EndFlags::PIPE,
+                flags,
+                capture_group_count,
+                parent_capture_group,
+            )?);
+
+            if !Self::next_operator(iter, *flags, '|') {
+                break;
+            }
+        }
+
+        if items.len() == 1 {
+            Ok(items.pop().unwrap())
+        } else if flags.contains(Flags::LONGEST_ALTERNATIVE) {
+            Ok(Pattern::LongestEither(items))
+        } else {
+            Ok(Pattern::Either(items))
+        }
+    }
+
+    fn parse_list(
+        iter: &mut PatternIter,
+        end: EndFlags,
+        flags: &mut Flags,
+        capture_group_count: &mut usize,
+        parent_capture_group: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        let mut pattern = None;
+
+        while iter.peek().is_some() {
+            if end.contains(EndFlags::RPAREN) && Self::at_operator(iter, *flags, ')')
+                || end.contains(EndFlags::PIPE) && Self::at_operator(iter, *flags, '|')
+            {
+                break;
+            }
+
+            if let Some(item) = Self::parse_one(
+                iter,
+                flags,
+                pattern.is_none(),
+                capture_group_count,
+                parent_capture_group,
+            )? {
+                pattern = if let Some(pattern) = pattern.take() {
+                    if let Pattern::List(mut items) = pattern {
+                        items.push(item);
+                        Some(Pattern::List(items))
+                    } else {
+                        let items = vec![pattern, item];
+                        Some(Pattern::List(items))
+                    }
+                } else {
+                    Some(item)
+                };
+            }
+        }
+
+        Ok(pattern.unwrap_or(Pattern::Empty))
+    }
+
+    fn parse_one(
+        iter: &mut PatternIter,
+        flags: &mut Flags,
+        at_start: bool,
+        capture_group_count: &mut usize,
+        parent_capture_group: Option<usize>,
+    ) -> anyhow::Result<Option<Self>> {
+        if let Some(mut c) = iter.next() {
+            let mut literal = false;
+            if flags.contains(Flags::BASIC) {
+                if c == '\\' && iter.peek().is_some_and(|c| BASIC_OPERATORS.contains(c)) {
+                    c = iter.expect()?;
+                } else {
+                    literal = BASIC_OPERATORS.contains(&c);
+                }
+            }
+
+            let mut item = match c {
+                _ if literal => Pattern::literal(c.encode_utf8(&mut [0; 4]), *flags),
+                '\\' => {
+                    let c = iter.expect()?;
+                    match c {
+                        's' | 'S' if flags.contains(Flags::PERL) => Pattern::CharacterGroup {
+                            positive: c == 's',
+                            ranges: PERL_SPACE.to_vec(),
+                        },
+                        'D' if flags.contains(Flags::PERL) => Pattern::Digit {
+                            unicode: flags.contains(Flags::UNICODE),
+                            positive: false,
+                        },
+                        'W' if flags.contains(Flags::PERL) => Pattern::Alphanumeric {
+                            unicode: flags.contains(Flags::UNICODE),
+                            positive: false,
+                        },
+                        'n' | 't' | 'r' | 'f' | 'v' | 'e' if flags.contains(Flags::PERL) => {
+                            Pattern::Literal(perl_control(c))
+                        }
+                        '{' | '}' if flags.contains(Flags::BASIC) => {
+                            anyhow::bail!("intervals are not supported")
+                        }
+                        // Basic syntax has back references, extended none.
+                        c if flags.contains(Flags::POSIX)
+                            && !c.is_ascii_punctuation()
+                            && !(flags.contains(Flags::BASIC) && c.is_ascii_digit()) =>
+                        {
+                            anyhow::bail!("'\\{}' is not POSIX", c)
+                        }
+                        // Escaping any other punctuation makes it match
+                        // itself.
+                        c if c.is_ascii_punctuation() => {
+                            Pattern::literal(c.encode_utf8(&mut [0; 4]), *flags)
+                        }
+                        'd' => Pattern::Digit {
+                            unicode: flags.contains(Flags::UNICODE),
+                            positive: true,
+                        },
+                        'w' => Pattern::Alphanumeric {
+                            unicode: flags.contains(Flags::UNICODE),
+                            positive: true,
+                        },
+                        'b' | 'B' => Pattern::WordBoundary {
+                            unicode: flags.contains(Flags::UNICODE),
+                            positive: c == 'b',
+                        },
+                        'A' => Pattern::StartAnchor,
+                        'G' => Pattern::ContinueAnchor,
+                        'x' => {
+                            let c = Self::parse_hex(iter)?;
+                            if flags.contains(Flags::CASE_INSENSITIVE) && has_case(c) {
+                                Pattern::FoldedLiteral(c)
+                            } else {
+                                Pattern::Literal(c)
+                            }
+                        }
+                        'p' | 'P' => {
+                            let positive = c == 'p';
+                            let c = iter.expect()?;
+                            anyhow::ensure!(c == '{', "expected '{{' after '\\p'");
+
+                            let mut name = String::new();
+                            loop {
+                                let c = iter.expect()?;
+                                if c == '}' {
+                                    break;
+                                }
+                                name.push(c);
+                            }
+
+                            if let Some(script) =
+                                Script::from_full_name(&name).or(Script::from_short_name(&name))
+                            {
+                                Pattern::Script { positive, script }
+                            } else {
+                                anyhow::bail!("unknown script '{}'", name);
+                            }
+                        }
+                        c => {
+                            if let Some(d) = c.to_digit(10) {
+                                let mut num = d;
+                                while let Some(c) = iter.peek() {
+                                    if let Some(d) = c.to_digit(10) {
+                                        iter.next();
+                                        num *= 10;
+                                        num += d;
+                                    } else {
+                                        break;
+                                    }
+                                }
+
+                                anyhow::ensure!(num != 0, "back reference id can't be 0");
+                                let id = num as usize;
+                                anyhow::ensure!(
+                                    id < *capture_group_count,
+                                    "back reference invalid"
+                                );
+                                if let Some(parent) = parent_capture_group {
+                                    anyhow::ensure!(
+                                        parent != id,
+                                        "back reference to current capture group"
+                                    );
+                                }
+
+                                if flags.contains(Flags::CASE_INSENSITIVE) {
+                                    Pattern::FoldedReference(id)
+                                } else {
+                                    Pattern::Reference(id)
+                                }
+                            } else {
+                                anyhow::bail!(
+                                    "expected 'd', 'w', 'b', 'B', 'A', 'G', 'x', 'p', 'P', number or punctuation, got '{}'",
+                                    c
+                                );
+                            }
+                        }
+                    }
+                }
+                '(' => {
+                    let mut group_flags = *flags;
+                    let mut ahead = iter.clone();
+                    let extensions = !flags.intersects(Flags::BASIC | Flags::POSIX);
+                    let named = extensions
+                        && ahead.next() == Some('?')
+                        && matches!(ahead.next(), Some('<' | 'P'));
+
+                    if named {
+                        iter.next();
+                        let name = Self::parse_group_name(iter)?;
+                        Self::parse_capture_group(
+                            iter,
+                            &mut group_flags,
+                            capture_group_count,
+                            Some(name),
+                        )?
+                    } else if extensions && iter.next_if_eq(&'?').is_some() {
+                        let mut enable = true;
+                        loop {
+                            let c = iter.expect()?;
+                            match c {
+                                'u' => group_flags.set(Flags::UNICODE, enable),
+                                'i' => group_flags.set(Flags::CASE_INSENSITIVE, enable),
+                                'R' => group_flags.set(Flags::CRLF, enable),
+                                '-' => {
+                                    anyhow::ensure!(enable, "repeated '-' in flags");
+                                    enable = false;
+                                }
+                                ':' => break,
+                                ')' => {
+                                    *flags = group_flags;
+                                    return Ok(None);
+                                }
+                                c => anyhow::bail!("unknown flag '{}'", c),
+                            }
+                        }
+
+                        let item = Self::parse_either(
+                            iter,
+                            EndFlags::RPAREN,
+                            &mut group_flags,
+                            capture_group_count,
+                            parent_capture_group,
+                        )?;
+                        anyhow::ensure!(Self::next_operator(iter, *flags, ')'), "expected ')'");
+                        item
+                    } else {
+                        Self::parse_capture_group(
+                            iter,
+                            &mut group_flags,
+                            capture_group_count,
+                            None,
+                        )?
+                    }
+                }
+                '[' => {
+                    let mut c = iter.expect()?;
+                    let positive = if c == '^' {
+                        c = iter.expect()?;
+                        false
+                    } else {
+                        true
+                    };
+
+                    let perl = flags.contains(Flags::PERL);
+                    let unicode = flags.contains(Flags::UNICODE);
+                    let mut ranges = Vec::new();
+                    // What `\d` and the like stand for, which has no case
+                    // to fold, as they don't outside brackets.
+                    let mut classes = Vec::new();
+                    loop {
+                        let start = if perl && c == '\\' {
+                            match Self::parse_bracket_escape(iter, unicode)? {
+                                BracketEscape::Char(c) => c,
+                                BracketEscape::Class(class) => {
+                                    classes.extend(class);
+                                    c = iter.expect()?;
+                                    if c == ']' {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            }
+                        } else {
+                            c
+                        };
+                        c = iter.expect()?;
+                        if c == '-' && iter.peek().is_some_and(|&c| c != ']') {
+                            let mut end = iter.expect()?;
+                            if perl && end == '\\' {
+                                let BracketEscape::Char(c) =
+                                    Self::parse_bracket_escape(iter, unicode)?
+                                else {
+                                    anyhow::bail!("invalid range ending in a class");
+                                };
+                                end = c;
+                            }
+                            anyhow::ensure!(start <= end, "invalid range '{}-{}'", start, end);
+                            ranges.push((start, end));
+                            c = iter.expect()?;
+                        } else {
+                            ranges.push((start, start));
+                        }
+
+                        if c == ']' {
+                            break;
+                        }
+                    }
+
+                    if flags.contains(Flags::CASE_INSENSITIVE) {
+                        fold_ranges(&mut ranges);
+                    }
+                    ranges.extend(classes);
+                    Pattern::CharacterGroup { positive, ranges }
+                }
+                '^' => {
+                    if flags.contains(Flags::LITERAL_ANCHORS) && !at_start {
+                        Pattern::Literal(c)
+                    } else {
+                        Pattern::StartAnchor
+                    }
+                }
+                '$' => {
+                    if flags.contains(Flags::LITERAL_ANCHORS)
+                        && iter.peek().is_some()
+                        && !Self::at_operator(iter, *flags, ')')
+                        && !Self::at_operator(iter, *flags, '|')
+                    {
+                        Pattern::Literal(c)
+                    } else {
+                        Pattern::EndAnchor {
+                            crlf: flags.contains(Flags::CRLF),
+                        }
+                    }
+                }
+                '+' | '*' | '?' => {
+                    if flags.contains(Flags::LITERAL_ANCHORS) {
+                        Pattern::Literal(c)
+                    } else {
+                        return Err(QuantifierError::NothingToRepeat { quantifier: c }.into());
+                    }
+                }
+                '{' if flags.contains(Flags::POSIX) => {
+                    anyhow::bail!("intervals are not supported")
+                }
+                '.' => Pattern::Wildcard {
+                    crlf: flags.contains(Flags::CRLF),
+                },
+                c => {
+                    if flags.contains(Flags::CASE_INSENSITIVE) && has_case(c) {
+                        Pattern::FoldedLiteral(c)
+                    } else {
+                        Pattern::Literal(c)
+                    }
+                }
+            };
+
+            let literal_quantifier =
+                flags.contains(Flags::LITERAL_ANCHORS) && item.unquantifiable().is_some();
+            if let Some(c) = Self::at_quantifier(iter, *flags).filter(|_| !literal_quantifier) {
+                Self::next_operator(iter, *flags, c);
+                if let Some(what) = item.unquantifiable() {
+                    return Err(QuantifierError::NotQuantifiable {
+                        quantifier: c,
+                        item: what,
+                    }
+                    .into());
+                }
+
+                item = match c {
+                    '+' => Pattern::OneOrMore(Box::new(item)),
+                    '*' => Pattern::ZeroOrMore(Box::new(item)),
+                    _ => Pattern::ZeroOrOne(Box::new(item)),
+                };
+
+                if let Some(next) = Self::at_quantifier(iter, *flags) {
+                    return Err(QuantifierError::Stacked {
+                        quantifier: next,
+                        previous: c,
+                    }
+                    .into());
+                }
+            }
+
+            Ok(Some(item))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Reads the two hex digits after `\x` as the char they number.
+    fn parse_hex(iter: &mut PatternIter) -> anyhow::Result<char> {
+        let mut code = 0;
+        for _ in 0..2 {
+            let c = iter.expect()?;
+            if let Some(d) = c.to_digit(16) {
+                code = code * 16 + d;
+            } else {
+                anyhow::bail!("expected hex digit, got '{}'", c);
+            }
+        }
+        Ok(char::from(code as u8))
+    }
+
+    /// Reads what follows a backslash inside brackets with `Flags::PERL`:
+    /// `\d`, `\w` and `\s` and their capitals, the control escapes, `\x`
+    /// or an escaped punctuation char.
+    fn parse_bracket_escape(
+        iter: &mut PatternIter,
+        unicode: bool,
+    ) -> anyhow::Result<BracketEscape> {
+        let c = iter.expect()?;
+        Ok(match c {
+            'd' | 'w' | 's' | 'D' | 'W' | 'S' => {
+                let class = match c.to_ascii_lowercase() {
+                    'd' if unicode => DECIMAL_DIGIT_ZEROS
+                        .iter()
+                        .map(|&zero| {
+                            let digit = |d| char::from_u32(zero + d).unwrap();
+                            (digit(0), digit(9))
+                        })
+                        .collect(),
+                    'd' => vec![('0', '9')],
+                    'w' if unicode => unicode_word_ranges().to_vec(),
+                    'w' => ASCII_WORD.to_vec(),
+                    _ => PERL_SPACE.to_vec(),
+                };
+                BracketEscape::Class(if c.is_ascii_uppercase() {
+                    complement(&class)
+                } else {
+                    class
+                })
+            }
+            'n' | 't' | 'r' | 'f' | 'v' | 'e' => BracketEscape::Char(perl_control(c)),
+            'x' => BracketEscape::Char(Self::parse_hex(iter)?),
+            c if c.is_ascii_punctuation() => BracketEscape::Char(c),
+            c => anyhow::bail!(
+                "expected 'd', 'w', 's', 'D', 'W', 'S', 'n', 't', 'r', 'f', 'v', 'e', 'x' or punctuation in brackets, got '{}'",
+                c
+            ),
+        })
+    }
+
+    fn parse_capture_group(
+        iter: &mut PatternIter,
+        flags: &mut Flags,
+        capture_group_count: &mut usize,
+        name: Option<String>,
+    ) -> anyhow::Result<Self> {
+        let id = *capture_group_count;
+        *capture_group_count += 1;
+        let item =
+            Self::parse_either(iter, EndFlags::RPAREN, flags, capture_group_count, Some(id))?;
+        anyhow::ensure!(Self::next_operator(iter, *flags, ')'), "expected ')'");
+        Ok(Pattern::CaptureGroup {
+            id,
+            name,
+            item: Box::new(item),
+        })
+    }
+
+    /// Whether the operator `op` comes next, with a backslash before it if
+    /// that's how the syntax writes it.
+    fn at_operator(iter: &PatternIter, flags: Flags, op: char) -> bool {
+        let mut ahead = iter.clone();
+        if flags.contains(Flags::BASIC) && BASIC_OPERATORS.contains(&op) {
+            ahead.next() == Some('\\') && ahead.next() == Some(op)
+        } else {
+            ahead.next() == Some(op)
+        }
+    }
+
+    /// Takes the operator `op` if it comes next.
+    fn next_operator(iter: &mut PatternIter, flags: Flags, op: char) -> bool {
+        if !Self::at_operator(iter, flags, op) {
+            return false;
+        }
+        if flags.contains(Flags::BASIC) && BASIC_OPERATORS.contains(&op) {
+            iter.next();
+        }
+        iter.next();
+        true
+    }
+
+    fn at_quantifier(iter: &PatternIter, flags: Flags) -> Option<char> {
+        ['+', '*', '?']
+            .into_iter()
+            .find(|&c| Self::at_operator(iter, flags, c))
+    }
+
+    /// Reads the `<name>` or `P<name>` after the `(?` of a named group.
+    fn parse_group_name(iter: &mut PatternIter) -> anyhow::Result<String> {
+        iter.next_if_eq(&'P');
+        let c = iter.expect()?;
+        anyhow::ensure!(c == '<', "expected '<' before group name");
+        let mut name = String::new();
+        loop {
+            let c = iter.expect()?;
+            if c == '>' {
+                break;
+            }
+            anyhow::ensure!(
+                c.is_alphanumeric() || c == '_',
+                "invalid character '{}' in group name",
+                c
+            );
+            name.push(c);
+        }
+        anyhow::ensure!(!name.is_empty(), "empty group name");
+        Ok(name)
+    }
+
+    fn unquantifiable(&self) -> Option<&'static str> {
+        match self {
+            Pattern::StartAnchor => Some("a start anchor"),
+            Pattern::EndAnchor { .. } => Some("an end anchor"),
+            Pattern::ContinueAnchor => Some("the '\\G' anchor"),
+            Pattern::WordBoundary { .. } | Pattern::NoWordChar { .. } => Some("a word boundary"),
+            _ => None,
+        }
+    }
+}
+
+const DECIMAL_DIGIT_ZEROS: &[u32] = &[
+    0x30, 0x660, 0x6F0, 0x7C0, 0x966, 0x9E6, 0xA66, 0xAE6, 0xB66, 0xBE6, 0xC66, 0xCE6, 0xD66,
+    0xDE6, 0xE50, 0xED0, 0xF20, 0x1040, 0x1090, 0x17E0, 0x1810, 0x1946, 0x19D0, 0x1A80, 0x1A90,
+    0x1B50, 0x1BB0, 0x1C40, 0x1C50, 0xA620, 0xA8D0, 0xA900, 0xA9D0, 0xA9F0, 0xAA50, 0xABF0, 0xFF10,
+    0x104A0, 0x10D30, 0x11066, 0x110F0, 0x11136, 0x111D0, 0x112F0, 0x11450, 0x114D0, 0x11650,
+    0x116C0, 0x11730, 0x118E0, 0x11950, 0x11C50, 0x11D50, 0x11DA0, 0x11F50, 0x16A60, 0x16AC0,
+    0x16B50, 0x1D7CE, 0x1D7D8, 0x1D7E2, 0x1D7EC, 0x1D7F6, 0x1E140, 0x1E2F0, 0x1E4F0, 0x1E950,
+    0x1FBF0,
+];
+
+pub(crate) fn is_digit_char(c: char, unicode: bool) -> bool {
+    if unicode {
+        let c = c as u32;
+        match DECIMAL_DIGIT_ZEROS.binary_search(&c) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(i) => c < DECIMAL_DIGIT_ZEROS[i - 1] + 10,
+        }
+    } else {
+        c.is_ascii_digit()
+    }
+}
+
+/// The chars `is_word_char` takes with `unicode`, as sorted ranges, worked
+/// out the first time they're asked for.
+fn unicode_word_ranges() -> &'static [(char, char)] {
+    static RANGES: OnceLock<Vec<(char, char)>> = OnceLock::new();
+    RANGES.get_or_init(|| {
+        let mut ranges: Vec<(char, char)> = Vec::new();
+        for c in ('\0'..=char::MAX).filter(|&c| is_word_char(c, true)) {
+            match ranges.last_mut() {
+                Some((_, end)) if *end as u32 + 1 == c as u32 => *end = c,
+                _ => ranges.push((c, c)),
+            }
+        }
+        ranges
+    })
+}
+
+/// The chars outside of sorted, disjoint `ranges`.
+fn complement(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    // The chars next to one, stepping over the surrogates, which aren't.
+    let before = |c: char| char::from_u32(c as u32 - 1).unwrap_or('\u{D7FF}');
+    let after = |c: char| char::from_u32(c as u32 + 1).unwrap_or('\u{E000}');
+    let mut complement = Vec::new();
+    let mut next = Some('\0');
+    for &(start, end) in ranges {
+        if let Some(from) = next.filter(|&from| from < start) {
+            complement.push((from, before(start)));
+        }
+        next = (end < char::MAX).then(|| after(end));
+    }
+    complement.extend(next.map(|from| (from, char::MAX)));
+    complement
+}
+
+pub(crate) fn is_word_char(c: char, unicode: bool) -> bool {
+    if unicode {
+        c.is_alphanumeric() || c == '_'
+    } else {
+        c.is_ascii_alphanumeric() || c == '_'
+    }
+}
+
+pub(crate) fn fold(c: char) -> impl Iterator<Item = char> {
+    c.to_lowercase()
+        .flat_map(char::to_uppercase)
+        .flat_map(char::to_lowercase)
+}
+
+/// Adds the other cases of the chars in `ranges`, so that a character group
+/// matches regardless of case, and merges the ranges that then overlap.
+fn fold_ranges(ranges: &mut Vec<(char, char)>) {
+    // The case a char maps to, if it's a single char.
+    fn single(mut chars: impl Iterator<Item = char>) -> Option<char> {
+        let c = chars.next()?;
+        chars.next().is_none().then_some(c)
+    }
+    let mut folded = Vec::new();
+    for &(start, end) in ranges.iter() {
+        for c in (start..=end).filter(|&c| has_case(c)) {
+            for other in [single(c.to_lowercase()), single(c.to_uppercase())]
+                .into_iter()
+                .flatten()
+            {
+                folded.push(other);
+                folded.extend(single(other.to_lowercase()));
+                folded.extend(single(other.to_uppercase()));
+            }
+        }
+    }
+    ranges.extend(folded.into_iter().map(|c| (c, c)));
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(char, char)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some((_, last)) if start as u32 <= *last as u32 + 1 => *last = (*last).max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn has_case(c: char) -> bool {
+    c.to_lowercase().ne(std::iter::once(c)) || c.to_uppercase().ne(std::iter::once(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Regex, RegexBuilder, Syntax};
+
+    fn find<'h>(pattern: &str, haystack: &'h str) -> Option<&'h str> {
+        Regex::new(pattern)
+            .unwrap()
+            .find(haystack)
+            .map(|m| m.as_str())
+    }
+
+    fn find_all<'h>(pattern: &str, haystack: &'h str) -> Vec<&'h str> {
+        Regex::new(pattern)
+            .unwrap()
+            .find_iter(haystack)
+            .map(|m| m.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn continue_anchor_holds_where_the_last_match_ended() {
+        assert_eq!(find_all(r"\Ga", "aab a"), ["a", "a"]);
+        assert_eq!(find_all(r"\G\d,?", "1,2,3 4"), ["1,", "2,", "3"]);
+        assert_eq!(find(r"\Gb", "ab"), None);
+        assert_eq!(find(r"a\G", "a"), None);
+    }
+
+    // 👍🏽 is two chars, the thumb and the skin tone after it, and e\u{301}
+    // an e followed by a combining acute accent: each matches as the chars
+    // it's made of.
+
+    #[test]
+    fn groups_of_emoji() {
+        assert_eq!(find("[👍]", "ok 👍🏽"), Some("👍"));
+        assert_eq!(find("[🏽]", "ok 👍🏽"), Some("🏽"));
+        assert_eq!(find("[👍🏽]+", "ok 👍🏽!"), Some("👍🏽"));
+        assert_eq!(find("[^a-z ]", "ok 👍🏽"), Some("👍"));
+        assert_eq!(find("[😀-😂]", "😃😁"), Some("😁"));
+        assert_eq!(find("[😀-😂]", "😃"), None);
+        assert_eq!(find("[a-😀]", "🎉"), Some("🎉"));
+    }
+
+    #[test]
+    fn groups_of_combining_chars() {
+        assert_eq!(find("[\u{301}]", "cafe\u{301}"), Some("\u{301}"));
+        assert_eq!(find("e[\u{300}-\u{36f}]", "cafe\u{301}"), Some("e\u{301}"));
+        assert_eq!(find("e[\u{300}-\u{36f}]", "café"), None);
+        assert_eq!(find("[é]", "cafe\u{301}"), None);
+    }
+
+    #[test]
+    fn wildcard_takes_one_char() {
+        assert_eq!(find("^.$", "👍"), Some("👍"));
+        assert_eq!(find("^.$", "👍🏽"), None);
+        assert_eq!(find("^..$", "👍🏽"), Some("👍🏽"));
+        assert_eq!(find("^.$", "é"), Some("é"));
+        assert_eq!(find("^.$", "e\u{301}"), None);
+        assert_eq!(find("^..$", "e\u{301}"), Some("e\u{301}"));
+        assert_eq!(find("a.c", "a🎉c"), Some("a🎉c"));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(find("👍🏽", "ok 👍🏽"), Some("👍🏽"));
+        assert_eq!(find("👍🏽", "ok 👍"), None);
+        assert_eq!(find("e\u{301}", "cafe\u{301}"), Some("e\u{301}"));
+        assert_eq!(find("e\u{301}", "café"), None);
+        assert_eq!(find("é", "cafe\u{301}"), None);
+        let regex = Regex::new("😁+").unwrap();
+        let found = regex.find("a😁😁b").unwrap();
+        assert_eq!(found.range(), 1..9);
+    }
+
+    #[test]
+    fn script_classes() {
+        assert_eq!(find(r"\p{Greek}+", "abc αβγ где"), Some("αβγ"));
+        assert_eq!(find(r"\p{Cyrillic}+", "abc αβγ где"), Some("где"));
+        assert_eq!(find(r"\P{Latin}", "abc αβγ"), Some(" "));
+        assert!(Regex::new(r"\p{Klingon}").is_err());
+    }
+
+    #[test]
+    fn ascii_and_unicode_classes() {
+        let perl = |pattern: &str, haystack: &str| {
+            let regex = RegexBuilder::new(pattern)
+                .syntax(Syntax::Perl)
+                .build()
+                .unwrap();
+            regex.find(haystack).map(|m| m.as_str().to_string())
+        };
+        // Unicode by default, ASCII with -P as in PCRE unless `(?u)` says
+        // otherwise.
+        assert_eq!(find(r"\d+", "x٣4"), Some("٣4"));
+        assert_eq!(find(r"\w+", "é!"), Some("é"));
+        assert_eq!(perl(r"\d+", "x٣4").as_deref(), Some("4"));
+        assert_eq!(perl(r"\w+", "é!"), None);
+        assert_eq!(perl(r"(?u)\w+", "é!").as_deref(), Some("é"));
+        assert_eq!(perl(r"\bb", "éb").as_deref(), Some("b"));
+        assert_eq!(perl(r"(?u)\bb", "éb"), None);
+    }
+
+    #[test]
+    fn quantifiers_give_back_chars() {
+        assert_eq!(find("a+ab", "xaaab"), Some("aaab"));
+        assert_eq!(find(r"\d+5", "12345"), Some("12345"));
+        assert_eq!(find("a?a", "a"), Some("a"));
+        assert_eq!(find(".*foo", "foo and foo!"), Some("foo and foo"));
+        assert_eq!(find("(a|ab)c", "abc"), Some("abc"));
+    }
+}
@@ -0,0 +1,388 @@
+use crate::pattern::State;
+use crate::prefilter::Prefilter;
+use crate::program::{Inst, Program};
+use std::ops::Range;
+use std::sync::Arc;
+
+struct History {
+    id: usize,
+    range: Range<usize>,
+    prev: Option<Arc<History>>,
+}
+
+struct Thread {
+    pc: usize,
+    history: Option<Arc<History>>,
+}
+
+/// Two threads at the same `pc` and position only behave the same from
+/// there on if equally many of the loops around `pc` started their current
+/// iteration at this position, so `seen[pc]` has one bit per such count.
+/// The slots of the `k`th thread are the `k`th run of `slot_count` in
+/// `slots`.
+#[derive(Default)]
+struct Threads {
+    list: Vec<Thread>,
+    slots: Vec<Option<usize>>,
+    seen: Vec<u64>,
+}
+
+impl Threads {
+    fn reset(&mut self, len: usize) {
+        self.clear();
+        self.seen.resize(len, 0);
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.slots.clear();
+        self.seen.fill(0);
+    }
+}
+
+/// The buffers of a search, kept between searches so that they only grow
+/// until they fit.
+#[derive(Default)]
+pub(crate) struct Cache {
+    current: Threads,
+    next: Threads,
+    slots: Vec<Option<usize>>,
+    found: Vec<Option<usize>>,
+}
+
+struct Vm<'p, 'a> {
+    program: &'p Program,
+    input: &'a str,
+    search_start: usize,
+    /// How many of the groups' slots are saved; the rest are skipped.
+    group_slots: usize,
+}
+
+impl<'p, 'a> Vm<'p, 'a> {
+    /// Adds the threads reached from `pc` without consuming anything,
+    /// leaving `slots` as it found them.
+    fn add(
+        &self,
+        threads: &mut Threads,
+        pc: usize,
+        i: usize,
+        slots: &mut [Option<usize>],
+        history: Option<Arc<History>>,
+    ) {
+        let empty_loops = self.program.loops[pc]
+            .iter()
+            .take_while(|&&slot| slots[slot] == Some(i))
+            .count()
+            .min(63);
+        if threads.seen[pc] & 1 << empty_loops != 0 {
+            return;
+        }
+        threads.seen[pc] |= 1 << empty_loops;
+
+        match &self.program.insts[pc] {
+            Inst::Jump(target) => self.add(threads, *target, i, slots, history),
+            Inst::Split(first, second) => {
+                self.add(threads, *first, i, slots, history.clone());
+                self.add(threads, *second, i, slots, history);
+            }
+            Inst::Save(slot)
+                if *slot >= self.group_slots && *slot < self.program.group_count * 2 =>
+            {
+                self.add(threads, pc + 1, i, slots, history);
+            }
+            Inst::Save(slot) => {
+                let saved = slots[*slot].replace(i);
+                // Group 0 always closes last and is added to the history
+                // once the match is settled.
+                let history = if slot % 2 == 1 && *slot > 1 && *slot < self.group_slots {
+                    slots[slot - 1].map(|start| {
+                        Arc::new(History {
+                            id: slot / 2,
+                            range: start..i,
+                            prev: history.clone(),
+                        })
+                    })
+                } else {
+                    history
+                };
+                self.add(threads, pc + 1, i, slots, history);
+                slots[*slot] = saved;
+            }
+            Inst::Progress { slot, exit } => {
+                if slots[*slot] == Some(i) {
+                    self.add(threads, *exit, i, slots, history);
+                } else {
+                    self.add(threads, pc + 1, i, slots, history);
+                }
+            }
+            Inst::Assert(assertion) => {
+                if assertion.holds(self.input, i, self.search_start) {
+                    self.add(threads, pc + 1, i, slots, history);
+                }
+            }
+            _ => {
+                threads.list.push(Thread { pc, history });
+                threads.slots.extend_from_slice(slots);
+            }
+        }
+    }
+}
+
+/// Runs the program from `start`, leaving the leftmost match in `state`
+/// with its groups if `captures` is set and only the whole match otherwise.
+/// Priority among threads follows the order of `Split` targets, so the
+/// result is the same one the backtracker would pick, in linear time.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn find_at(
+    program: &Program,
+    input: &str,
+    start: usize,
+    anchored: bool,
+    longest: bool,
+    prefilter: Option<&Prefilter>,
+    captures: bool,
+    cache: &mut Cache,
+    state: &mut State,
+) -> bool {
+    let vm = Vm {
+        program,
+        input,
+        search_start: start,
+        group_slots: if captures { program.group_count * 2 } else { 2 },
+    };
+
+    let n = program.slot_count;
+    let Cache {
+        current,
+        next,
+        slots,
+        found,
+    } = cache;
+    current.reset(program.insts.len());
+    next.reset(program.insts.len());
+    let mut matched: Option<Option<Arc<History>>> = None;
+
+    let mut i = start;
+    loop {
+        if let Some(prefilter) = prefilter.filter(|_| current.list.is_empty() && matched.is_none())
+        {
+            let Some(next) = prefilter.find(input, i) else {
+                return false;
+            };
+            i = next;
+            current.clear();
+        }
+
+        let too_short = input.len() - i < program.min_len;
+        if matched.is_none() && (!anchored || i == start) && !too_short {
+            slots.clear();
+            slots.resize(n, None);
+            vm.add(current, 0, i, slots, None);
+        }
+
+        if current.list.is_empty() && (matched.is_some() || anchored || too_short) {
+            break;
+        }
+
+        let c = input[i..].chars().next();
+        next.clear();
+        for (k, thread) in current.list.drain(..).enumerate() {
+            let thread_slots = &current.slots[k * n..(k + 1) * n];
+            let inst = &program.insts[thread.pc];
+            if *inst == Inst::Match {
+                let better = matched.is_none()
+                    || !longest
+                    || thread_slots[0] < found[0]
+                    || thread_slots[0] == found[0] && thread_slots[1] > found[1];
+                if better {
+                    found.clear();
+                    found.extend_from_slice(thread_slots);
+                    matched = Some(thread.history);
+                }
+                if longest {
+                    continue;
+                }
+                break;
+            }
+
+            if let Some(c) = c {
+                if inst.matches_char(c) {
+                    slots.clear();
+                    slots.extend_from_slice(thread_slots);
+                    vm.add(next, thread.pc + 1, i + c.len_utf8(), slots, thread.history);
+                }
+            }
+        }
+
+        if let Some(c) = c {
+            i += c.len_utf8();
+            std::mem::swap(current, next);
+        } else {
+            break;
+        }
+    }
+
+    let Some(mut history) = matched else {
+        return false;
+    };
+    state.reset(program.group_count);
+    for (id, group) in state.groups.iter_mut().enumerate() {
+        if let (Some(start), Some(end)) = (found[id * 2], found[id * 2 + 1]) {
+            *group = Some(start..end);
+        }
+    }
+    while let Some(node) = history {
+        state.history.push((node.id, node.range.clone()));
+        history = node.prev.clone();
+    }
+    state.history.reverse();
+    if let Some(range) = state.groups[0].clone() {
+        state.history.push((0, range));
+    }
+    true
+}
+
+/// Like `find_at` from the start of `input`, but only says whether there is
+/// a match: threads carry nothing but the loop slots and the first one to
+/// reach `Match` settles it.
+pub(crate) fn is_match(
+    program: &Program,
+    input: &str,
+    anchored: bool,
+    prefilter: Option<&Prefilter>,
+    cache: &mut Cache,
+) -> bool {
+    let vm = Vm {
+        program,
+        input,
+        search_start: 0,
+        group_slots: 0,
+    };
+
+    let n = program.slot_count;
+    let Cache {
+        current,
+        next,
+        slots,
+        ..
+    } = cache;
+    current.reset(program.insts.len());
+    next.reset(program.insts.len());
+
+    let mut i = 0;
+    loop {
+        if let Some(prefilter) = prefilter.filter(|_| current.list.is_empty()) {
+            let Some(start) = prefilter.find(input, i) else {
+                return false;
+            };
+            i = start;
+            current.clear();
+        }
+
+        let too_short = input.len() - i < program.min_len;
+        if (!anchored || i == 0) && !too_short {
+            slots.clear();
+            slots.resize(n, None);
+            vm.add(current, 0, i, slots, None);
+        }
+
+        if current.list.is_empty() && (anchored || too_short) {
+            return false;
+        }
+
+        let c = input[i..].chars().next();
+        next.clear();
+        for (k, thread) in current.list.drain(..).enumerate() {
+            let inst = &program.insts[thread.pc];
+            if *inst == Inst::Match {
+                return true;
+            }
+
+            if let Some(c) = c {
+                if inst.matches_char(c) {
+                    slots.clear();
+                    slots.extend_from_slice(&current.slots[k * n..(k + 1) * n]);
+                    vm.add(next, thread.pc + 1, i + c.len_utf8(), slots, None);
+                }
+            }
+        }
+
+        let Some(c) = c else {
+            return false;
+        };
+        i += c.len_utf8();
+        std::mem::swap(current, next);
+    }
+}
+
+/// Runs a program joined from several by `Program::union` over the whole of
+/// `input`, marking in `matched` each of them that matches somewhere in it;
+/// `patterns[pc]` is the one the `Match` at `pc` ends. Unlike a search for
+/// one match, threads keep going past a `Match` until all have matched.
+pub(crate) fn which_match(
+    program: &Program,
+    input: &str,
+    patterns: &[usize],
+    matched: &mut [bool],
+    cache: &mut Cache,
+) {
+    let vm = Vm {
+        program,
+        input,
+        search_start: 0,
+        group_slots: 0,
+    };
+
+    let n = program.slot_count;
+    let Cache {
+        current,
+        next,
+        slots,
+        ..
+    } = cache;
+    current.reset(program.insts.len());
+    next.reset(program.insts.len());
+
+    let mut left = matched.iter().filter(|&&matched| !matched).count();
+    let mut i = 0;
+    while left > 0 {
+        let too_short = input.len() - i < program.min_len;
+        if !too_short {
+            slots.clear();
+            slots.resize(n, None);
+            vm.add(current, 0, i, slots, None);
+        }
+
+        if current.list.is_empty() && too_short {
+            return;
+        }
+
+        let c = input[i..].chars().next();
+        next.clear();
+        for (k, thread) in current.list.drain(..).enumerate() {
+            let inst = &program.insts[thread.pc];
+            if *inst == Inst::Match {
+                let pattern = patterns[thread.pc];
+                if !matched[pattern] {
+                    matched[pattern] = true;
+                    left -= 1;
+                }
+                continue;
+            }
+
+            if let Some(c) = c {
+                if inst.matches_char(c) {
+                    slots.clear();
+                    slots.extend_from_slice(&current.slots[k * n..(k + 1) * n]);
+                    vm.add(next, thread.pc + 1, i + c.len_utf8(), slots, None);
+                }
+            }
+        }
+
+        let Some(c) = c else {
+            return;
+        };
+        i += c.len_utf8();
+        std::mem::swap(current, next);
+    }
+}
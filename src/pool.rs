@@ -0,0 +1,52 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Scratch space handed out to one search at a time. A search takes a value
+/// of its own, or a new one when they're all in use, and puts it back when
+/// done, so threads never wait on each other past the pop and push and in
+/// steady state every thread reuses buffers that have already grown.
+#[derive(Default)]
+pub(crate) struct Pool<T> {
+    items: Mutex<Vec<Box<T>>>,
+}
+
+pub(crate) struct PoolGuard<'p, T: Default> {
+    pool: &'p Pool<T>,
+    /// Only `None` once dropped.
+    item: Option<Box<T>>,
+}
+
+impl<T: Default> Pool<T> {
+    pub(crate) fn get(&self) -> PoolGuard<'_, T> {
+        PoolGuard {
+            pool: self,
+            item: Some(self.lock().pop().unwrap_or_default()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Box<T>>> {
+        self.items.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T: Default> Deref for PoolGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().unwrap()
+    }
+}
+
+impl<T: Default> DerefMut for PoolGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().unwrap()
+    }
+}
+
+impl<T: Default> Drop for PoolGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.pool.lock().push(item);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::byteset::ByteSet;
+use crate::ir::Ir;
+use crate::program::{Inst, Program};
+use aho_corasick::{AhoCorasick, MatchKind};
+use memchr::memmem;
+use std::ops::Range;
+
+enum Searcher {
+    Memmem(Box<memmem::Finder<'static>>),
+    AhoCorasick(AhoCorasick),
+    ByteSet(ByteSet),
+}
+
+/// Past this many ASCII bytes that can start a match, scanning for them
+/// stops too often to pay off.
+const MAX_FIRST_BYTES: usize = 64;
+
+/// Finds where a match can start, from literals one of which every match
+/// starts with or else the bytes its first char can start with. When the
+/// pattern is nothing but a literal or an alternation of literals the
+/// searcher finds the matches themselves.
+pub(crate) struct Prefilter {
+    searcher: Searcher,
+    exact: bool,
+}
+
+impl Prefilter {
+    pub(crate) fn new(ir: &Ir, capture_group_count: usize, longest: bool) -> Option<Self> {
+        let (mut prefixes, complete) = ir.literal_prefixes(ir.root());
+        if prefixes.iter().any(String::is_empty) {
+            return None;
+        }
+
+        if prefixes.len() == 1 {
+            return Some(Prefilter {
+                searcher: Searcher::Memmem(Box::new(
+                    memmem::Finder::new(prefixes.pop().unwrap().as_bytes()).into_owned(),
+                )),
+                exact: complete && capture_group_count == 1,
+            });
+        }
+
+        let searcher = AhoCorasick::builder()
+            .match_kind(if longest {
+                MatchKind::LeftmostLongest
+            } else {
+                MatchKind::LeftmostFirst
+            })
+            .build(&prefixes)
+            .ok()?;
+        Some(Prefilter {
+            searcher: Searcher::AhoCorasick(searcher),
+            exact: complete && capture_group_count == 1,
+        })
+    }
+
+    /// Scans for the bytes the chars the program can consume first start
+    /// with; `None` if it can match the empty string.
+    pub(crate) fn first_bytes(program: &Program) -> Option<Self> {
+        let mut ascii = [false; 128];
+        let mut non_ascii = false;
+
+        let mut stack = vec![0];
+        let mut seen = vec![false; program.insts.len()];
+        while let Some(pc) = stack.pop() {
+            if seen[pc] {
+                continue;
+            }
+            seen[pc] = true;
+
+            match &program.insts[pc] {
+                Inst::Jump(target) => stack.push(*target),
+                Inst::Split(first, second) => stack.extend([*first, *second]),
+                Inst::Progress { exit, .. } => stack.extend([*exit, pc + 1]),
+                Inst::Save(_) | Inst::Assert(_) => stack.push(pc + 1),
+                Inst::Match | Inst::Reference(_) | Inst::FoldedReference(_) => return None,
+                inst => {
+                    for (c, member) in ascii.iter_mut().enumerate() {
+                        *member |= inst.matches_char(char::from(c as u8));
+                    }
+                    non_ascii |= match inst {
+                        Inst::Literal(c) => !c.is_ascii(),
+                        Inst::Digit { unicode, positive }
+                        | Inst::Alphanumeric { unicode, positive } => *unicode || !*positive,
+                        Inst::CharacterGroup {
+                            positive: true,
+                            ranges,
+                        } => ranges.iter().any(|&(_, end)| !end.is_ascii()),
+                        _ => true,
+                    };
+                }
+            }
+        }
+
+        if ascii.iter().filter(|&&member| member).count() > MAX_FIRST_BYTES {
+            return None;
+        }
+
+        Some(Prefilter {
+            searcher: Searcher::ByteSet(ByteSet::new(ascii, non_ascii)),
+            exact: false,
+        })
+    }
+
+    /// Returns the first position from `start` on where a match could start.
+    pub(crate) fn find(&self, input: &str, start: usize) -> Option<usize> {
+        match &self.searcher {
+            Searcher::Memmem(finder) => finder.find(&input.as_bytes()[start..]).map(|i| start + i),
+            Searcher::AhoCorasick(searcher) => searcher
+                .find(&input[start..])
+                .map(|found| start + found.start()),
+            Searcher::ByteSet(set) => set.find(&input.as_bytes()[start..]).map(|i| start + i),
+        }
+    }
+
+    /// Returns the first match from `start` on if the searcher finds whole
+    /// matches.
+    pub(crate) fn find_exact(&self, input: &str, start: usize) -> Option<Option<Range<usize>>> {
+        if !self.exact {
+            return None;
+        }
+
+        Some(match &self.searcher {
+            Searcher::Memmem(finder) => finder
+                .find(&input.as_bytes()[start..])
+                .map(|i| start + i..start + i + finder.needle().len()),
+            Searcher::AhoCorasick(searcher) => searcher
+                .find(&input[start..])
+                .map(|found| start + found.start()..start + found.end()),
+            Searcher::ByteSet(_) => unreachable!(),
+        })
+    }
+}
+
+/// A literal every match ends with, so a match can't start past the last
+/// place it occurs.
+pub(crate) struct Suffix {
+    finder: memmem::FinderRev<'static>,
+}
+
+impl Suffix {
+    pub(crate) fn new(ir: &Ir) -> Option<Self> {
+        let (suffix, _) = ir.literal_suffix(ir.root());
+        if suffix.is_empty() {
+            return None;
+        }
+
+        Some(Suffix {
+            finder: memmem::FinderRev::new(suffix.as_bytes()).into_owned(),
+        })
+    }
+
+    /// Returns the last position from `start` on where a match could start.
+    pub(crate) fn last_start(&self, input: &str, start: usize) -> Option<usize> {
+        self.finder
+            .rfind(&input.as_bytes()[start..])
+            .map(|i| start + i)
+    }
+}
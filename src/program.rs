@@ -0,0 +1,362 @@
+use crate::ir::{Ir, Node, NodeId};
+use crate::pattern::{fold, is_digit_char, is_word_char};
+use std::fmt;
+use unicode_script::{Script, UnicodeScript};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Inst {
+    Literal(char),
+    FoldedLiteral(char),
+    Digit {
+        unicode: bool,
+        positive: bool,
+    },
+    Alphanumeric {
+        unicode: bool,
+        positive: bool,
+    },
+    CharacterGroup {
+        positive: bool,
+        ranges: Vec<(char, char)>,
+    },
+    Script {
+        positive: bool,
+        script: Script,
+    },
+    Wildcard {
+        crlf: bool,
+    },
+    Assert(Assertion),
+    Split(usize, usize),
+    Jump(usize),
+    Save(usize),
+    Progress {
+        slot: usize,
+        exit: usize,
+    },
+    Reference(usize),
+    FoldedReference(usize),
+    Match,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Assertion {
+    Start,
+    End { crlf: bool },
+    Continue,
+    WordBoundary { unicode: bool, positive: bool },
+    NoWordChar { unicode: bool, before: bool },
+}
+
+impl Inst {
+    pub(crate) fn matches_char(&self, c: char) -> bool {
+        match self {
+            Inst::Literal(expected) => c == *expected,
+            Inst::FoldedLiteral(expected) => fold(c).eq(fold(*expected)),
+            Inst::Digit { unicode, positive } => !*positive ^ is_digit_char(c, *unicode),
+            Inst::Alphanumeric { unicode, positive } => !*positive ^ is_word_char(c, *unicode),
+            Inst::CharacterGroup { positive, ranges } => {
+                !*positive ^ ranges.iter().any(|&(start, end)| start <= c && c <= end)
+            }
+            Inst::Script { positive, script } => !*positive ^ (c.script() == *script),
+            Inst::Wildcard { crlf } => !*crlf || c != '\r' && c != '\n',
+            _ => false,
+        }
+    }
+}
+
+impl Assertion {
+    pub(crate) fn holds(&self, input: &str, i: usize, search_start: usize) -> bool {
+        match self {
+            Assertion::Start => i == 0,
+            Assertion::End { crlf } => {
+                i == input.len() || *crlf && matches!(&input[i..], "\r" | "\r\n")
+            }
+            Assertion::Continue => i == search_start,
+            Assertion::WordBoundary { unicode, positive } => {
+                let before = input[..i]
+                    .chars()
+                    .next_back()
+                    .is_some_and(|c| is_word_char(c, *unicode));
+                let after = input[i..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| is_word_char(c, *unicode));
+                !*positive ^ (before != after)
+            }
+            Assertion::NoWordChar { unicode, before } => {
+                let c = if *before {
+                    input[..i].chars().next_back()
+                } else {
+                    input[i..].chars().next()
+                };
+                !c.is_some_and(|c| is_word_char(c, *unicode))
+            }
+        }
+    }
+}
+
+/// A pattern compiled to Thompson NFA bytecode. Group `n` is saved to slots
+/// `2n` and `2n + 1`, with group 0 wrapping the whole program; each loop gets
+/// one more slot holding where its current iteration started, so that an
+/// iteration matching nothing leaves the loop like it does when backtracking.
+/// `loops[pc]` lists the slots of the loops around `pc`, innermost first,
+/// `references` the groups back references read and `min_len` the fewest
+/// bytes a match spans.
+#[derive(Clone, Debug)]
+pub(crate) struct Program {
+    pub(crate) insts: Vec<Inst>,
+    pub(crate) loops: Vec<Vec<usize>>,
+    pub(crate) references: Vec<usize>,
+    pub(crate) group_count: usize,
+    pub(crate) slot_count: usize,
+    pub(crate) min_len: usize,
+}
+
+impl Program {
+    /// Joins programs without back references into one that runs them side
+    /// by side behind a chain of `Split`s, with their groups dropped and
+    /// their loop slots numbered apart. The second value gives, for the
+    /// `Match` at each `pc`, the index of the program it ends.
+    pub(crate) fn union(programs: &[&Program]) -> (Program, Vec<usize>) {
+        let mut insts = Vec::new();
+        let mut loops = Vec::new();
+        let mut patterns = Vec::new();
+
+        let mut start = programs.len();
+        for k in 0..programs.len() {
+            insts.push(if k + 1 < programs.len() {
+                Inst::Split(start, k + 1)
+            } else {
+                Inst::Jump(start)
+            });
+            loops.push(Vec::new());
+            patterns.push(0);
+            start += programs[k].insts.len();
+        }
+
+        let mut slot_count = 0;
+        for (k, program) in programs.iter().enumerate() {
+            let offset = insts.len();
+            let groups = program.group_count * 2;
+            let slot = |slot: usize| slot_count + slot - groups;
+            for (pc, inst) in program.insts.iter().enumerate() {
+                insts.push(match inst {
+                    Inst::Split(first, second) => Inst::Split(offset + first, offset + second),
+                    Inst::Jump(target) => Inst::Jump(offset + target),
+                    Inst::Save(group_slot) if *group_slot < groups => Inst::Jump(offset + pc + 1),
+                    Inst::Save(loop_slot) => Inst::Save(slot(*loop_slot)),
+                    Inst::Progress {
+                        slot: loop_slot,
+                        exit,
+                    } => Inst::Progress {
+                        slot: slot(*loop_slot),
+                        exit: offset + exit,
+                    },
+                    inst => inst.clone(),
+                });
+                loops.push(
+                    program.loops[pc]
+                        .iter()
+                        .map(|&loop_slot| slot(loop_slot))
+                        .collect(),
+                );
+                patterns.push(k);
+            }
+            slot_count += program.slot_count - groups;
+        }
+
+        let program = Program {
+            insts,
+            loops,
+            references: Vec::new(),
+            group_count: 0,
+            slot_count,
+            min_len: programs
+                .iter()
+                .map(|program| program.min_len)
+                .min()
+                .unwrap_or(0),
+        };
+        (program, patterns)
+    }
+
+    /// Writes the instructions, one a line after its `pc`.
+    pub(crate) fn dump(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        for (pc, inst) in self.insts.iter().enumerate() {
+            writeln!(out, "{pc:4}  {inst:?}")?;
+        }
+        Ok(())
+    }
+
+    /// Returns `None` for longest alternatives, which only the tree matcher
+    /// handles.
+    pub(crate) fn compile(ir: &Ir, capture_group_count: usize) -> Option<Self> {
+        let mut compiler = Compiler {
+            insts: Vec::new(),
+            loops: Vec::new(),
+            active_loops: Vec::new(),
+            references: Vec::new(),
+            slot_count: capture_group_count * 2,
+        };
+        compiler.push(Inst::Save(0));
+        compiler.compile(ir, ir.root())?;
+        compiler.push(Inst::Save(1));
+        compiler.push(Inst::Match);
+
+        Some(Program {
+            insts: compiler.insts,
+            loops: compiler.loops,
+            references: compiler.references,
+            group_count: capture_group_count,
+            slot_count: compiler.slot_count,
+            min_len: ir.min_len(ir.root()),
+        })
+    }
+}
+
+struct Compiler {
+    insts: Vec<Inst>,
+    loops: Vec<Vec<usize>>,
+    active_loops: Vec<usize>,
+    references: Vec<usize>,
+    slot_count: usize,
+}
+
+impl Compiler {
+    fn compile(&mut self, ir: &Ir, node: NodeId) -> Option<()> {
+        match ir.node(node) {
+            Node::Empty => {}
+            Node::Literal(c) => self.push(Inst::Literal(*c)),
+            Node::String(span) => {
+                for c in ir.text(span).chars() {
+                    self.push(Inst::Literal(c));
+                }
+            }
+            Node::FoldedLiteral(c) => self.push(Inst::FoldedLiteral(*c)),
+            Node::Digit { unicode, positive } => self.push(Inst::Digit {
+                unicode: *unicode,
+                positive: *positive,
+            }),
+            Node::Alphanumeric { unicode, positive } => self.push(Inst::Alphanumeric {
+                unicode: *unicode,
+                positive: *positive,
+            }),
+            Node::WordBoundary { unicode, positive } => {
+                self.push(Inst::Assert(Assertion::WordBoundary {
+                    unicode: *unicode,
+                    positive: *positive,
+                }))
+            }
+            Node::NoWordChar { unicode, before } => {
+                self.push(Inst::Assert(Assertion::NoWordChar {
+                    unicode: *unicode,
+                    before: *before,
+                }))
+            }
+            Node::CharacterGroup { positive, ranges } => self.push(Inst::CharacterGroup {
+                positive: *positive,
+                ranges: ir.ranges(ranges).to_vec(),
+            }),
+            Node::Script { positive, script } => self.push(Inst::Script {
+                positive: *positive,
+                script: *script,
+            }),
+            Node::StartAnchor => self.push(Inst::Assert(Assertion::Start)),
+            Node::EndAnchor { crlf } => self.push(Inst::Assert(Assertion::End { crlf: *crlf })),
+            Node::ContinueAnchor => self.push(Inst::Assert(Assertion::Continue)),
+            Node::Wildcard { crlf } => self.push(Inst::Wildcard { crlf: *crlf }),
+            Node::OneOrMore(inner) => {
+                let slot = self.slot();
+                let start = self.insts.len();
+                self.push(Inst::Save(slot));
+                self.active_loops.push(slot);
+                self.compile(ir, *inner)?;
+                let progress = self.insts.len();
+                self.push(Inst::Progress { slot, exit: 0 });
+                self.active_loops.pop();
+                self.push(Inst::Split(start, progress + 2));
+                self.insts[progress] = Inst::Progress {
+                    slot,
+                    exit: progress + 2,
+                };
+            }
+            Node::ZeroOrMore(inner) => {
+                let slot = self.slot();
+                let split = self.insts.len();
+                self.push(Inst::Split(split + 1, 0));
+                self.push(Inst::Save(slot));
+                self.active_loops.push(slot);
+                self.compile(ir, *inner)?;
+                let progress = self.insts.len();
+                self.push(Inst::Progress { slot, exit: 0 });
+                self.active_loops.pop();
+                self.push(Inst::Jump(split));
+                let exit = self.insts.len();
+                self.insts[split] = Inst::Split(split + 1, exit);
+                self.insts[progress] = Inst::Progress { slot, exit };
+            }
+            Node::ZeroOrOne(inner) => {
+                let split = self.insts.len();
+                self.push(Inst::Split(split + 1, 0));
+                self.compile(ir, *inner)?;
+                let exit = self.insts.len();
+                self.insts[split] = Inst::Split(split + 1, exit);
+            }
+            Node::List(items) => {
+                for &item in ir.children(items) {
+                    self.compile(ir, item)?;
+                }
+            }
+            Node::Either(items) => {
+                let mut jumps = Vec::new();
+                let items = ir.children(items);
+                for (k, &item) in items.iter().enumerate() {
+                    if k + 1 < items.len() {
+                        let split = self.insts.len();
+                        self.push(Inst::Split(split + 1, 0));
+                        self.compile(ir, item)?;
+                        jumps.push(self.insts.len());
+                        self.push(Inst::Jump(0));
+                        let next = self.insts.len();
+                        self.insts[split] = Inst::Split(split + 1, next);
+                    } else {
+                        self.compile(ir, item)?;
+                    }
+                }
+                let end = self.insts.len();
+                for jump in jumps {
+                    self.insts[jump] = Inst::Jump(end);
+                }
+            }
+            Node::CaptureGroup { id, item } => {
+                self.push(Inst::Save(id * 2));
+                self.compile(ir, *item)?;
+                self.push(Inst::Save(id * 2 + 1));
+            }
+            Node::Reference(id) | Node::FoldedReference(id) => {
+                self.push(if matches!(ir.node(node), Node::Reference(_)) {
+                    Inst::Reference(*id)
+                } else {
+                    Inst::FoldedReference(*id)
+                });
+                if !self.references.contains(id) {
+                    self.references.push(*id);
+                }
+            }
+            Node::LongestEither(_) => return None,
+        }
+        Some(())
+    }
+
+    fn push(&mut self, inst: Inst) {
+        self.insts.push(inst);
+        self.loops
+            .push(self.active_loops.iter().rev().copied().collect());
+    }
+
+    fn slot(&mut self) -> usize {
+        self.slot_count += 1;
+        self.slot_count - 1
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::Context;
+use codecrafters_grep::bytes::Captures;
+use codecrafters_grep::Regex;
+
+/// What `--replace` prints in place of each match: text with groups of the
+/// match put in, `$1` or `${1}` by number and `${name}` by name, `$0` being
+/// the whole match. `$$` is a dollar sign, as is one followed by nothing
+/// that names a group.
+pub(crate) struct Template {
+    pieces: Vec<Piece>,
+}
+
+enum Piece {
+    Text(String),
+    Group(usize),
+}
+
+impl Template {
+    /// Reads `template`, failing on groups the regex doesn't have.
+    pub(crate) fn parse(template: &str, regex: &Regex) -> anyhow::Result<Self> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(i) = rest.find('$') {
+            text.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let (group, len) = if rest.starts_with('$') {
+                (None, 1)
+            } else if digits > 0 {
+                (Some(&rest[..digits]), digits)
+            } else if let Some(end) = rest.strip_prefix('{').and_then(|braced| braced.find('}')) {
+                (Some(&rest[1..end + 1]), end + 2)
+            } else {
+                (None, 0)
+            };
+            rest = &rest[len..];
+
+            let Some(group) = group else {
+                text.push('$');
+                continue;
+            };
+            let index = match group.parse::<usize>() {
+                Ok(index) if index < regex.captures_len() => index,
+                Ok(_) => anyhow::bail!("No group {group} in the pattern."),
+                Err(_) => regex
+                    .capture_names()
+                    .position(|name| name == Some(group))
+                    .with_context(|| format!("No group named '{group}' in the pattern."))?,
+            };
+            if !text.is_empty() {
+                pieces.push(Piece::Text(std::mem::take(&mut text)));
+            }
+            pieces.push(Piece::Group(index));
+        }
+        text.push_str(rest);
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(Template { pieces })
+    }
+
+    /// Writes the template for a match to `out`, with nothing for the groups
+    /// that took no part in it.
+    pub(crate) fn expand(&self, captures: &Captures, out: &mut Vec<u8>) {
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => out.extend_from_slice(text.as_bytes()),
+                Piece::Group(index) => {
+                    if let Some(group) = captures.get(*index) {
+                        out.extend_from_slice(group.as_bytes());
+                    }
+                }
+            }
+        }
+    }
+}
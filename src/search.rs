@@ -0,0 +1,690 @@
+use crate::color::Colors;
+use crate::replace::Template;
+use anyhow::Context;
+use codecrafters_grep::Regex;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Searches input line by line and prints what matched.
+pub(crate) struct Searcher<'r> {
+    pub(crate) regex: &'r Regex,
+    /// Says which regex gave up when a search does.
+    pub(crate) source: String,
+    pub(crate) print_captures: bool,
+    /// Selects the lines that don't match instead, for `-v`.
+    pub(crate) invert: bool,
+    /// Prints each match on a line of its own instead of the whole line,
+    /// for `-o`.
+    pub(crate) only_matching: bool,
+    /// Starts each line printed with the name of the input and a colon.
+    pub(crate) with_filename: bool,
+    /// Ends names with a NUL instead of a colon or newline, for `-Z`.
+    pub(crate) null_after_name: bool,
+    /// Starts each line printed with its number and a colon, after the name.
+    pub(crate) line_number: bool,
+    /// Starts each line printed with how many bytes of the input came before
+    /// it, or with `-o` before the match, after the line number. For `-b`.
+    pub(crate) byte_offset: bool,
+    /// Stops reading an input after this many lines are selected, for `-m`.
+    pub(crate) max_count: Option<usize>,
+    /// How many lines to print after each selected line, for `-A`.
+    pub(crate) after_context: usize,
+    /// How many lines to print before each selected line, for `-B`.
+    pub(crate) before_context: usize,
+    /// The line between groups of context, if any.
+    pub(crate) group_separator: Option<String>,
+    pub(crate) output: Output,
+    pub(crate) binary_files: BinaryFiles,
+    /// Flushes the output after each line, for `--line-buffered`.
+    pub(crate) line_buffered: bool,
+    /// What ends each line read and printed: a newline, or a NUL for `-z`.
+    pub(crate) line_terminator: u8,
+    /// What to print in place of each match in a selected line, for
+    /// `--replace`.
+    pub(crate) replace: Option<Template>,
+    /// Highlights the matches and the names and numbers, for `--color`.
+    pub(crate) colors: Option<Colors>,
+    /// Whether any input has had lines printed yet, so that context from
+    /// one is separated from the next's.
+    pub(crate) printed_any: AtomicBool,
+    /// Whether that's done here. Inputs searched in parallel are printed
+    /// one after another into buffers of their own, so it's left to
+    /// whatever writes them out, with `print_group_separator`.
+    pub(crate) separate_inputs: bool,
+    /// What's been searched so far, for `--stats`.
+    pub(crate) stats: Stats,
+}
+
+/// Totals over the inputs searched to the end, or as far as they needed to
+/// be read.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    files: AtomicUsize,
+    matched_files: AtomicUsize,
+    matched_lines: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl Stats {
+    fn add(&self, matched_lines: usize, bytes: usize) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        if matched_lines > 0 {
+            self.matched_files.fetch_add(1, Ordering::Relaxed);
+        }
+        self.matched_lines
+            .fetch_add(matched_lines, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Prints the totals after the output, with a blank line between.
+    pub(crate) fn print(&self, elapsed: Duration, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out)?;
+        writeln!(out, "{} files searched", self.files.load(Ordering::Relaxed))?;
+        writeln!(
+            out,
+            "{} files contained matches",
+            self.matched_files.load(Ordering::Relaxed)
+        )?;
+        writeln!(
+            out,
+            "{} matched lines",
+            self.matched_lines.load(Ordering::Relaxed)
+        )?;
+        writeln!(out, "{} bytes searched", self.bytes.load(Ordering::Relaxed))?;
+        writeln!(out, "{:.6} seconds", elapsed.as_secs_f64())
+    }
+}
+
+/// What's printed for each input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Output {
+    /// The lines selected.
+    #[default]
+    Lines,
+    /// How many lines were selected, for `-c`.
+    Count,
+    /// The name of the input if a line was selected, for `-l`.
+    FilesWithMatches,
+    /// The name of the input if no line was selected, for `-L`.
+    FilesWithoutMatch,
+    /// Nothing, for `-q`.
+    Quiet,
+    /// A JSON object on a line of its own for each match, for `--json`.
+    Json,
+}
+
+/// How to treat inputs with NUL bytes in them, which text doesn't have.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum BinaryFiles {
+    /// Says whether one matches instead of printing its lines.
+    #[default]
+    Binary,
+    /// Searches them like any other, for `-a`.
+    Text,
+    /// Takes them not to match, for `-I`.
+    WithoutMatch,
+}
+
+/// Where the printing of an input is up to.
+#[derive(Default)]
+struct Printed {
+    /// The number of the last line printed.
+    last: Option<usize>,
+    /// How many of the lines coming are still context for the last selected
+    /// one.
+    after: usize,
+    /// The lines since the last one printed, up to as many as are context
+    /// for the next selected line.
+    before: VecDeque<(usize, usize, Vec<u8>)>,
+}
+
+impl Searcher<'_> {
+    /// Prints the lines of `lines` that match, or with `--captures` their
+    /// groups, and returns whether any did. With `invert` it's the lines
+    /// that don't match instead, which have no groups to print. `name` says
+    /// where the input comes from, in errors and before the lines printed.
+    /// Notices that aren't errors, like that a binary file matches, go to
+    /// `messages`.
+    pub(crate) fn search(
+        &self,
+        name: &str,
+        lines: impl Lines,
+        out: &mut impl Write,
+        messages: &mut impl Write,
+    ) -> anyhow::Result<bool> {
+        let (count, read) = self.search_lines(name, lines, 1, 0, out, messages)?;
+        self.finish(name, count, read, out)
+    }
+
+    /// Whether the lines of `data` can be searched in runs apart, with
+    /// `search_run`, the outputs one after another being what `search`
+    /// gives: so whether each line's output is the line's alone, with no
+    /// context, `-m`, binary files to tell apart or search stopping at the
+    /// first match, and whether it can wait to be written, which with
+    /// `--line-buffered` it can't.
+    pub(crate) fn can_split(&self, data: &[u8]) -> bool {
+        self.max_count.is_none()
+            && !self.context()
+            && !self.line_buffered
+            && matches!(self.output, Output::Lines | Output::Count)
+            && !(self.detects_binary() && memchr::memchr(0, data).is_some())
+    }
+
+    /// Searches `run`, lines of an input starting with line `number` at
+    /// `offset`, like `search`, and returns how many were selected. For
+    /// searching an input split into runs on several threads, where what's
+    /// printed for the input as a whole is left to `finish`.
+    pub(crate) fn search_run(
+        &self,
+        name: &str,
+        run: &[u8],
+        number: usize,
+        offset: usize,
+        out: &mut impl Write,
+    ) -> anyhow::Result<usize> {
+        // There's nothing to say, with no binary files among those split.
+        let (count, _) = self.search_lines(name, run, number, offset, out, &mut io::sink())?;
+        Ok(count)
+    }
+
+    /// Searches `lines` from line `first` at offset `next_offset`, and
+    /// returns how many were selected and the offset it stopped reading at.
+    fn search_lines(
+        &self,
+        name: &str,
+        mut lines: impl Lines,
+        first: usize,
+        mut next_offset: usize,
+        out: &mut impl Write,
+        messages: &mut impl Write,
+    ) -> anyhow::Result<(usize, usize)> {
+        let mut count = 0;
+        let mut printed = Printed::default();
+        // Binary files mostly have a NUL early on, so it's looked for
+        // before anything is printed as well as in each line.
+        let detect_binary = self.detects_binary();
+        let mut binary = detect_binary
+            && lines
+                .peek()
+                .with_context(|| format!("reading {name}"))?
+                .contains(&0);
+        for number in first.. {
+            // Past the last line `-m` allows, only its context is left.
+            let done = self.max_count.is_some_and(|max_count| count >= max_count);
+            if done && printed.after == 0 {
+                break;
+            }
+            // What the last line printed shows up before waiting on the next.
+            if self.line_buffered {
+                out.flush()?;
+            }
+            let line = lines
+                .next_line(self.line_terminator)
+                .with_context(|| format!("reading {name}"))?;
+            if line.is_empty() {
+                break;
+            }
+            let offset = next_offset;
+            next_offset += line.len();
+            let line = line.strip_suffix(&[self.line_terminator]).unwrap_or(line);
+
+            binary |= detect_binary && line.contains(&0);
+            if binary && self.binary_files == BinaryFiles::WithoutMatch {
+                break;
+            }
+            if binary && matches!(self.output, Output::Lines | Output::Json) {
+                if done {
+                    break;
+                }
+                if self.is_selected(line, || format!("line {number} of {name}"))? {
+                    out.flush()?;
+                    writeln!(messages, "grep: {name}: binary file matches")?;
+                    return Ok((1, next_offset));
+                }
+                continue;
+            }
+
+            if !done && self.search_line(name, number, offset, line, out, &mut printed)? {
+                count += 1;
+                if self.context() {
+                    printed.after = self.after_context;
+                }
+                printed.before.clear();
+                // One line is all it takes to settle whether the input is
+                // listed, or with `-q` the exit status.
+                if matches!(
+                    self.output,
+                    Output::FilesWithMatches | Output::FilesWithoutMatch | Output::Quiet
+                ) {
+                    break;
+                }
+            } else if printed.after > 0 {
+                printed.after -= 1;
+                self.print_prefix(name, number, offset, '-', out, &mut printed)?;
+                self.print_line(name, number, line, false, out)?;
+            } else if self.context() && self.before_context > 0 {
+                if printed.before.len() == self.before_context {
+                    printed.before.pop_front();
+                }
+                printed.before.push_back((number, offset, line.to_vec()));
+            }
+        }
+        Ok((count, next_offset))
+    }
+
+    /// Prints what's printed once an input with `count` lines selected is
+    /// done, having read `read` bytes of it, and returns whether any were.
+    pub(crate) fn finish(
+        &self,
+        name: &str,
+        count: usize,
+        read: usize,
+        out: &mut impl Write,
+    ) -> anyhow::Result<bool> {
+        match self.output {
+            Output::Lines | Output::Quiet | Output::Json => {}
+            Output::Count => {
+                if self.with_filename {
+                    self.print_name(name, b":", out)?;
+                }
+                writeln!(out, "{count}")?;
+            }
+            Output::FilesWithMatches => {
+                if count > 0 {
+                    self.print_name(name, b"\n", out)?;
+                }
+            }
+            Output::FilesWithoutMatch => {
+                if count == 0 {
+                    self.print_name(name, b"\n", out)?;
+                }
+            }
+        }
+        if self.line_buffered {
+            out.flush()?;
+        }
+        self.stats.add(count, read);
+        Ok(count > 0)
+    }
+
+    fn search_line(
+        &self,
+        name: &str,
+        number: usize,
+        offset: usize,
+        line: &[u8],
+        out: &mut impl Write,
+        printed: &mut Printed,
+    ) -> anyhow::Result<bool> {
+        let location = || format!("line {number} of {name}");
+        if self.output == Output::Json {
+            return self.print_json(name, number, offset, line, out);
+        }
+        if self.output != Output::Lines {
+            return self.is_selected(line, location);
+        }
+
+        if self.print_captures && !self.invert {
+            let captures = self
+                .regex
+                .try_captures_bytes(line)
+                .with_context(|| self.gave_up(location()))?;
+            if let Some(captures) = captures {
+                for i in 1..captures.len() {
+                    self.print_prefix(name, number, offset, ':', out, printed)?;
+                    write!(out, "{}:", i)?;
+                    if let Some(group) = captures.get(i) {
+                        out.write_all(b" ")?;
+                        out.write_all(group.as_bytes())?;
+                    }
+                    out.write_all(&[self.line_terminator])?;
+                }
+                return Ok(true);
+            }
+        } else if self.only_matching && !self.invert {
+            return self.for_each_match(line, true, location, |found, text| {
+                let offset = offset + found.start;
+                self.print_prefix(name, number, offset, ':', out, printed)?;
+                self.paint(|colors| &colors.selected_match, text, out)?;
+                out.write_all(&[self.line_terminator])?;
+                Ok(())
+            });
+        } else if self.is_selected(line, location)? {
+            // The lines `-v` selects have no matches to print with `-o`.
+            if !self.only_matching {
+                self.print_prefix(name, number, offset, ':', out, printed)?;
+                self.print_line(name, number, line, true, out)?;
+            }
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Prints each match in the line as an object with where it is in the
+    /// input, by line number and by byte offsets, and its text and groups.
+    /// A group that took no part in the match is null.
+    fn print_json(
+        &self,
+        name: &str,
+        number: usize,
+        offset: usize,
+        line: &[u8],
+        out: &mut impl Write,
+    ) -> anyhow::Result<bool> {
+        let mut matched = false;
+        for captures in self.regex.try_captures_iter_bytes(line) {
+            let captures =
+                captures.with_context(|| self.gave_up(format!("line {number} of {name}")))?;
+            matched = true;
+            let found = captures.get(0).expect("a match has a group 0");
+            out.write_all(b"{\"file\":")?;
+            write_json_string(name.as_bytes(), out)?;
+            write!(
+                out,
+                ",\"line_number\":{number},\"start\":{},\"end\":{},\"text\":",
+                offset + found.start(),
+                offset + found.end(),
+            )?;
+            write_json_string(found.as_bytes(), out)?;
+            out.write_all(b",\"captures\":[")?;
+            for i in 1..captures.len() {
+                if i > 1 {
+                    out.write_all(b",")?;
+                }
+                match captures.get(i) {
+                    Some(group) => {
+                        write!(
+                            out,
+                            "{{\"start\":{},\"end\":{},\"text\":",
+                            offset + group.start(),
+                            offset + group.end(),
+                        )?;
+                        write_json_string(group.as_bytes(), out)?;
+                        out.write_all(b"}")?;
+                    }
+                    None => out.write_all(b"null")?,
+                }
+            }
+            out.write_all(b"]}\n")?;
+        }
+        Ok(matched)
+    }
+
+    /// Calls `f` with where each match in the line is and what to print for
+    /// it: the match, or with `replace` set what `--replace` makes of it.
+    /// Empty matches are passed over unless replaced, having nothing to show.
+    /// Returns whether there were any.
+    fn for_each_match(
+        &self,
+        line: &[u8],
+        replace: bool,
+        location: impl Fn() -> String,
+        mut f: impl FnMut(Range<usize>, &[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<bool> {
+        let mut matched = false;
+        match self.replace.as_ref().filter(|_| replace) {
+            Some(template) => {
+                let mut replaced = Vec::new();
+                for captures in self.regex.try_captures_iter_bytes(line) {
+                    let captures = captures.with_context(|| self.gave_up(location()))?;
+                    matched = true;
+                    replaced.clear();
+                    template.expand(&captures, &mut replaced);
+                    let found = captures.get(0).expect("a match has a group 0");
+                    f(found.range(), &replaced)?;
+                }
+            }
+            None => {
+                for found in self.regex.try_find_iter_bytes(line) {
+                    let found = found.with_context(|| self.gave_up(location()))?;
+                    matched = true;
+                    if !found.as_bytes().is_empty() {
+                        f(found.range(), found.as_bytes())?;
+                    }
+                }
+            }
+        }
+        Ok(matched)
+    }
+
+    fn is_selected(&self, line: &[u8], location: impl Fn() -> String) -> anyhow::Result<bool> {
+        let matched = self
+            .regex
+            .try_is_match_bytes(line)
+            .with_context(|| self.gave_up(location()))?;
+        Ok(matched != self.invert)
+    }
+
+    /// Whether inputs with a NUL in them are told apart from text, which
+    /// with `-z` they aren't, being split on them.
+    fn detects_binary(&self) -> bool {
+        self.binary_files != BinaryFiles::Text && self.line_terminator != 0
+    }
+
+    /// Whether lines around the ones selected are printed too.
+    fn context(&self) -> bool {
+        (self.after_context > 0 || self.before_context > 0)
+            && self.output == Output::Lines
+            && !self.only_matching
+    }
+
+    /// Starts line `number` of the input, which is selected if `separator`
+    /// is a colon and context if it's a dash, and separates it from the
+    /// line printed before it if there are lines left out between them.
+    /// The context before a selected line is printed first.
+    fn print_prefix(
+        &self,
+        name: &str,
+        number: usize,
+        offset: usize,
+        separator: char,
+        out: &mut impl Write,
+        printed: &mut Printed,
+    ) -> anyhow::Result<()> {
+        if separator == ':' {
+            while let Some((number, offset, line)) = printed.before.pop_front() {
+                self.print_prefix(name, number, offset, '-', out, printed)?;
+                self.print_line(name, number, &line, false, out)?;
+            }
+        }
+
+        let gap = match printed.last {
+            Some(last) => number > last + 1,
+            None => self.separate_inputs && self.printed_any.swap(true, Ordering::Relaxed),
+        };
+        if gap {
+            self.print_group_separator(out)?;
+        }
+        printed.last = Some(number);
+
+        let mut separator_bytes = [0; 4];
+        let separator = separator.encode_utf8(&mut separator_bytes).as_bytes();
+        if self.with_filename {
+            self.print_name(name, separator, out)?;
+        }
+        if self.line_number {
+            self.paint(
+                |colors| &colors.line_number,
+                number.to_string().as_bytes(),
+                out,
+            )?;
+            self.paint(|colors| &colors.separator, separator, out)?;
+        }
+        if self.byte_offset {
+            let offset = offset.to_string();
+            self.paint(|colors| &colors.byte_offset, offset.as_bytes(), out)?;
+            self.paint(|colors| &colors.separator, separator, out)?;
+        }
+        Ok(())
+    }
+
+    /// Prints the name of the input and what follows it, a NUL instead with
+    /// `-Z`.
+    fn print_name(&self, name: &str, separator: &[u8], out: &mut impl Write) -> io::Result<()> {
+        self.paint(|colors| &colors.filename, name.as_bytes(), out)?;
+        if self.null_after_name {
+            out.write_all(b"\0")
+        } else if separator == b"\n" {
+            out.write_all(separator)
+        } else {
+            self.paint(|colors| &colors.separator, separator, out)
+        }
+    }
+
+    /// Separates groups of lines and their context that aren't next to each
+    /// other, or are from different inputs.
+    pub(crate) fn print_group_separator(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.context() {
+            if let Some(group_separator) = &self.group_separator {
+                self.paint(|colors| &colors.separator, group_separator.as_bytes(), out)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prints a whole line, selected or context, with the matches in it
+    /// highlighted if colors are on; with `-v` it's the context lines that
+    /// have them. In selected lines `--replace` replaces them.
+    fn print_line(
+        &self,
+        name: &str,
+        number: usize,
+        line: &[u8],
+        selected: bool,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let replace = selected && self.replace.is_some();
+        if self.colors.is_none() && !replace {
+            out.write_all(line)?;
+            out.write_all(&[self.line_terminator])?;
+            return Ok(());
+        }
+
+        let (match_color, line_color) = match &self.colors {
+            Some(colors) if selected => (&colors.selected_match, &colors.selected_line),
+            Some(colors) => (&colors.context_match, &colors.context_line),
+            None => (&String::new(), &String::new()),
+        };
+        let colors = self.colors.as_ref();
+        if let Some(colors) = colors {
+            colors.start(line_color, out)?;
+        }
+        let mut written = 0;
+        // Whether the line's color is on, which a match ending the line
+        // leaves off, as GNU grep does.
+        let mut line_colored = true;
+        let location = || format!("line {number} of {name}");
+        self.for_each_match(line, replace, location, |found, text| {
+            out.write_all(&line[written..found.start])?;
+            if let Some(colors) = colors {
+                colors.paint(match_color, text, out)?;
+                // Ending the match ended the line's color too.
+                line_colored = found.end < line.len();
+                if line_colored {
+                    colors.start(line_color, out)?;
+                }
+            } else {
+                out.write_all(text)?;
+            }
+            written = found.end;
+            Ok(())
+        })?;
+        out.write_all(&line[written..])?;
+        if let Some(colors) = colors.filter(|_| line_colored) {
+            colors.end(line_color, out)?;
+        }
+        out.write_all(&[self.line_terminator])?;
+        Ok(())
+    }
+
+    /// Writes `text` in the color `part` picks, if colors are on.
+    fn paint(
+        &self,
+        part: impl Fn(&Colors) -> &String,
+        text: &[u8],
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        match &self.colors {
+            Some(colors) => colors.paint(part(colors), text, out),
+            None => out.write_all(text),
+        }
+    }
+
+    fn gave_up(&self, location: String) -> String {
+        format!("searching {location} for {}", self.source)
+    }
+}
+
+/// Writes `text` as a JSON string, with the bytes that aren't UTF-8 taken as
+/// replacement chars.
+fn write_json_string(text: &[u8], out: &mut impl Write) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in String::from_utf8_lossy(text).chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{c}")?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/// Where `Searcher::search` takes lines from: a reader, which each line is
+/// copied out of, or input already in memory, like a mapped file, which
+/// lines are borrowed from as they are.
+pub(crate) trait Lines {
+    /// The start of what's left to read, at least, without reading it.
+    fn peek(&mut self) -> io::Result<&[u8]>;
+
+    /// The next line with its terminator, if it has one, or nothing at the
+    /// end.
+    fn next_line(&mut self, terminator: u8) -> io::Result<&[u8]>;
+}
+
+/// The lines of a reader, each read into the same buffer.
+pub(crate) struct ReadLines<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: BufRead> ReadLines<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        ReadLines {
+            reader,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<R: BufRead> Lines for ReadLines<R> {
+    fn peek(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn next_line(&mut self, terminator: u8) -> io::Result<&[u8]> {
+        self.buffer.clear();
+        self.reader.read_until(terminator, &mut self.buffer)?;
+        Ok(&self.buffer)
+    }
+}
+
+impl Lines for &[u8] {
+    fn peek(&mut self) -> io::Result<&[u8]> {
+        Ok(self)
+    }
+
+    fn next_line(&mut self, terminator: u8) -> io::Result<&[u8]> {
+        let end = memchr::memchr(terminator, self).map_or(self.len(), |i| i + 1);
+        let (line, rest) = self.split_at(end);
+        *self = rest;
+        Ok(line)
+    }
+}
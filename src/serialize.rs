@@ -0,0 +1,671 @@
+use crate::ir::{Ir, Node, NodeId};
+use crate::program::{Assertion, Inst, Program};
+use crate::Regex;
+use std::ops::Range;
+use std::time::Duration;
+use unicode_script::Script;
+
+const MAGIC: &[u8; 4] = b"CGRX";
+const VERSION: u8 = 6;
+
+impl Regex {
+    /// Saves the regex as its tree and bytecode, the parts that take parsing
+    /// and compiling to get; the engines worked out from those are set up
+    /// again on loading, and the DFA builds its states lazily anyway.
+    /// Numbers are LEB128 varints, chars their scalar values and scripts
+    /// their short names, and a checksum of the rest comes last.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer(MAGIC.to_vec());
+        w.u8(VERSION);
+        w.bool(self.longest);
+        w.option(self.backtrack_limit, Writer::usize);
+        w.option(self.timeout, Writer::duration);
+        w.usize(self.dfa_size_limit);
+        w.usize(self.capture_group_count);
+        for name in &self.group_names {
+            w.option(name.as_deref(), Writer::str);
+        }
+        w.ir(&self.ir);
+        w.option(self.program.as_ref(), Writer::program);
+        let checksum = checksum(&w.0);
+        w.0.extend_from_slice(&checksum.to_le_bytes());
+        w.0
+    }
+
+    /// Loads a regex saved by `to_bytes`, which must come from the same
+    /// version of this crate. A damaged file fails the checksum; beyond
+    /// that the data is only checked to index within itself, so a search
+    /// can't read out of bounds, and not that it's anything the compiler
+    /// could have produced.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Regex> {
+        let Some(rest) = bytes.strip_prefix(MAGIC) else {
+            anyhow::bail!("Not a compiled regex.");
+        };
+        let Some(&version) = rest.first() else {
+            return Err(corrupt());
+        };
+        if version != VERSION {
+            anyhow::bail!("Unsupported compiled regex version {version}.");
+        }
+        let Some(split) = bytes
+            .len()
+            .checked_sub(8)
+            .filter(|&split| split > MAGIC.len())
+        else {
+            return Err(corrupt());
+        };
+        let (data, expected) = bytes.split_at(split);
+        ensure(checksum(data).to_le_bytes() == expected)?;
+
+        let mut r = Reader(&data[MAGIC.len() + 1..]);
+
+        let longest = r.bool()?;
+        let backtrack_limit = r.option(Reader::usize)?;
+        let timeout = r.option(Reader::duration)?;
+        let dfa_size_limit = r.usize()?;
+        let capture_group_count = r.len()?;
+        let group_names = (0..capture_group_count)
+            .map(|_| r.option(|r| r.str().map(str::to_string)))
+            .collect::<anyhow::Result<_>>()?;
+        let ir = r.ir(capture_group_count)?;
+        let program = r.option(Reader::program)?;
+        if !r.0.is_empty() {
+            return Err(corrupt());
+        }
+        if let Some(program) = &program {
+            check_program(program, capture_group_count)?;
+        }
+
+        Ok(Regex::from_parts(
+            ir,
+            program,
+            group_names,
+            longest,
+            backtrack_limit,
+            timeout,
+            dfa_size_limit,
+        ))
+    }
+}
+
+/// 64-bit FNV-1a.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn corrupt() -> anyhow::Error {
+    anyhow::anyhow!("Corrupt compiled regex.")
+}
+
+fn ensure(ok: bool) -> anyhow::Result<()> {
+    if ok {
+        Ok(())
+    } else {
+        Err(corrupt())
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    fn usize(&mut self, mut value: usize) {
+        while value >= 0x80 {
+            self.u8(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.u8(value as u8);
+    }
+
+    fn char(&mut self, c: char) {
+        self.usize(c as usize);
+    }
+
+    fn range(&mut self, range: &Range<usize>) {
+        self.usize(range.start);
+        self.usize(range.end);
+    }
+
+    fn duration(&mut self, duration: Duration) {
+        self.usize(duration.as_secs() as usize);
+        self.usize(duration.subsec_nanos() as usize);
+    }
+
+    fn option<T>(&mut self, value: Option<T>, write: impl FnOnce(&mut Self, T)) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            write(self, value);
+        }
+    }
+
+    fn ranges(&mut self, ranges: &[(char, char)]) {
+        self.usize(ranges.len());
+        for &(start, end) in ranges {
+            self.char(start);
+            self.char(end);
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn script(&mut self, script: Script) {
+        self.str(script.short_name());
+    }
+
+    fn ir(&mut self, ir: &Ir) {
+        self.str(&ir.text);
+        self.ranges(&ir.ranges);
+        self.usize(ir.children.len());
+        for &child in &ir.children {
+            self.usize(child);
+        }
+        self.usize(ir.nodes.len());
+        for node in &ir.nodes {
+            self.node(node);
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::Empty => self.u8(0),
+            Node::Literal(c) => {
+                self.u8(1);
+                self.char(*c);
+            }
+            Node::String(span) => {
+                self.u8(2);
+                self.range(span);
+            }
+            Node::FoldedLiteral(c) => {
+                self.u8(3);
+                self.char(*c);
+            }
+            Node::Digit { unicode, positive } => {
+                self.u8(4);
+                self.bool(*unicode);
+                self.bool(*positive);
+            }
+            Node::Alphanumeric { unicode, positive } => {
+                self.u8(5);
+                self.bool(*unicode);
+                self.bool(*positive);
+            }
+            Node::WordBoundary { unicode, positive } => {
+                self.u8(6);
+                self.bool(*unicode);
+                self.bool(*positive);
+            }
+            Node::CharacterGroup { positive, ranges } => {
+                self.u8(7);
+                self.bool(*positive);
+                self.range(ranges);
+            }
+            Node::Script { positive, script } => {
+                self.u8(8);
+                self.bool(*positive);
+                self.script(*script);
+            }
+            Node::StartAnchor => self.u8(9),
+            Node::EndAnchor { crlf } => {
+                self.u8(10);
+                self.bool(*crlf);
+            }
+            Node::ContinueAnchor => self.u8(11),
+            Node::OneOrMore(item) => {
+                self.u8(12);
+                self.usize(*item);
+            }
+            Node::ZeroOrMore(item) => {
+                self.u8(13);
+                self.usize(*item);
+            }
+            Node::ZeroOrOne(item) => {
+                self.u8(14);
+                self.usize(*item);
+            }
+            Node::Wildcard { crlf } => {
+                self.u8(15);
+                self.bool(*crlf);
+            }
+            Node::List(items) => {
+                self.u8(16);
+                self.range(items);
+            }
+            Node::Either(items) => {
+                self.u8(17);
+                self.range(items);
+            }
+            Node::LongestEither(items) => {
+                self.u8(18);
+                self.range(items);
+            }
+            Node::Reference(id) => {
+                self.u8(19);
+                self.usize(*id);
+            }
+            Node::FoldedReference(id) => {
+                self.u8(20);
+                self.usize(*id);
+            }
+            Node::CaptureGroup { id, item } => {
+                self.u8(21);
+                self.usize(*id);
+                self.usize(*item);
+            }
+            Node::NoWordChar { unicode, before } => {
+                self.u8(22);
+                self.bool(*unicode);
+                self.bool(*before);
+            }
+        }
+    }
+
+    fn program(&mut self, program: &Program) {
+        self.usize(program.group_count);
+        self.usize(program.slot_count);
+        self.usize(program.min_len);
+        self.usize(program.references.len());
+        for &id in &program.references {
+            self.usize(id);
+        }
+        self.usize(program.insts.len());
+        for (inst, loops) in program.insts.iter().zip(&program.loops) {
+            self.inst(inst);
+            self.usize(loops.len());
+            for &slot in loops {
+                self.usize(slot);
+            }
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match inst {
+            Inst::Literal(c) => {
+                self.u8(0);
+                self.char(*c);
+            }
+            Inst::FoldedLiteral(c) => {
+                self.u8(1);
+                self.char(*c);
+            }
+            Inst::Digit { unicode, positive } => {
+                self.u8(2);
+                self.bool(*unicode);
+                self.bool(*positive);
+            }
+            Inst::Alphanumeric { unicode, positive } => {
+                self.u8(3);
+                self.bool(*unicode);
+                self.bool(*positive);
+            }
+            Inst::CharacterGroup { positive, ranges } => {
+                self.u8(4);
+                self.bool(*positive);
+                self.ranges(ranges);
+            }
+            Inst::Script { positive, script } => {
+                self.u8(5);
+                self.bool(*positive);
+                self.script(*script);
+            }
+            Inst::Wildcard { crlf } => {
+                self.u8(6);
+                self.bool(*crlf);
+            }
+            Inst::Assert(Assertion::Start) => self.u8(7),
+            Inst::Assert(Assertion::End { crlf }) => {
+                self.u8(8);
+                self.bool(*crlf);
+            }
+            Inst::Assert(Assertion::Continue) => self.u8(9),
+            Inst::Assert(Assertion::WordBoundary { unicode, positive }) => {
+                self.u8(10);
+                self.bool(*unicode);
+                self.bool(*positive);
+            }
+            Inst::Split(first, second) => {
+                self.u8(11);
+                self.usize(*first);
+                self.usize(*second);
+            }
+            Inst::Jump(target) => {
+                self.u8(12);
+                self.usize(*target);
+            }
+            Inst::Save(slot) => {
+                self.u8(13);
+                self.usize(*slot);
+            }
+            Inst::Progress { slot, exit } => {
+                self.u8(14);
+                self.usize(*slot);
+                self.usize(*exit);
+            }
+            Inst::Reference(id) => {
+                self.u8(15);
+                self.usize(*id);
+            }
+            Inst::FoldedReference(id) => {
+                self.u8(16);
+                self.usize(*id);
+            }
+            Inst::Match => self.u8(17),
+            Inst::Assert(Assertion::NoWordChar { unicode, before }) => {
+                self.u8(18);
+                self.bool(*unicode);
+                self.bool(*before);
+            }
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        let (&byte, rest) = self.0.split_first().ok_or_else(corrupt)?;
+        self.0 = rest;
+        Ok(byte)
+    }
+
+    fn bool(&mut self) -> anyhow::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(corrupt()),
+        }
+    }
+
+    fn usize(&mut self) -> anyhow::Result<usize> {
+        let mut value = 0usize;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            let bits = usize::from(byte & 0x7f);
+            ensure(shift < usize::BITS && bits << shift >> shift == bits)?;
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    /// Reads a length, which can't be more than the bytes left since every
+    /// item takes at least one, so a bad one can't ask for a huge
+    /// allocation.
+    fn len(&mut self) -> anyhow::Result<usize> {
+        let len = self.usize()?;
+        ensure(len <= self.0.len())?;
+        Ok(len)
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.len()?;
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn str(&mut self) -> anyhow::Result<&'a str> {
+        std::str::from_utf8(self.bytes()?).map_err(|_| corrupt())
+    }
+
+    fn char(&mut self) -> anyhow::Result<char> {
+        u32::try_from(self.usize()?)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(corrupt)
+    }
+
+    fn range(&mut self) -> anyhow::Result<Range<usize>> {
+        let range = self.usize()?..self.usize()?;
+        ensure(range.start <= range.end)?;
+        Ok(range)
+    }
+
+    fn duration(&mut self) -> anyhow::Result<Duration> {
+        let secs = self.usize()? as u64;
+        let nanos = u32::try_from(self.usize()?)
+            .ok()
+            .filter(|&nanos| nanos < 1_000_000_000)
+            .ok_or_else(corrupt)?;
+        Ok(Duration::new(secs, nanos))
+    }
+
+    fn option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Option<T>> {
+        if self.bool()? {
+            read(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn ranges(&mut self) -> anyhow::Result<Vec<(char, char)>> {
+        (0..self.len()?)
+            .map(|_| Ok((self.char()?, self.char()?)))
+            .collect()
+    }
+
+    fn script(&mut self) -> anyhow::Result<Script> {
+        Script::from_short_name(self.str()?).ok_or_else(corrupt)
+    }
+
+    fn ir(&mut self, capture_group_count: usize) -> anyhow::Result<Ir> {
+        let text = self.str()?.to_string();
+        let ranges = self.ranges()?;
+        let children = (0..self.len()?)
+            .map(|_| self.usize())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let nodes = (0..self.len()?)
+            .map(|_| self.node())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        ensure(!nodes.is_empty())?;
+
+        // Children come before their parents, which is what keeps the tree
+        // from having cycles.
+        let child = |item: NodeId, id: NodeId| item < id;
+        let items = |span: &Range<usize>, id: NodeId| {
+            children
+                .get(span.clone())
+                .is_some_and(|items| items.iter().all(|&item| child(item, id)))
+        };
+        for (id, node) in nodes.iter().enumerate() {
+            ensure(match node {
+                Node::String(span) => text.get(span.clone()).is_some(),
+                Node::CharacterGroup { ranges: span, .. } => span.end <= ranges.len(),
+                Node::OneOrMore(item) | Node::ZeroOrMore(item) | Node::ZeroOrOne(item) => {
+                    child(*item, id)
+                }
+                Node::List(span) | Node::Either(span) | Node::LongestEither(span) => {
+                    items(span, id)
+                }
+                Node::Reference(group) | Node::FoldedReference(group) => {
+                    *group < capture_group_count
+                }
+                Node::CaptureGroup { id: group, item } => {
+                    *group < capture_group_count && child(*item, id)
+                }
+                _ => true,
+            })?;
+        }
+
+        Ok(Ir {
+            nodes,
+            children,
+            text,
+            ranges,
+        })
+    }
+
+    fn node(&mut self) -> anyhow::Result<Node> {
+        Ok(match self.u8()? {
+            0 => Node::Empty,
+            1 => Node::Literal(self.char()?),
+            2 => Node::String(self.range()?),
+            3 => Node::FoldedLiteral(self.char()?),
+            4 => Node::Digit {
+                unicode: self.bool()?,
+                positive: self.bool()?,
+            },
+            5 => Node::Alphanumeric {
+                unicode: self.bool()?,
+                positive: self.bool()?,
+            },
+            6 => Node::WordBoundary {
+                unicode: self.bool()?,
+                positive: self.bool()?,
+            },
+            7 => Node::CharacterGroup {
+                positive: self.bool()?,
+                ranges: self.range()?,
+            },
+            8 => Node::Script {
+                positive: self.bool()?,
+                script: self.script()?,
+            },
+            9 => Node::StartAnchor,
+            10 => Node::EndAnchor { crlf: self.bool()? },
+            11 => Node::ContinueAnchor,
+            12 => Node::OneOrMore(self.usize()?),
+            13 => Node::ZeroOrMore(self.usize()?),
+            14 => Node::ZeroOrOne(self.usize()?),
+            15 => Node::Wildcard { crlf: self.bool()? },
+            16 => Node::List(self.range()?),
+            17 => Node::Either(self.range()?),
+            18 => Node::LongestEither(self.range()?),
+            19 => Node::Reference(self.usize()?),
+            20 => Node::FoldedReference(self.usize()?),
+            21 => Node::CaptureGroup {
+                id: self.usize()?,
+                item: self.usize()?,
+            },
+            22 => Node::NoWordChar {
+                unicode: self.bool()?,
+                before: self.bool()?,
+            },
+            _ => return Err(corrupt()),
+        })
+    }
+
+    fn program(&mut self) -> anyhow::Result<Program> {
+        let group_count = self.usize()?;
+        let slot_count = self.usize()?;
+        let min_len = self.usize()?;
+        let references = (0..self.len()?)
+            .map(|_| self.usize())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut insts = Vec::new();
+        let mut loops = Vec::new();
+        for _ in 0..self.len()? {
+            insts.push(self.inst()?);
+            loops.push(
+                (0..self.len()?)
+                    .map(|_| self.usize())
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+            );
+        }
+        Ok(Program {
+            insts,
+            loops,
+            references,
+            group_count,
+            slot_count,
+            min_len,
+        })
+    }
+
+    fn inst(&mut self) -> anyhow::Result<Inst> {
+        Ok(match self.u8()? {
+            0 => Inst::Literal(self.char()?),
+            1 => Inst::FoldedLiteral(self.char()?),
+            2 => Inst::Digit {
+                unicode: self.bool()?,
+                positive: self.bool()?,
+            },
+            3 => Inst::Alphanumeric {
+                unicode: self.bool()?,
+                positive: self.bool()?,
+            },
+            4 => Inst::CharacterGroup {
+                positive: self.bool()?,
+                ranges: self.ranges()?,
+            },
+            5 => Inst::Script {
+                positive: self.bool()?,
+                script: self.script()?,
+            },
+            6 => Inst::Wildcard { crlf: self.bool()? },
+            7 => Inst::Assert(Assertion::Start),
+            8 => Inst::Assert(Assertion::End { crlf: self.bool()? }),
+            9 => Inst::Assert(Assertion::Continue),
+            10 => Inst::Assert(Assertion::WordBoundary {
+                unicode: self.bool()?,
+                positive: self.bool()?,
+            }),
+            11 => Inst::Split(self.usize()?, self.usize()?),
+            12 => Inst::Jump(self.usize()?),
+            13 => Inst::Save(self.usize()?),
+            14 => Inst::Progress {
+                slot: self.usize()?,
+                exit: self.usize()?,
+            },
+            15 => Inst::Reference(self.usize()?),
+            16 => Inst::FoldedReference(self.usize()?),
+            17 => Inst::Match,
+            18 => Inst::Assert(Assertion::NoWordChar {
+                unicode: self.bool()?,
+                before: self.bool()?,
+            }),
+            _ => return Err(corrupt()),
+        })
+    }
+}
+
+/// Checks that every jump lands on an instruction, every slot and group is
+/// one the engines allocate, and that only instructions ending a thread can
+/// be last, since the others go on to the next `pc`.
+fn check_program(program: &Program, capture_group_count: usize) -> anyhow::Result<()> {
+    let len = program.insts.len();
+    let groups = program.group_count;
+    ensure(
+        groups == capture_group_count
+            && groups * 2 <= program.slot_count
+            && !program.insts.is_empty()
+            && program.references.iter().all(|&id| id < groups)
+            && program
+                .loops
+                .iter()
+                .flatten()
+                .all(|&slot| slot < program.slot_count),
+    )?;
+    for (pc, inst) in program.insts.iter().enumerate() {
+        ensure(match inst {
+            Inst::Split(first, second) => *first < len && *second < len,
+            Inst::Jump(target) => *target < len,
+            Inst::Match => true,
+            Inst::Save(slot) => *slot < program.slot_count && pc + 1 < len,
+            Inst::Progress { slot, exit } => {
+                *slot < program.slot_count && *exit < len && pc + 1 < len
+            }
+            Inst::Reference(id) | Inst::FoldedReference(id) => *id < groups && pc + 1 < len,
+            _ => pc + 1 < len,
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use crate::dfa::Dfa;
+use crate::pikevm;
+use crate::pool::Pool;
+use crate::program::Program;
+use crate::{Regex, RegexBuilder, Syntax, TooComplexError};
+use std::time::Duration;
+
+/// Several patterns matched together, reporting which of them match. The
+/// ones without back references or longest alternatives are joined into a
+/// single program, so the input is scanned once for all of them: by the DFA
+/// when only asked whether any matches, and by the Pike VM for which do. The
+/// others are matched one at a time.
+pub struct RegexSet {
+    len: usize,
+    union: Option<Union>,
+    separate: Vec<(usize, Regex)>,
+}
+
+struct Union {
+    program: Program,
+    /// The pattern, as an index into `ids`, that the `Match` at each `pc`
+    /// ends.
+    patterns: Vec<usize>,
+    ids: Vec<usize>,
+    dfa: Option<Dfa>,
+    caches: Pool<pikevm::Cache>,
+}
+
+impl Union {
+    fn dfa_is_match(&self, input: &str) -> Option<bool> {
+        self.dfa
+            .as_ref()
+            .and_then(|dfa| dfa.is_match(&self.program, input, None))
+    }
+}
+
+/// Which patterns of a `RegexSet` matched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetMatches {
+    matched: Vec<bool>,
+}
+
+impl SetMatches {
+    pub fn matched_any(&self) -> bool {
+        self.matched.contains(&true)
+    }
+
+    pub fn matched(&self, i: usize) -> bool {
+        self.matched[i]
+    }
+
+    /// Returns the indexes of the patterns that matched, in order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.matched
+            .iter()
+            .enumerate()
+            .filter(|(_, &matched)| matched)
+            .map(|(i, _)| i)
+    }
+}
+
+impl RegexSet {
+    pub fn new<I, S>(patterns: I) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        RegexSetBuilder::new(patterns).build()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Panics if a search gives up; see `RegexBuilder::backtrack_limit`.
+    pub fn is_match(&self, input: &str) -> bool {
+        self.try_is_match(input)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_is_match(&self, input: &str) -> Result<bool, TooComplexError> {
+        if let Some(union) = &self.union {
+            let matched = union.dfa_is_match(input).unwrap_or_else(|| {
+                pikevm::is_match(&union.program, input, false, None, &mut union.caches.get())
+            });
+            if matched {
+                return Ok(true);
+            }
+        }
+
+        for (_, regex) in &self.separate {
+            if regex.try_is_match(input)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Panics if a search gives up; see `RegexBuilder::backtrack_limit`.
+    pub fn matches(&self, input: &str) -> SetMatches {
+        self.try_matches(input)
+            .unwrap_or_else(|err| panic!("{err}"))
+    }
+
+    pub fn try_matches(&self, input: &str) -> Result<SetMatches, TooComplexError> {
+        let mut matched = vec![false; self.len];
+
+        // Most inputs match none of the patterns, which the DFA settles
+        // without the VM tracking each of them.
+        if let Some(union) = self
+            .union
+            .as_ref()
+            .filter(|union| union.dfa_is_match(input) != Some(false))
+        {
+            let mut found = vec![false; union.ids.len()];
+            pikevm::which_match(
+                &union.program,
+                input,
+                &union.patterns,
+                &mut found,
+                &mut union.caches.get(),
+            );
+            for (&id, found) in union.ids.iter().zip(found) {
+                matched[id] = found;
+            }
+        }
+
+        for (id, regex) in &self.separate {
+            matched[*id] = regex.try_is_match(input)?;
+        }
+        Ok(SetMatches { matched })
+    }
+}
+
+/// Builds a `RegexSet` with the same options for every pattern as
+/// `RegexBuilder` has for one.
+pub struct RegexSetBuilder {
+    patterns: Vec<String>,
+    builder: RegexBuilder,
+}
+
+impl RegexSetBuilder {
+    pub fn new<I, S>(patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        RegexSetBuilder {
+            patterns: patterns
+                .into_iter()
+                .map(|pattern| pattern.as_ref().to_string())
+                .collect(),
+            builder: RegexBuilder::new(""),
+        }
+    }
+
+    pub fn syntax(&mut self, syntax: Syntax) -> &mut Self {
+        self.builder.syntax(syntax);
+        self
+    }
+
+    pub fn posix(&mut self, yes: bool) -> &mut Self {
+        self.builder.posix(yes);
+        self
+    }
+
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+        self.builder.case_insensitive(yes);
+        self
+    }
+
+    pub fn crlf(&mut self, yes: bool) -> &mut Self {
+        self.builder.crlf(yes);
+        self
+    }
+
+    pub fn longest_alternative(&mut self, yes: bool) -> &mut Self {
+        self.builder.longest_alternative(yes);
+        self
+    }
+
+    pub fn literal_misplaced_anchors(&mut self, yes: bool) -> &mut Self {
+        self.builder.literal_misplaced_anchors(yes);
+        self
+    }
+
+    pub fn fixed_string(&mut self, yes: bool) -> &mut Self {
+        self.builder.fixed_string(yes);
+        self
+    }
+
+    pub fn word(&mut self, yes: bool) -> &mut Self {
+        self.builder.word(yes);
+        self
+    }
+
+    pub fn line(&mut self, yes: bool) -> &mut Self {
+        self.builder.line(yes);
+        self
+    }
+
+    pub fn backtrack_limit(&mut self, limit: usize) -> &mut Self {
+        self.builder.backtrack_limit(limit);
+        self
+    }
+
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.builder.timeout(timeout);
+        self
+    }
+
+    pub fn dfa_size_limit(&mut self, limit: usize) -> &mut Self {
+        self.builder.dfa_size_limit(limit);
+        self
+    }
+
+    pub fn build(&self) -> anyhow::Result<RegexSet> {
+        let mut joined = Vec::new();
+        let mut separate = Vec::new();
+        for (id, pattern) in self.patterns.iter().enumerate() {
+            let mut builder = self.builder.clone();
+            builder.patterns = vec![pattern.clone()];
+            let regex = builder.build()?;
+            match &regex.program {
+                Some(program) if program.references.is_empty() => {
+                    joined.push((id, program.clone()));
+                }
+                _ => separate.push((id, regex)),
+            }
+        }
+
+        let union = (!joined.is_empty()).then(|| {
+            let programs = joined
+                .iter()
+                .map(|(_, program)| program)
+                .collect::<Vec<_>>();
+            let (program, patterns) = Program::union(&programs);
+            Union {
+                dfa: Dfa::new(&program, false, self.builder.dfa_size_limit),
+                program,
+                patterns,
+                ids: joined.iter().map(|&(id, _)| id).collect(),
+                caches: Pool::default(),
+            }
+        });
+
+        Ok(RegexSet {
+            len: self.patterns.len(),
+            union,
+            separate,
+        })
+    }
+}
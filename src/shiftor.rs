@@ -0,0 +1,97 @@
+use crate::prefilter::Prefilter;
+use crate::program::{Inst, Program};
+use std::ops::Range;
+
+/// Matches programs that are nothing but a run of at most 64 instructions
+/// consuming one char each, with the Shift-Or algorithm: bit `k` of the state
+/// is clear while the last `k + 1` chars match the first `k + 1`
+/// instructions, so each char costs a shift, an or and a table lookup.
+/// Every match has the same number of chars, so the first one to end is the
+/// leftmost.
+pub(crate) struct ShiftOr {
+    positions: Vec<Inst>,
+    ascii: [u64; 128],
+}
+
+impl ShiftOr {
+    pub(crate) fn new(program: &Program) -> Option<Self> {
+        let [Inst::Save(0), positions @ .., Inst::Save(1), Inst::Match] = &program.insts[..] else {
+            return None;
+        };
+        if positions.is_empty()
+            || positions.len() > 64
+            || positions.iter().any(|inst| {
+                matches!(
+                    inst,
+                    Inst::Split(..)
+                        | Inst::Jump(_)
+                        | Inst::Save(_)
+                        | Inst::Progress { .. }
+                        | Inst::Assert(_)
+                        | Inst::Reference(_)
+                        | Inst::FoldedReference(_)
+                        | Inst::Match
+                )
+            })
+        {
+            return None;
+        }
+
+        let mut shiftor = ShiftOr {
+            positions: positions.to_vec(),
+            ascii: [0; 128],
+        };
+        for c in 0..128u8 {
+            shiftor.ascii[c as usize] = shiftor.mask(char::from(c));
+        }
+        Some(shiftor)
+    }
+
+    /// The bits of the positions that don't accept `c`.
+    fn mask(&self, c: char) -> u64 {
+        let mut mask = !0;
+        for (k, inst) in self.positions.iter().enumerate() {
+            if inst.matches_char(c) {
+                mask &= !(1 << k);
+            }
+        }
+        mask
+    }
+
+    pub(crate) fn find_at(
+        &self,
+        input: &str,
+        start: usize,
+        prefilter: Option<&Prefilter>,
+    ) -> Option<Range<usize>> {
+        let last = 1 << (self.positions.len() - 1);
+
+        let mut state = !0u64;
+        let mut i = start;
+        loop {
+            if state == !0 {
+                if let Some(prefilter) = prefilter {
+                    i = prefilter.find(input, i)?;
+                }
+            }
+
+            let c = input[i..].chars().next()?;
+            let mask = if c.is_ascii() {
+                self.ascii[c as usize]
+            } else {
+                self.mask(c)
+            };
+            state = state << 1 | mask;
+            i += c.len_utf8();
+
+            if state & last == 0 {
+                let start = input[..i]
+                    .char_indices()
+                    .nth_back(self.positions.len() - 1)
+                    .unwrap()
+                    .0;
+                return Some(start..i);
+            }
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use crate::dfa::Scan;
+use crate::program::Program;
+use crate::Regex;
+use std::fmt;
+
+/// Answers `is_match_bytes` for a haystack that arrives in pieces, as from a
+/// pipe or a socket, without holding on to the pieces: the DFA state is all
+/// that's carried from one piece to the next, plus the start of a char split
+/// between them.
+pub struct Stream<'r> {
+    regex: &'r Regex,
+    scan: Scan<'r>,
+    partial: Vec<u8>,
+}
+
+/// The pattern can't be matched on a stream, as it has back references or
+/// an assertion the DFA can't decide from one char of lookahead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamError;
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pattern can't be matched on a stream")
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl Regex {
+    /// Starts matching input fed in pieces. Only patterns the DFA can run
+    /// are streamed, since anything else would have to keep the whole input
+    /// to match it at the end; those return `StreamError`, and the input
+    /// should be joined and searched with `is_match_bytes` instead.
+    pub fn stream(&self) -> Result<Stream<'_>, StreamError> {
+        let dfa = self.dfa.as_ref().ok_or(StreamError)?;
+        Ok(Stream {
+            regex: self,
+            scan: dfa.scan(),
+            partial: Vec::new(),
+        })
+    }
+}
+
+impl Stream<'_> {
+    /// Returns whether the input fed so far matches whatever comes after it.
+    pub fn feed(&mut self, mut chunk: &[u8]) -> bool {
+        let program = self.regex.program.as_ref().unwrap();
+        while !self.partial.is_empty() && !chunk.is_empty() {
+            self.partial.push(chunk[0]);
+            chunk = &chunk[1..];
+            let joined = std::mem::take(&mut self.partial);
+            feed_decoded(&mut self.scan, program, &joined, Some(&mut self.partial));
+        }
+        feed_decoded(&mut self.scan, program, chunk, Some(&mut self.partial)) == Some(true)
+    }
+
+    /// Returns whether the whole input matched, the same as
+    /// `is_match_bytes` on the pieces joined together.
+    pub fn finish(mut self) -> bool {
+        let program = self.regex.program.as_ref().unwrap();
+        feed_decoded(&mut self.scan, program, &self.partial, None);
+        self.scan.finish(program)
+    }
+}
+
+/// Feeds `bytes` decoded the way the byte search methods decode their
+/// haystack, each byte that isn't part of a valid char standing for the char
+/// with its value. A char cut off at the end is left in `partial`, or taken
+/// as invalid bytes when there's no more input.
+fn feed_decoded(
+    scan: &mut Scan,
+    program: &Program,
+    mut bytes: &[u8],
+    mut partial: Option<&mut Vec<u8>>,
+) -> Option<bool> {
+    loop {
+        let (valid, invalid) = match std::str::from_utf8(bytes) {
+            Ok(valid) => return scan.feed(program, valid),
+            Err(err) => {
+                let valid = std::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap();
+                match (err.error_len(), partial.as_deref_mut()) {
+                    (None, Some(partial)) => {
+                        partial.extend_from_slice(&bytes[valid.len()..]);
+                        return scan.feed(program, valid);
+                    }
+                    (Some(len), _) => (valid, len),
+                    (None, None) => (valid, bytes.len() - valid.len()),
+                }
+            }
+        };
+
+        if let Some(decided) = scan.feed(program, valid) {
+            return Some(decided);
+        }
+        for &b in &bytes[valid.len()..valid.len() + invalid] {
+            if let Some(decided) = scan.feed(program, char::from(b).encode_utf8(&mut [0; 2])) {
+                return Some(decided);
+            }
+        }
+        bytes = &bytes[valid.len() + invalid..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::RegexBuilder;
+
+    #[test]
+    fn patterns_off_the_dfa_are_refused() {
+        for (pattern, crlf) in [(r"(a)\1", false), ("a$", true)] {
+            let regex = RegexBuilder::new(pattern).crlf(crlf).build().unwrap();
+            assert!(regex.stream().is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn matches_across_pieces() {
+        let regex = RegexBuilder::new("aβc").build().unwrap();
+        let mut stream = regex.stream().unwrap();
+        let bytes = "xaβcd".as_bytes();
+        assert!(!stream.feed(&bytes[..3]));
+        assert!(stream.feed(&bytes[3..]));
+        assert!(stream.finish());
+
+        let mut stream = regex.stream().unwrap();
+        assert!(!stream.feed(&bytes[..3]));
+        assert!(!stream.feed("bc".as_bytes()));
+        assert!(!stream.finish());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::glob::Glob;
+use crate::ignore::Ignores;
+use anyhow::Context;
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::vec;
+
+/// The files to search under a path given with `-r` or `-R`: the path
+/// itself if it isn't a directory, or else every regular file below it,
+/// each directory's entries in name order. Symbolic links found on the way
+/// are skipped unless following links, for `-R`; the path itself is
+/// followed like any other argument, and searched whatever its name, as it
+/// is when ignore files would skip it or it's hidden. Errors reading a
+/// directory are yielded in place of its entries and the walk goes on.
+pub(crate) struct Walk {
+    root: Option<PathBuf>,
+    /// Names what's in the root without the root in front, for the working
+    /// directory when no paths are given.
+    relative: bool,
+    options: WalkOptions,
+    /// The entries of each directory entered but not finished yet.
+    stack: Vec<vec::IntoIter<PathBuf>>,
+    /// Where the directories in `stack` really are, when following links,
+    /// so that a link back to one of them isn't walked into forever.
+    ancestors: Vec<PathBuf>,
+    /// What the ignore files on the way skip, unless `--no-ignore`.
+    ignores: Option<Ignores>,
+}
+
+/// A link back to a directory the walk is already in, when following
+/// links. It's only a warning: the walk just doesn't go round again.
+#[derive(Debug)]
+pub(crate) struct DirectoryLoop(PathBuf);
+
+impl fmt::Display for DirectoryLoop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: warning: recursive directory loop", self.0.display())
+    }
+}
+
+impl std::error::Error for DirectoryLoop {}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct WalkOptions {
+    pub(crate) follow_links: bool,
+    /// How many directories deep to look, where 1 is only the entries of
+    /// the path itself and 0 skips directories altogether.
+    pub(crate) max_depth: Option<usize>,
+    /// Only files with a name one of these matches are searched, if there
+    /// are any, for `--include`.
+    pub(crate) include: Vec<Glob>,
+    /// Files with a name one of these matches aren't, for `--exclude`.
+    pub(crate) exclude: Vec<Glob>,
+    /// Directories with a name one of these matches aren't entered, for
+    /// `--exclude-dir`.
+    pub(crate) exclude_dir: Vec<Glob>,
+    /// Walks into what `.gitignore`, `.ignore` and the global git excludes
+    /// say to skip, for `--no-ignore`.
+    pub(crate) no_ignore: bool,
+    /// Walks into files and directories with a name starting with a dot,
+    /// for `--hidden`.
+    pub(crate) hidden: bool,
+}
+
+impl WalkOptions {
+    fn selects(&self, file: &Path) -> bool {
+        let name = name(file);
+        (self.include.is_empty() || self.include.iter().any(|glob| glob.matches(&name)))
+            && !self.exclude.iter().any(|glob| glob.matches(&name))
+    }
+
+    fn enters(&self, dir: &Path) -> bool {
+        let name = name(dir);
+        !self.exclude_dir.iter().any(|glob| glob.matches(&name))
+    }
+}
+
+fn name(path: &Path) -> Cow<'_, str> {
+    path.file_name()
+        .map_or(Cow::Borrowed(""), OsStr::to_string_lossy)
+}
+
+impl Walk {
+    pub(crate) fn new(root: PathBuf, options: WalkOptions) -> Self {
+        Walk {
+            root: Some(root),
+            relative: false,
+            ignores: (!options.no_ignore).then(Ignores::new),
+            options,
+            stack: Vec::new(),
+            ancestors: Vec::new(),
+        }
+    }
+
+    /// Walks the working directory.
+    pub(crate) fn current_dir(options: WalkOptions) -> Self {
+        Walk {
+            relative: true,
+            ..Walk::new(PathBuf::from("."), options)
+        }
+    }
+
+    /// Starts on the entries of `dir`, unless that's deeper than allowed.
+    fn enter(&mut self, dir: &Path) -> anyhow::Result<()> {
+        if self
+            .options
+            .max_depth
+            .is_some_and(|max_depth| self.stack.len() >= max_depth)
+        {
+            return Ok(());
+        }
+
+        let real = if self.options.follow_links {
+            let real =
+                fs::canonicalize(dir).with_context(|| format!("reading {}", dir.display()))?;
+            if self.ancestors.contains(&real) {
+                return Err(DirectoryLoop(dir.to_path_buf()).into());
+            }
+            Some(real)
+        } else {
+            None
+        };
+
+        let mut entries = fs::read_dir(dir)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| {
+                        entry.map(|entry| {
+                            if self.relative && self.stack.is_empty() {
+                                PathBuf::from(entry.file_name())
+                            } else {
+                                entry.path()
+                            }
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .with_context(|| format!("reading {}", dir.display()))?;
+        entries.sort();
+        if let Some(ignores) = &mut self.ignores {
+            ignores.enter(dir, self.stack.len());
+        }
+        self.stack.push(entries.into_iter());
+        self.ancestors.extend(real);
+        Ok(())
+    }
+}
+
+impl Iterator for Walk {
+    type Item = anyhow::Result<PathBuf>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(root) = self.root.take() {
+            if !root.is_dir() {
+                return Some(Ok(root));
+            }
+            // As in GNU grep a directory given to `-r` is skipped too when
+            // `--exclude-dir` matches it.
+            if !self.options.enters(&root) {
+                return None;
+            }
+            if let Err(err) = self.enter(&root) {
+                return Some(Err(err));
+            }
+        }
+
+        loop {
+            let depth = self.stack.len().checked_sub(1)?;
+            let entries = &mut self.stack[depth];
+            let Some(path) = entries.next() else {
+                self.stack.pop();
+                if let Some(ignores) = &mut self.ignores {
+                    ignores.leave(depth);
+                }
+                if self.options.follow_links {
+                    self.ancestors.pop();
+                }
+                continue;
+            };
+
+            let metadata = if self.options.follow_links {
+                fs::metadata(&path)
+            } else {
+                fs::symlink_metadata(&path)
+            };
+            let file_type = match metadata {
+                Ok(metadata) => metadata.file_type(),
+                Err(err) => {
+                    return Some(Err(
+                        anyhow::Error::new(err).context(format!("reading {}", path.display()))
+                    ))
+                }
+            };
+            if !self.options.hidden && name(&path).starts_with('.') {
+                continue;
+            }
+            if self
+                .ignores
+                .as_ref()
+                .is_some_and(|ignores| ignores.ignores(&path, depth, file_type.is_dir()))
+            {
+                continue;
+            }
+            if file_type.is_dir() {
+                if !self.options.enters(&path) {
+                    continue;
+                }
+                if let Err(err) = self.enter(&path) {
+                    return Some(Err(err));
+                }
+            } else if file_type.is_file() && self.options.selects(&path) {
+                return Some(Ok(path));
+            }
+        }
+    }
+}
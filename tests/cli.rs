@@ -0,0 +1,1327 @@
+//! The command line, run as the built binary on files in a directory of
+//! each test's own.
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{self, Command, Stdio};
+
+/// A directory for a test's files, removed once the test is done.
+struct Dir(PathBuf);
+
+impl Dir {
+    fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("grep-cli-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Dir(path)
+    }
+
+    /// Writes `contents` to `file`, making the directories it's in.
+    fn write(&self, file: &str, contents: impl AsRef<[u8]>) -> &Self {
+        let path = self.0.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+        self
+    }
+
+    /// Runs grep with `args` in the directory.
+    fn grep(&self, args: &[&str]) -> Run {
+        self.grep_stdin(args, "")
+    }
+
+    /// Runs grep with `args` in the directory, with `stdin` to read.
+    fn grep_stdin(&self, args: &[&str], stdin: &str) -> Run {
+        self.grep_env(args, stdin, &[])
+    }
+
+    /// Runs grep with `args` in the directory, with `stdin` to read and the
+    /// environment variables `vars` set. Those grep reads are otherwise
+    /// unset, so the environment the tests run in, or the git excludes of
+    /// whoever runs them, don't matter.
+    fn grep_env(&self, args: &[&str], stdin: &str, vars: &[(&str, &str)]) -> Run {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_codecrafters-grep"))
+            .args(args)
+            .current_dir(&self.0)
+            .env_remove("GREP_COLORS")
+            .env_remove("HOME")
+            .env_remove("XDG_CONFIG_HOME")
+            .envs(vars.iter().copied())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Grep may be done, say with an error, before it reads any of it.
+        let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+        let output = child.wait_with_output().unwrap();
+        Run {
+            status: output.status.code().unwrap(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+}
+
+impl Drop for Dir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Run {
+    status: i32,
+    stdout: String,
+    stderr: String,
+}
+
+#[test]
+fn end_anchor_holds_before_the_newline() {
+    let dir = Dir::new("end-anchor");
+    assert_eq!(dir.grep_stdin(&["-E", "cat$"], "a cat\n").status, 0);
+    assert_eq!(dir.grep_stdin(&["-E", "cat$"], "a cat").status, 0);
+    assert_eq!(dir.grep_stdin(&["-E", "^a cat$"], "a cat\n").status, 0);
+    assert_eq!(dir.grep_stdin(&["-E", "cat$"], "cats\n").status, 1);
+}
+
+#[test]
+fn crlf_takes_windows_line_ends() {
+    let dir = Dir::new("crlf");
+    assert_eq!(
+        dir.grep_stdin(&["-E", "--crlf", "cat$"], "a cat\r\n")
+            .status,
+        0
+    );
+    assert_eq!(dir.grep_stdin(&["-E", "cat$"], "a cat\r\n").status, 1);
+}
+
+#[test]
+fn empty_pattern_matches_any_line() {
+    let dir = Dir::new("empty-pattern");
+    assert_eq!(dir.grep_stdin(&["-E", ""], "abc\n").status, 0);
+    assert_eq!(dir.grep_stdin(&["-E", ""], "\n").status, 0);
+    // But there has to be a line.
+    assert_eq!(dir.grep_stdin(&["-E", ""], "").status, 1);
+}
+
+#[test]
+fn backtrack_limit_fails_the_search() {
+    let dir = Dir::new("backtrack-limit");
+    let line = format!("{}bc", "a".repeat(30));
+    let run = dir.grep_stdin(&["-E", r"(a|aa)*(a)\2c", "--backtrack-limit", "100"], &line);
+    assert_eq!(run.status, 2);
+    assert!(run.stderr.contains("gave up after 100 backtracking steps"));
+    let run = dir.grep_stdin(&["-E", r"(a|aa)*(a)\2c"], &line);
+    assert_eq!(
+        run,
+        Run {
+            status: 1,
+            stdout: String::new(),
+            stderr: String::new()
+        }
+    );
+}
+
+#[test]
+fn compiled_patterns_load_back() {
+    let dir = Dir::new("compiled");
+    let saved = dir.grep_stdin(&["-E", "(a+)b\\1", "--save-compiled", "re.bin"], "aaba\n");
+    assert_eq!(saved.status, 0);
+    assert!(dir.0.join("re.bin").exists());
+    assert_eq!(
+        dir.grep_stdin(&["-E", "--load-compiled", "re.bin"], "xaaba\n")
+            .status,
+        0
+    );
+    assert_eq!(
+        dir.grep_stdin(&["-E", "--load-compiled", "re.bin"], "ab\n")
+            .status,
+        1
+    );
+
+    fs::write(dir.0.join("bad.bin"), "not a regex").unwrap();
+    let run = dir.grep_stdin(&["-E", "--load-compiled", "bad.bin"], "a\n");
+    assert_eq!(run.status, 2);
+    assert!(run.stderr.contains("Not a compiled regex."), "{run:?}");
+}
+
+#[test]
+fn dfa_size_limit_leaves_the_result_alone() {
+    let dir = Dir::new("dfa-size-limit");
+    for limit in ["0", "100000"] {
+        let args = ["-E", "--dfa-size-limit", limit, "a[ab][ab][ab]c$"];
+        assert_eq!(dir.grep_stdin(&args, "bbabbbc\n").status, 0);
+        assert_eq!(dir.grep_stdin(&args, "bbbabbc\n").status, 1);
+    }
+    let run = dir.grep_stdin(&["-E", "--dfa-size-limit", "lots", "a"], "a\n");
+    assert_eq!(run.status, 2);
+    assert!(run.stderr.contains("--dfa-size-limit"), "{run:?}");
+}
+
+#[test]
+fn captures_print_a_line_per_group() {
+    let dir = Dir::new("captures-per-group");
+    let run = dir.grep_stdin(&["-E", "--captures", r"(a)(x)?(b)-(\d+)"], "ab-12\n");
+    assert_eq!(run.status, 0);
+    assert_eq!(run.stdout, "1: a\n2:\n3: b\n4: 12\n");
+    let run = dir.grep_stdin(&["-E", "--captures", "(a)"], "b\n");
+    assert_eq!((run.status, run.stdout.as_str()), (1, ""));
+}
+
+#[test]
+fn bench_searches_every_file_under_the_dir() {
+    let dir = Dir::new("bench");
+    dir.write("corpus/a.txt", "foo\nbar\nfoo bar\n")
+        .write("corpus/sub/b.txt", "no\nfood")
+        .write("corpus/empty.txt", "");
+    let run = dir.grep_stdin(&["-E", "foo", "--bench", "corpus", "--bench-runs", "3"], "");
+    assert_eq!(run.status, 0, "{run:?}");
+    assert!(run.stdout.contains("(3 files, 0.0 MB)"), "{run:?}");
+    assert!(run.stdout.contains("over 3 runs"), "{run:?}");
+    assert!(run.stdout.contains(" 3 matching lines"), "{run:?}");
+}
+
+#[cfg(unix)]
+#[test]
+fn bench_leaves_out_linked_dirs() {
+    use std::os::unix::fs::symlink;
+
+    let dir = Dir::new("bench-links");
+    dir.write("corpus/a.txt", "foo\n")
+        .write("corpus/sub/b.txt", "foo\n");
+    symlink("..", dir.0.join("corpus/sub/up")).unwrap();
+    let run = dir.grep_stdin(&["-E", "foo", "--bench", "corpus", "--bench-runs", "1"], "");
+    assert_eq!(run.status, 0, "{run:?}");
+    assert!(run.stdout.contains("(2 files, 0.0 MB)"), "{run:?}");
+    assert!(run.stdout.contains(" 2 matching lines"), "{run:?}");
+}
+
+#[test]
+fn timeout_fails_the_search_naming_the_line() {
+    let dir = Dir::new("timeout");
+    let line = format!("{}bc", "a".repeat(200));
+    let run = dir.grep_stdin(&["-E", r"(a|aa)*(a)\2c", "--timeout", "0"], &line);
+    assert_eq!(run.status, 2);
+    assert!(
+        run.stderr
+            .starts_with(r"grep: searching line 1 of (standard input) for '(a|aa)*(a)\2c': "),
+        "{run:?}"
+    );
+    assert!(run.stderr.contains("gave up after 0ns"), "{run:?}");
+    let run = dir.grep_stdin(&["-E", "a+c", "--timeout", "1000"], &line);
+    assert_eq!(run.status, 1);
+    assert!(run.stderr.is_empty(), "{run:?}");
+}
+
+#[test]
+fn files_after_the_pattern_are_searched() {
+    let dir = Dir::new("files");
+    dir.write("a.txt", "apple\nbanana\ncherry\n")
+        .write("b.txt", "grape\napricot");
+    let run = dir.grep(&["-E", "^a", "a.txt", "b.txt"]);
+    assert_eq!(run.status, 0);
+    assert_eq!(run.stdout, "a.txt:apple\nb.txt:apricot\n");
+    assert_eq!(dir.grep(&["-E", "^z", "a.txt", "b.txt"]).status, 1);
+    let run = dir.grep(&["-E", "--captures", "(an)+", "a.txt"]);
+    assert_eq!(run.stdout, "1: an\n");
+    let run = dir.grep(&["-E", "--captures", "(p)(r)?", "a.txt", "b.txt"]);
+    let groups = "a.txt:1: p\na.txt:2:\nb.txt:1: p\nb.txt:2:\nb.txt:1: p\nb.txt:2: r\n";
+    assert_eq!(run.stdout, groups);
+
+    // A file that can't be opened doesn't stop the others being searched.
+    let run = dir.grep(&["-E", "^b", "missing.txt", "a.txt"]);
+    assert_eq!((run.status, run.stdout.as_str()), (2, "a.txt:banana\n"));
+    assert!(
+        run.stderr.starts_with("grep: opening missing.txt"),
+        "{run:?}"
+    );
+}
+
+#[test]
+fn many_files_come_out_in_order() {
+    let dir = Dir::new("many-files");
+    let mut paths = Vec::new();
+    let mut expected = String::new();
+    for i in 0..50 {
+        let path = format!("{i:02}.txt");
+        dir.write(&path, format!("foo {i}\nbar\n").repeat(i % 5 + 1));
+        expected += &format!("{path}:foo {i}\n").repeat(i % 5 + 1);
+        paths.push(path);
+    }
+    let paths = paths.iter().map(String::as_str);
+    let run = dir.grep(&["-E", "foo"].into_iter().chain(paths).collect::<Vec<_>>());
+    assert_eq!(run.status, 0);
+    assert!(run.stdout == expected);
+}
+
+#[test]
+fn big_files_are_searched_whole() {
+    let dir = Dir::new("big");
+    let line = |i: usize| format!("line {i} {}\n", if i % 1000 == 0 { "x" } else { "y" });
+    let big = (0..1_000_000).map(line).collect::<String>();
+    assert!(big.len() > 8 << 20);
+    dir.write("big.txt", &big);
+    let run = dir.grep(&["-E", "x$", "big.txt"]);
+    assert_eq!(run.status, 0);
+    assert!(run.stdout == (0..1000).map(|i| line(i * 1000)).collect::<String>());
+    assert_eq!(dir.grep(&["-E", "-c", "x$", "big.txt"]).stdout, "1000\n");
+    let numbered = (0..1000).map(|i| format!("{}:{}", i * 1000 + 1, line(i * 1000)));
+    let run = dir.grep(&["-E", "-n", "x$", "big.txt"]);
+    assert!(run.stdout == numbered.collect::<String>());
+    // So are offsets, here those of the last and only match.
+    let run = dir.grep(&["-E", "-b", "-o", "999000 x", "big.txt"]);
+    let offset = big.find("line 999000 x").unwrap() + "line ".len();
+    assert_eq!(run.stdout, format!("{offset}:999000 x\n"));
+
+    // Lines are still numbered from the start of the file.
+    let mut lines = big.lines().collect::<Vec<_>>();
+    let hard = format!("{}bc", "a".repeat(30));
+    lines[700_000] = &hard;
+    dir.write("hard.txt", lines.join("\n"));
+    let args = [
+        "-E",
+        "--backtrack-limit",
+        "100",
+        r"(a|aa)*(a)\2c",
+        "hard.txt",
+    ];
+    let run = dir.grep(&args);
+    assert_eq!(run.status, 2);
+    let error = "grep: searching line 700001 of hard.txt";
+    assert!(run.stderr.starts_with(error), "{run:?}");
+}
+
+#[test]
+fn mmap_and_no_mmap_give_the_same_output() {
+    let dir = Dir::new("mmap");
+    let line = |i: usize| format!("line {i} {}\n", if i % 100 == 0 { "x" } else { "y" });
+    dir.write("big.txt", (0..200_000).map(line).collect::<String>())
+        .write("small.txt", "a\nb x\nc");
+    for file in ["big.txt", "small.txt"] {
+        let run = |how| dir.grep(&["-E", "-n", "-b", how, "x", file]);
+        let read = run("--no-mmap");
+        assert_eq!(read.status, 0, "{file}");
+        assert_eq!(run("--mmap"), read, "{file}");
+    }
+    // Standard input can't be mapped, so is read anyway.
+    let run = dir.grep_stdin(&["-E", "--mmap", "x"], "x\n");
+    assert_eq!(run.stdout, "x\n");
+}
+
+#[test]
+fn options_are_told_apart_from_paths() {
+    let dir = Dir::new("options");
+    dir.write("-a.txt", "-x\n");
+    for args in [
+        &["-E", "--bogus", "x"][..],
+        &["-E", "-k", "x"],
+        &["-E", "-ky", "x"],
+    ] {
+        let run = dir.grep(args);
+        assert_eq!(run.status, 2, "{args:?}");
+        assert!(
+            run.stderr.starts_with("grep: Unknown option '-"),
+            "{args:?}"
+        );
+    }
+    // After `--` nothing is an option.
+    let run = dir.grep(&["-E", "--", "-x", "-a.txt"]);
+    assert_eq!((run.status, run.stdout.as_str()), (0, "-x\n"));
+}
+
+#[test]
+fn dash_reads_stdin_under_a_label() {
+    let dir = Dir::new("label");
+    dir.write("a.txt", "foo file\n");
+    let run = dir.grep_stdin(&["-E", "foo", "a.txt", "-"], "foo in\nbar\n");
+    assert_eq!(run.stdout, "a.txt:foo file\n(standard input):foo in\n");
+    let run = dir.grep_stdin(&["-E", "--label=piped", "foo", "-", "a.txt"], "foo in\n");
+    assert_eq!(run.stdout, "piped:foo in\na.txt:foo file\n");
+    let run = dir.grep_stdin(&["-E", "foo", "-"], "bar\nfoo in\n");
+    assert_eq!((run.status, run.stdout.as_str()), (0, "foo in\n"));
+}
+
+#[test]
+fn stdin_is_searched_line_by_line() {
+    let dir = Dir::new("stdin");
+    let run = dir.grep_stdin(&["-E", "o+"], "one\ntwo\nthree\nfour");
+    assert_eq!((run.status, run.stdout.as_str()), (0, "one\ntwo\nfour\n"));
+    let run = dir.grep_stdin(&["-E", "^t"], "one\nfour\n");
+    assert_eq!((run.status, run.stdout.as_str()), (1, ""));
+}
+
+#[test]
+fn recursive_search_walks_directories_in_name_order() {
+    let dir = Dir::new("recursive");
+    dir.write("d/b.txt", "foo b\n")
+        .write("d/a/z.txt", "foo z\nbar\n")
+        .write("d/a/y.txt", "bar\n")
+        .write("d/c.txt", "foo c\n");
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("b.txt", dir.0.join("d/link.txt")).unwrap();
+    let run = dir.grep(&["-E", "-r", "foo", "d"]);
+    assert_eq!(run.status, 0);
+    assert_eq!(
+        run.stdout,
+        "d/a/z.txt:foo z\nd/b.txt:foo b\nd/c.txt:foo c\n"
+    );
+
+    // Given by itself a file is searched, and a link followed.
+    let run = dir.grep(&["-E", "-r", "foo", "d/b.txt"]);
+    assert_eq!(run.stdout, "d/b.txt:foo b\n");
+    #[cfg(unix)]
+    assert_eq!(
+        dir.grep(&["-E", "-r", "foo", "d/link.txt"]).stdout,
+        "d/link.txt:foo b\n"
+    );
+
+    // Without paths it's the working directory, named from inside it.
+    let run = Command::new(env!("CARGO_BIN_EXE_codecrafters-grep"))
+        .args(["-E", "-r", "foo z"])
+        .current_dir(dir.0.join("d"))
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&run.stdout), "a/z.txt:foo z\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn dereference_recursive_follows_links_but_not_loops() {
+    use std::os::unix::fs::symlink;
+
+    let dir = Dir::new("dereference-recursive");
+    dir.write("d/a.txt", "foo a\n")
+        .write("other/b.txt", "foo b\n");
+    symlink("../other", dir.0.join("d/other")).unwrap();
+    fs::create_dir(dir.0.join("d/sub")).unwrap();
+    symlink("..", dir.0.join("d/sub/up")).unwrap();
+    let run = dir.grep(&["-E", "-R", "foo", "d"]);
+    assert_eq!(run.status, 0);
+    assert_eq!(run.stdout, "d/a.txt:foo a\nd/other/b.txt:foo b\n");
+    assert_eq!(
+        run.stderr,
+        "grep: d/sub/up: warning: recursive directory loop\n"
+    );
+    // The loop is only a warning, so without a match it's still no match.
+    let run = dir.grep(&["-E", "-R", "bar", "d"]);
+    assert_eq!(run.status, 1);
+    // And `-s` keeps it quiet like any other message about a file.
+    let run = dir.grep(&["-E", "-R", "-s", "foo", "d"]);
+    assert_eq!((run.status, run.stderr.as_str()), (0, ""));
+    // Without following them links are left out.
+    let run = dir.grep(&["-E", "-r", "foo", "d"]);
+    assert_eq!(
+        (run.stdout.as_str(), run.stderr.as_str()),
+        ("d/a.txt:foo a\n", "")
+    );
+}
+
+#[test]
+fn max_depth_stops_the_walk() {
+    let dir = Dir::new("max-depth");
+    dir.write("d/a.txt", "foo\n")
+        .write("d/e/b.txt", "foo\n")
+        .write("d/e/f/c.txt", "foo\n");
+    let files = |depth| {
+        let run = dir.grep(&["-E", "-r", "--max-depth", depth, "foo", "d"]);
+        run.stdout.lines().map(String::from).collect::<Vec<_>>()
+    };
+    assert_eq!(files("1"), ["d/a.txt:foo"]);
+    assert_eq!(files("2"), ["d/a.txt:foo", "d/e/b.txt:foo"]);
+    assert_eq!(files("9").len(), 3);
+    assert!(files("0").is_empty());
+    let run = dir.grep(&["-E", "-r", "--max-depth", "0", "foo", "d/a.txt"]);
+    assert_eq!(run.stdout, "d/a.txt:foo\n");
+}
+
+#[test]
+fn ignore_case_matches_either_case() {
+    let dir = Dir::new("ignore-case");
+    let run = dir.grep_stdin(&["-E", "-i", "^h[a-e]llo"], "HELLO\nhallo\nHxllo\n");
+    assert_eq!(run.stdout, "HELLO\nhallo\n");
+    assert_eq!(dir.grep_stdin(&["-E", "h[a-e]llo"], "HELLO\n").status, 1);
+}
+
+#[test]
+fn invert_selects_the_lines_that_dont_match() {
+    let dir = Dir::new("invert");
+    let run = dir.grep_stdin(&["-E", "-v", "a"], "abc\nxyz\nbar\nfoo\n");
+    assert_eq!((run.status, run.stdout.as_str()), (0, "xyz\nfoo\n"));
+    assert_eq!(dir.grep_stdin(&["-E", "-v", "a"], "abc\nbar\n").status, 1);
+    // Lines that don't match have no groups, so they're printed whole.
+    let run = dir.grep_stdin(&["-E", "-v", "--captures", "(a)"], "abc\nxyz\n");
+    assert_eq!(run.stdout, "xyz\n");
+}
+
+#[test]
+fn count_prints_how_many_lines_were_selected() {
+    let dir = Dir::new("count");
+    dir.write("a.txt", "foo\nbar\nfoo bar\n")
+        .write("b.txt", "baz\n");
+    assert_eq!(dir.grep(&["-E", "-c", "foo", "a.txt"]).stdout, "2\n");
+    let run = dir.grep(&["-E", "-c", "foo", "a.txt", "b.txt"]);
+    assert_eq!((run.status, run.stdout.as_str()), (0, "a.txt:2\nb.txt:0\n"));
+    let run = dir.grep(&["-E", "-c", "-v", "foo", "a.txt", "b.txt"]);
+    assert_eq!(run.stdout, "a.txt:1\nb.txt:1\n");
+    let run = dir.grep(&["-E", "-c", "foo", "b.txt"]);
+    assert_eq!((run.status, run.stdout.as_str()), (1, "0\n"));
+}
+
+#[test]
+fn line_numbers_follow_the_file_name() {
+    let dir = Dir::new("line-number");
+    dir.write("a.txt", "foo\nbar\nfoo bar\n")
+        .write("b.txt", "bar foo\n");
+    let run = dir.grep(&["-E", "-n", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "1:foo\n3:foo bar\n");
+    let run = dir.grep(&["-E", "-n", "foo", "a.txt", "b.txt"]);
+    assert_eq!(
+        run.stdout,
+        "a.txt:1:foo\na.txt:3:foo bar\nb.txt:1:bar foo\n"
+    );
+    let run = dir.grep(&["-E", "-n", "--captures", "(o+) (b)", "a.txt"]);
+    assert_eq!(run.stdout, "3:1: oo\n3:2: b\n");
+    // Bundled with another short option.
+    assert_eq!(dir.grep(&["-E", "-vn", "foo", "a.txt"]).stdout, "2:bar\n");
+}
+
+#[test]
+fn files_with_matches_lists_each_once() {
+    let dir = Dir::new("files-with-matches");
+    dir.write("a.txt", "foo\nfoo\n")
+        .write("b.txt", "bar\n")
+        .write("c.txt", "x foo\n");
+    let run = dir.grep(&["-E", "-l", "foo", "a.txt", "b.txt", "c.txt"]);
+    assert_eq!((run.status, run.stdout.as_str()), (0, "a.txt\nc.txt\n"));
+    let run = dir.grep(&["-E", "-l", "-v", "foo", "a.txt", "b.txt"]);
+    assert_eq!(run.stdout, "b.txt\n");
+    assert_eq!(dir.grep(&["-E", "-l", "baz", "a.txt", "b.txt"]).status, 1);
+}
+
+#[test]
+fn files_without_match_lists_the_rest() {
+    let dir = Dir::new("files-without-match");
+    dir.write("a.txt", "foo\n")
+        .write("b.txt", "bar\n")
+        .write("c.txt", "baz\n");
+    let run = dir.grep(&["-E", "-L", "foo", "a.txt", "b.txt", "c.txt"]);
+    // The status still says whether a line was selected.
+    assert_eq!((run.status, run.stdout.as_str()), (0, "b.txt\nc.txt\n"));
+    let run = dir.grep(&["-E", "-L", "x", "a.txt", "b.txt"]);
+    assert_eq!((run.status, run.stdout.as_str()), (1, "a.txt\nb.txt\n"));
+}
+
+#[test]
+fn listing_files_goes_over_counting() {
+    let dir = Dir::new("list-over-count");
+    dir.write("a.txt", "foo\nfoo\n").write("b.txt", "bar\n");
+    for flags in ["-lc", "-cl"] {
+        let run = dir.grep(&[flags, "foo", "a.txt", "b.txt"]);
+        assert_eq!((run.status, run.stdout.as_str()), (0, "a.txt\n"), "{flags}");
+    }
+    for flags in ["-Lc", "-cL"] {
+        let run = dir.grep(&[flags, "foo", "a.txt", "b.txt"]);
+        assert_eq!(run.stdout, "b.txt\n", "{flags}");
+    }
+}
+
+#[test]
+fn only_matching_prints_each_match() {
+    let dir = Dir::new("only-matching");
+    let run = dir.grep_stdin(&["-E", "-o", "-n", r"\d+"], "a1b22\nnone\n333\n");
+    assert_eq!((run.status, run.stdout.as_str()), (0, "1:1\n1:22\n3:333\n"));
+    // Empty matches select the line but print nothing.
+    let run = dir.grep_stdin(&["-E", "-o", "x*"], "abc\n");
+    assert_eq!((run.status, run.stdout.as_str()), (0, ""));
+    let run = dir.grep_stdin(&["-E", "-o", "-v", "a"], "abc\nxyz\n");
+    assert_eq!((run.status, run.stdout.as_str()), (0, ""));
+}
+
+#[test]
+fn quiet_stops_at_the_first_match() {
+    let dir = Dir::new("quiet");
+    dir.write("a.txt", "foo\n").write("b.txt", "bar\n");
+    let run = dir.grep(&["-E", "-q", "foo", "a.txt", "b.txt"]);
+    assert_eq!((run.status, run.stdout.as_str()), (0, ""));
+    // Nothing after the match is opened.
+    let run = dir.grep(&["-E", "-q", "foo", "a.txt", "missing.txt"]);
+    assert_eq!((run.status, run.stderr.as_str()), (0, ""));
+    assert_eq!(dir.grep(&["-E", "-q", "baz", "a.txt", "b.txt"]).status, 1);
+    // Whatever else asks for output, and in whichever order.
+    for flags in [&["-q", "-c"], &["-q", "-l"], &["-c", "-q"], &["-l", "-q"]] {
+        let run = dir.grep(&[&flags[..], &["foo", "a.txt", "b.txt"]].concat());
+        assert_eq!((run.status, run.stdout.as_str()), (0, ""), "{flags:?}");
+    }
+}
+
+#[test]
+fn max_count_stops_each_input() {
+    let dir = Dir::new("max-count");
+    dir.write("a.txt", "foo 1\nbar\nfoo 2\nfoo 3\n")
+        .write("b.txt", "foo 4\nfoo 5\n");
+    let run = dir.grep(&["-E", "-m", "2", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "foo 1\nfoo 2\n");
+    // The limit is per input.
+    let run = dir.grep(&["-E", "-m", "1", "foo", "a.txt", "b.txt"]);
+    assert_eq!(run.stdout, "a.txt:foo 1\nb.txt:foo 4\n");
+    let run = dir.grep(&["-E", "-c", "-m", "2", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "2\n");
+    let run = dir.grep(&["-E", "-m", "0", "foo", "a.txt"]);
+    assert_eq!((run.status, run.stdout.as_str()), (1, ""));
+    let run = dir.grep(&["-E", "-m", "x", "foo", "a.txt"]);
+    assert_eq!(
+        run.stderr,
+        "grep: Invalid value for '-m'.: invalid digit found in string\n"
+    );
+}
+
+#[test]
+fn after_context_follows_each_selected_line() {
+    let dir = Dir::new("after-context");
+    dir.write("a.txt", "foo\n1\n2\n3\nfoo\n4\nfoo\n5\n")
+        .write("b.txt", "foo\n6\n");
+    let run = dir.grep(&["-E", "-n", "-A", "1", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "1:foo\n2-1\n--\n5:foo\n6-4\n7:foo\n8-5\n");
+    // `-m` still prints the context after the last line it allows.
+    let run = dir.grep(&["-E", "-m", "1", "-A", "2", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "foo\n1\n2\n");
+    // Groups from different inputs are separated too.
+    let run = dir.grep(&["-E", "-A", "1", "foo", "b.txt", "a.txt", "b.txt"]);
+    assert_eq!(
+        run.stdout,
+        "b.txt:foo\nb.txt-6\n--\na.txt:foo\na.txt-1\n--\na.txt:foo\na.txt-4\n\
+         a.txt:foo\na.txt-5\n--\nb.txt:foo\nb.txt-6\n"
+    );
+    // Context is left out with `-o` and `-c`.
+    let run = dir.grep(&["-E", "-o", "-A", "1", "fo", "b.txt"]);
+    assert_eq!(run.stdout, "fo\n");
+    assert_eq!(
+        dir.grep(&["-E", "-c", "-A", "1", "foo", "a.txt"]).stdout,
+        "3\n"
+    );
+}
+
+#[test]
+fn values_can_be_attached_to_options() {
+    let dir = Dir::new("attached-values");
+    dir.write("a.txt", "foo\n1\nfoo\n2\n");
+    for args in [
+        &["-E", "-A1", "-m1", "foo", "a.txt"][..],
+        &["-E", "-A", "1", "-m1", "foo", "a.txt"],
+        &["-E", "--after-context=1", "--max-count=1", "foo", "a.txt"],
+    ] {
+        assert_eq!(dir.grep(args).stdout, "foo\n1\n", "{args:?}");
+    }
+    let run = dir.grep(&["-E", "-nA1", "-m", "1", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "1:foo\n2-1\n");
+    let run = dir.grep(&["-E", "--max-count", "x", "foo", "a.txt"]);
+    assert!(run
+        .stderr
+        .starts_with("grep: Invalid value for '--max-count'."));
+}
+
+#[test]
+fn before_context_leads_each_selected_line() {
+    let dir = Dir::new("before-context");
+    dir.write("a.txt", "1\n2\n3\nfoo\n4\nfoo\n5\n6\n7\nfoo\n");
+    let run = dir.grep(&["-E", "-n", "-B", "2", "foo", "a.txt"]);
+    assert_eq!(
+        run.stdout,
+        "2-2\n3-3\n4:foo\n5-4\n6:foo\n--\n8-6\n9-7\n10:foo\n"
+    );
+    // Context that overlaps, before or after, is printed once.
+    let run = dir.grep(&["-E", "-B1", "--after-context=1", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "3\nfoo\n4\nfoo\n5\n--\n7\nfoo\n");
+    let run = dir.grep(&["-E", "--before-context=9", "-m", "1", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "1\n2\n3\nfoo\n");
+}
+
+#[test]
+fn context_goes_both_ways() {
+    let dir = Dir::new("context");
+    dir.write("a.txt", "1\n2\n3\nfoo\n4\n5\n6\n");
+    let run = dir.grep(&["-E", "-C", "1", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "3\nfoo\n4\n");
+    for args in [
+        &["-E", "-C1", "foo", "a.txt"][..],
+        &["-E", "--context=1", "foo", "a.txt"],
+        &["-E", "--context", "1", "foo", "a.txt"],
+    ] {
+        assert_eq!(dir.grep(args).stdout, "3\nfoo\n4\n", "{args:?}");
+    }
+    // Without a number it's 2 lines each side.
+    let run = dir.grep(&["-E", "-C", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "2\n3\nfoo\n4\n5\n");
+    let run = dir.grep(&["-E", "-nC", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "2-2\n3-3\n4:foo\n5-4\n6-5\n");
+    // `-A` and `-B` win for their own side, whichever comes first.
+    let run = dir.grep(&["-E", "-A", "0", "-C", "1", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "3\nfoo\n");
+    let run = dir.grep(&["-E", "-C", "1", "-B", "3", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "1\n2\n3\nfoo\n4\n");
+}
+
+#[test]
+fn group_separator_can_be_changed_or_left_out() {
+    let dir = Dir::new("group-separator");
+    dir.write("a.txt", "foo\n1\n2\nfoo\n")
+        .write("b.txt", "foo\n");
+    let run = dir.grep(&["-E", "-A", "1", "--group-separator", "==", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "foo\n1\n==\nfoo\n");
+    let run = dir.grep(&["-E", "-A1", "--group-separator=", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "foo\n1\n\nfoo\n");
+    let run = dir.grep(&["-E", "-A1", "--no-group-separator", "foo", "a.txt", "b.txt"]);
+    assert_eq!(run.stdout, "a.txt:foo\na.txt-1\na.txt:foo\nb.txt:foo\n");
+}
+
+#[test]
+fn color_highlights_matches_names_and_numbers() {
+    let dir = Dir::new("color");
+    dir.write("a.txt", "a foo b\n");
+    let run = dir.grep(&["-E", "--color=always", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "a \x1b[01;31m\x1b[Kfoo\x1b[m\x1b[K b\n");
+    let run = dir.grep(&["-E", "--color=always", "-n", "foo", "a.txt", "a.txt"]);
+    assert_eq!(
+        run.stdout.lines().next().unwrap(),
+        "\x1b[35m\x1b[Ka.txt\x1b[m\x1b[K\x1b[36m\x1b[K:\x1b[m\x1b[K\
+         \x1b[32m\x1b[K1\x1b[m\x1b[K\x1b[36m\x1b[K:\x1b[m\x1b[K\
+         a \x1b[01;31m\x1b[Kfoo\x1b[m\x1b[K b"
+    );
+    // Not a terminal, so `auto` and a bare `--color` leave it out.
+    assert_eq!(
+        dir.grep(&["-E", "--color", "foo", "a.txt"]).stdout,
+        "a foo b\n"
+    );
+    let run = dir.grep(&["-E", "--color=sometimes", "foo", "a.txt"]);
+    assert_eq!(
+        run.stderr,
+        "grep: Invalid value for '--color': 'sometimes'.\n"
+    );
+}
+
+#[test]
+fn grep_colors_sets_the_colors() {
+    let dir = Dir::new("grep-colors");
+    let colors = [("GREP_COLORS", "ms=4:sl=1:ne")];
+    let run = dir.grep_env(&["-E", "--color=always", "o"], "foo\n", &colors);
+    // The line's color isn't started again after a match that ends it.
+    assert_eq!(run.stdout, "\x1b[1mf\x1b[4mo\x1b[m\x1b[1m\x1b[4mo\x1b[m\n");
+    let colors = [("GREP_COLORS", "mt=:fn=")];
+    let run = dir.grep_env(&["-E", "--color=always", "o"], "foo\n", &colors);
+    assert_eq!(run.stdout, "foo\n");
+}
+
+#[test]
+fn word_selects_whole_words() {
+    let dir = Dir::new("word");
+    let run = dir.grep_stdin(&["-E", "-w", "foo"], "foo bar\nfoobar\nbar-foo\n");
+    assert_eq!((run.status, run.stdout.as_str()), (0, "foo bar\nbar-foo\n"));
+    let run = dir.grep_stdin(&["-E", "-w", "-o", "o+"], "foo oo\n");
+    assert_eq!(run.stdout, "oo\n");
+}
+
+#[test]
+fn word_only_looks_at_the_chars_around_the_match() {
+    let dir = Dir::new("word-edges");
+    let run = dir.grep_stdin(&["-w", "@"], "a @ b\na@b\n");
+    assert_eq!((run.status, run.stdout.as_str()), (0, "a @ b\n"));
+    let run = dir.grep_stdin(&["-w", "foo "], "foo bar\n");
+    assert_eq!((run.status, run.stdout.as_str()), (1, ""));
+}
+
+#[test]
+fn line_regexp_selects_whole_lines() {
+    let dir = Dir::new("line-regexp");
+    let run = dir.grep_stdin(&["-E", "-x", "foo|bar"], "foo\nfoo bar\nbar\n");
+    assert_eq!((run.status, run.stdout.as_str()), (0, "foo\nbar\n"));
+    assert_eq!(
+        dir.grep_stdin(&["-E", "-x", "-v", "foo"], "foo\nfoo bar\n")
+            .stdout,
+        "foo bar\n"
+    );
+}
+
+#[test]
+fn fixed_strings_take_each_line_of_the_pattern() {
+    let dir = Dir::new("fixed-strings");
+    let run = dir.grep_stdin(&["-E", "-F", "a.c"], "abc\na.c\n");
+    assert_eq!(run.stdout, "a.c\n");
+    let run = dir.grep_stdin(&["-E", "-F", "(x)\n[y]"], "(x)\nx\n[y]\n");
+    assert_eq!(run.stdout, "(x)\n[y]\n");
+    let run = dir.grep_stdin(&["-E", "-F", "-o", "-i", "ab\nB"], "xAbcb\n");
+    assert_eq!(run.stdout, "Ab\nb\n");
+}
+
+#[test]
+fn each_e_gives_a_pattern() {
+    let dir = Dir::new("e-patterns");
+    dir.write("a.txt", "foo\nbar\nbaz\n");
+    let run = dir.grep(&["-E", "-e", "foo", "-ebaz", "a.txt"]);
+    assert_eq!(run.stdout, "foo\nbaz\n");
+    // A newline splits a pattern too.
+    assert_eq!(
+        dir.grep(&["-E", "foo\nba(r)", "a.txt"]).stdout,
+        "foo\nbar\n"
+    );
+    let run = dir.grep(&["-E", "-e", "(a)\\1", "-e", "(b)\\1", "-"]);
+    assert_eq!(run.status, 1);
+    let run = dir.grep_stdin(&["-E", "-e", "(a)\\1", "-e", "(b)\\1", "-"], "bb\n");
+    assert_eq!(run.stdout, "bb\n");
+    let run = dir.grep(&["-E", "-e", "x", "--load-compiled", "r.bin", "a.txt"]);
+    assert_eq!(
+        run.stderr,
+        "grep: Can't use '-e' or '-f' with '--load-compiled'.\n"
+    );
+}
+
+#[test]
+fn f_reads_a_pattern_per_line() {
+    let dir = Dir::new("f-patterns");
+    dir.write("a.txt", "foo\nbar\nbaz\n")
+        .write("patterns", "foo\nz$\n")
+        .write("empty", "")
+        .write("blank", "\n");
+    let run = dir.grep(&["-E", "-f", "patterns", "-e", "bar", "a.txt"]);
+    assert_eq!(run.stdout, "foo\nbar\nbaz\n");
+    let run = dir.grep_stdin(&["-E", "-f", "-", "a.txt"], "ba\n");
+    assert_eq!(run.stdout, "bar\nbaz\n");
+    // An empty file matches nothing, and an empty line everything.
+    assert_eq!(dir.grep(&["-E", "-f", "empty", "a.txt"]).status, 1);
+    assert_eq!(
+        dir.grep(&["-E", "-fblank", "a.txt"]).stdout,
+        "foo\nbar\nbaz\n"
+    );
+    let run = dir.grep(&["-E", "-f", "missing", "a.txt"]);
+    assert!(run.stderr.starts_with("grep: reading missing: "));
+}
+
+#[test]
+fn include_and_exclude_pick_files_by_name() {
+    let dir = Dir::new("include");
+    dir.write("src/main.rs", "foo\n")
+        .write("src/lib.rs", "foo\n")
+        .write("web/app.js", "foo\n")
+        .write("web/app.min.js", "foo\n");
+    for args in [&["--include=*.rs"][..], &["--include", "*.rs"]] {
+        let run = dir.grep(&[&["-E", "-r"][..], args, &["foo", "."]].concat());
+        assert_eq!(
+            run.stdout, "./src/lib.rs:foo\n./src/main.rs:foo\n",
+            "{args:?}"
+        );
+    }
+    for args in [&["--exclude=*.min.js"][..], &["--exclude", "*.min.js"]] {
+        let run = dir.grep(&[&["-E", "-r"][..], args, &["foo", "web"]].concat());
+        assert_eq!(run.stdout, "web/app.js:foo\n", "{args:?}");
+    }
+    // A path given is searched whatever its name.
+    let run = dir.grep(&["-E", "--exclude=*.js", "foo", "web/app.js"]);
+    assert_eq!(run.stdout, "foo\n");
+    // Nothing left to search finds nothing.
+    let run = dir.grep(&["-E", "-r", "--include", "*.txt", "foo", "."]);
+    assert_eq!((run.status, run.stdout.as_str()), (1, ""));
+}
+
+#[test]
+fn exclude_dir_and_exclude_from_prune_the_walk() {
+    let dir = Dir::new("exclude-dir");
+    dir.write("src/a.rs", "foo\n")
+        .write("target/debug/a.rs", "foo\n")
+        .write("notes/a.rs.orig", "foo\n")
+        .write("globs", "*.orig\n\n");
+    for args in [
+        &["--exclude-dir=debug", "--exclude-from=globs"][..],
+        &["--exclude-dir", "debug", "--exclude-from", "globs"],
+    ] {
+        let run = dir.grep(&[&["-E", "-r"][..], args, &["foo", "src", "target", "notes"]].concat());
+        assert_eq!(run.stdout, "src/a.rs:foo\n", "{args:?}");
+    }
+    // A directory given is skipped too when it matches.
+    let run = dir.grep(&["-E", "-r", "--exclude-dir=target", "foo", "target"]);
+    assert_eq!((run.status, run.stdout.as_str()), (1, ""));
+    let run = dir.grep(&["-E", "-r", "--exclude-from", "missing", "foo", "src"]);
+    assert!(run.stderr.starts_with("grep: reading missing: "));
+}
+
+#[test]
+fn ignore_files_skip_what_they_match() {
+    let dir = Dir::new("ignore");
+    dir.write("repo/.git/HEAD", "")
+        .write("repo/.gitignore", "*.log\nbuild/\n/top.txt\n")
+        .write("repo/top.txt", "foo\n")
+        .write("repo/src/top.txt", "foo\n")
+        .write("repo/src/a.log", "foo\n")
+        .write("repo/src/keep.log", "foo\n")
+        .write("repo/src/.ignore", "!keep.log\n")
+        .write("repo/src/build/a.rs", "foo\n")
+        .write("repo/src/a.rs", "foo\n")
+        .write("config/git/ignore", "*.rs\n")
+        .write("plain/.gitignore", "a.txt\n")
+        .write("plain/a.txt", "foo\n");
+    let run = dir.grep(&["-E", "-r", "foo", "repo"]);
+    assert_eq!(
+        run.stdout,
+        "repo/src/a.rs:foo\nrepo/src/keep.log:foo\nrepo/src/top.txt:foo\n"
+    );
+    // Those above the root count inside the repository.
+    let run = dir.grep(&["-E", "-r", "foo", "repo/src"]);
+    assert_eq!(
+        run.stdout,
+        "repo/src/a.rs:foo\nrepo/src/keep.log:foo\nrepo/src/top.txt:foo\n"
+    );
+    // So do the global excludes.
+    let config = dir.0.join("config");
+    let vars = [("XDG_CONFIG_HOME", config.to_str().unwrap())];
+    let run = dir.grep_env(&["-E", "-r", "foo", "repo/src"], "", &vars);
+    assert_eq!(run.stdout, "repo/src/keep.log:foo\nrepo/src/top.txt:foo\n");
+    let run = dir.grep(&["-E", "-r", "--no-ignore", "foo", "repo/src"]);
+    assert_eq!(run.stdout.lines().count(), 5);
+    // A path given is searched all the same.
+    let run = dir.grep(&["-E", "-r", "foo", "repo/src/a.log"]);
+    assert_eq!(run.stdout, "repo/src/a.log:foo\n");
+    let run = dir.grep(&["-E", "-r", "foo", "plain"]);
+    assert_eq!((run.status, run.stdout.as_str()), (1, ""));
+}
+
+#[test]
+fn hidden_files_are_skipped_unless_asked_for() {
+    let dir = Dir::new("hidden");
+    dir.write("a.txt", "foo\n")
+        .write(".env", "foo\n")
+        .write(".config/b.txt", "foo\n");
+    let run = dir.grep(&["-E", "-r", "foo", "."]);
+    assert_eq!(run.stdout, "./a.txt:foo\n");
+    let run = dir.grep(&["-E", "-r", "--hidden", "foo", "."]);
+    assert_eq!(run.stdout, "./.config/b.txt:foo\n./.env:foo\n./a.txt:foo\n");
+    // A path given is searched whatever its name.
+    let run = dir.grep(&["-E", "-r", "foo", ".config"]);
+    assert_eq!(run.stdout, ".config/b.txt:foo\n");
+}
+
+#[test]
+fn unrestricted_lifts_a_filter_at_a_time() {
+    let dir = Dir::new("unrestricted");
+    dir.write(".gitignore", "ignored.txt\n")
+        .write("ignored.txt", "foo\n")
+        .write(".hidden", "foo\n")
+        .write("bin", "foo\0\n");
+    let run = dir.grep(&["-E", "-r", "foo", "."]);
+    assert_eq!(run.stdout, "");
+    let run = dir.grep(&["-E", "-r", "-u", "foo", "."]);
+    assert_eq!(run.stdout, "./ignored.txt:foo\n");
+    for args in [&["-uu"][..], &["-u", "--unrestricted"]] {
+        let run = dir.grep(&[&["-E", "-r"][..], args, &["foo", "."]].concat());
+        assert_eq!(run.stdout, "./.hidden:foo\n./ignored.txt:foo\n", "{args:?}");
+    }
+    let run = dir.grep(&["-E", "-r", "-uuu", "-c", "foo", "."]);
+    assert_eq!(
+        run.stdout,
+        "./.gitignore:0\n./.hidden:1\n./bin:1\n./ignored.txt:1\n"
+    );
+}
+
+#[test]
+fn json_describes_each_match() {
+    let dir = Dir::new("json");
+    dir.write("a.txt", "x\nfoo=1 bar=\"2\"\n");
+    let run = dir.grep(&["-E", "--json", "(foo|bar)=(\\d)?", "a.txt"]);
+    assert_eq!(
+        run.stdout,
+        concat!(
+            r#"{"file":"a.txt","line_number":2,"start":2,"end":7,"text":"foo=1","captures":[{"start":2,"end":5,"text":"foo"},{"start":6,"end":7,"text":"1"}]}"#,
+            "\n",
+            r#"{"file":"a.txt","line_number":2,"start":8,"end":12,"text":"bar=","captures":[{"start":8,"end":11,"text":"bar"},null]}"#,
+            "\n",
+        )
+    );
+    let run = dir.grep(&["-E", "--json", "-v", "foo", "a.txt"]);
+    assert_eq!(run.status, 2);
+    assert_eq!(run.stderr, "grep: Can't use '--json' with '-v'.\n");
+    // `-q` still prints nothing, before or after it.
+    for flags in [["-q", "--json"], ["--json", "-q"]] {
+        let run = dir.grep(&[&flags[..], &["foo", "a.txt"]].concat());
+        assert_eq!((run.status, run.stdout.as_str()), (0, ""), "{flags:?}");
+    }
+}
+
+#[test]
+fn stats_sum_up_the_search() {
+    let dir = Dir::new("stats");
+    dir.write("a.txt", "foo\nbar\nfoo\n")
+        .write("b.txt", "bar\n");
+    let run = dir.grep(&["-E", "--stats", "foo", "a.txt", "b.txt", "missing"]);
+    let (output, time) = run
+        .stdout
+        .rsplit_once('\n')
+        .unwrap()
+        .0
+        .rsplit_once('\n')
+        .unwrap();
+    assert_eq!(
+        output,
+        "a.txt:foo\na.txt:foo\n\n2 files searched\n1 files contained matches\n\
+         2 matched lines\n16 bytes searched"
+    );
+    assert!(time.ends_with(" seconds"), "{time}");
+    // `-l` reads no further than the first match.
+    let run = dir.grep(&["-E", "-l", "--stats", "foo", "a.txt"]);
+    assert!(
+        run.stdout.contains("\n4 bytes searched\n"),
+        "{}",
+        run.stdout
+    );
+}
+
+#[test]
+fn replace_rewrites_each_match() {
+    let dir = Dir::new("replace");
+    dir.write("a.txt", "x=1, y=2\nnone\n");
+    let pattern = "(?<key>[a-z])=(\\d)";
+    let run = dir.grep(&["-E", "--replace", "${2}:${key} $$", pattern, "a.txt"]);
+    assert_eq!(run.stdout, "1:x $, 2:y $\n");
+    let run = dir.grep(&["-E", "-o", "--replace=$0!", pattern, "a.txt"]);
+    assert_eq!(run.stdout, "x=1!\ny=2!\n");
+    let run = dir.grep(&["-E", "--replace", "$3", pattern, "a.txt"]);
+    assert_eq!(
+        (run.status, run.stderr.as_str()),
+        (2, "grep: No group 3 in the pattern.\n")
+    );
+    let run = dir.grep(&["-E", "--replace", "${value}", pattern, "a.txt"]);
+    assert_eq!(run.stderr, "grep: No group named 'value' in the pattern.\n");
+}
+
+#[test]
+fn syntax_options_go_anywhere_and_the_last_wins() {
+    let dir = Dir::new("syntax");
+    dir.write("a.txt", "a+b\naab\n");
+    assert_eq!(dir.grep(&["a+b", "a.txt"]).stdout, "a+b\n");
+    assert_eq!(dir.grep(&["-G", "a+b", "a.txt"]).stdout, "a+b\n");
+    assert_eq!(dir.grep(&["-n", "-E", "a+b", "a.txt"]).stdout, "2:aab\n");
+    assert_eq!(dir.grep(&["-E", "-G", "a+b", "a.txt"]).stdout, "a+b\n");
+    assert_eq!(dir.grep(&["-cP", "a\\+b", "a.txt"]).stdout, "1\n");
+    let run = dir.grep_stdin(&["-P", "\\d\\s\\D"], "1 x\n12\n");
+    assert_eq!(run.stdout, "1 x\n");
+    let run = dir.grep_stdin(&["-P", "[\\d]"], "5\nx\n");
+    assert_eq!((run.status, run.stdout.as_str()), (0, "5\n"));
+    let run = dir.grep_stdin(&["-P", "-o", "[^\\s\\]]+"], "a] b\n");
+    assert_eq!(run.stdout, "a\nb\n");
+}
+
+#[test]
+fn posix_rejects_extensions() {
+    let dir = Dir::new("posix");
+    let run = dir.grep_stdin(&["--posix", "-E", "-o", "a|ab"], "ab\n");
+    assert_eq!(run.stdout, "ab\n");
+    let run = dir.grep_stdin(&["--posix", "-E", "\\d"], "1\n");
+    assert_eq!(run.status, 2);
+    let run = dir.grep_stdin(&["--posix", "-P", "a"], "a\n");
+    assert_eq!(
+        (run.status, run.stderr.as_str()),
+        (2, "grep: Can't use '--posix' with '-P'.\n")
+    );
+}
+
+#[test]
+fn threads_keep_the_output_in_order() {
+    let dir = Dir::new("threads");
+    for i in 0..50 {
+        dir.write(&format!("d/{i:02}.txt"), format!("foo {i}\nbar\nfoo\n"));
+    }
+    dir.write("d/bin", "foo\0");
+    let sequential = dir.grep(&["-r", "-n", "-C", "1", "-j1", "foo", "d"]);
+    assert_eq!(sequential.status, 0);
+    assert!(sequential
+        .stderr
+        .contains("grep: d/bin: binary file matches"));
+    for threads in [&["-j4"][..], &["--threads=4"], &["-j", "4"]] {
+        let args = [&["-r", "-n", "-C", "1"][..], threads, &["foo", "d"]].concat();
+        assert_eq!(dir.grep(&args), sequential, "{threads:?}");
+    }
+    assert_eq!(dir.grep(&["-r", "-q", "-j", "4", "foo", "d"]).status, 0);
+}
+
+#[test]
+fn threads_split_big_files_the_same() {
+    let dir = Dir::new("split");
+    let line = "some words and then a number 12345 at the end\n";
+    let big = line.repeat((9 << 20) / line.len()) + "last 99\n";
+    dir.write("big.txt", big);
+    for args in [
+        &["-c", "9"][..],
+        &["-n", "-o", "-b", "9+"],
+        &["-v", "number"],
+    ] {
+        let run = |threads| dir.grep(&[&["-E", threads][..], args, &["big.txt"]].concat());
+        assert_eq!(run("-j4"), run("-j1"), "{args:?}");
+    }
+}
+
+#[test]
+fn threads_must_be_positive() {
+    let dir = Dir::new("threads-invalid");
+    for args in [&["-j0", "x"][..], &["--threads", "x", "x"]] {
+        let run = dir.grep(args);
+        assert_eq!(run.status, 2, "{args:?}");
+        assert!(
+            run.stderr.starts_with("grep: Invalid value for"),
+            "{args:?}"
+        );
+    }
+}
+
+#[test]
+fn debug_dumps_the_pattern_to_stderr() {
+    let dir = Dir::new("debug");
+    let run = dir.grep_stdin(&["-E", "--debug", "a+"], "xaa\n");
+    assert_eq!(run.stdout, "xaa\n");
+    assert!(
+        run.stderr.starts_with("tree:\n  OneOrMore\n"),
+        "{}",
+        run.stderr
+    );
+    assert!(run.stderr.contains("\nprogram:\n"), "{}", run.stderr);
+}
+
+#[test]
+fn trace_logs_each_step_to_stderr() {
+    let dir = Dir::new("trace");
+    let run = dir.grep_stdin(&["-E", "--trace", r"a(b|c)\1"], "xacc\n");
+    assert_eq!(run.stdout, "xacc\n");
+    assert!(
+        run.stderr.starts_with("trace: searching from 0\n"),
+        "{}",
+        run.stderr
+    );
+    assert!(
+        run.stderr.contains("Literal('a'): fails on 'x'\n"),
+        "{}",
+        run.stderr
+    );
+    assert!(
+        run.stderr.contains("trace: backtracking to "),
+        "{}",
+        run.stderr
+    );
+    assert!(
+        run.stderr.contains("Reference(1): consumes \"c\"\n"),
+        "{}",
+        run.stderr
+    );
+    assert!(run.stderr.ends_with("Match: a match\n"), "{}", run.stderr);
+    let run = dir.grep(&["-E", "--trace", "--load-compiled", "re", "a.txt"]);
+    assert_eq!(
+        run.stderr,
+        "grep: Can't use '--trace' with '--load-compiled'.\n"
+    );
+}
+
+#[test]
+fn check_validates_without_searching() {
+    let dir = Dir::new("check");
+    dir.write("patterns", "a+\n(b\nc\n*d\n");
+    let run = dir.grep(&["-E", "--check", "a+", "missing"]);
+    assert_eq!(
+        (run.status, run.stdout.as_str(), run.stderr.as_str()),
+        (0, "", "")
+    );
+    let run = dir.grep(&["-E", "--check", "-f", "patterns"]);
+    assert_eq!(run.status, 2);
+    let errors = run.stderr.lines().collect::<Vec<_>>();
+    assert_eq!(errors.len(), 2, "{}", run.stderr);
+    assert!(errors[0].starts_with("grep: '(b': "), "{}", run.stderr);
+    assert!(errors[1].starts_with("grep: '*d': "), "{}", run.stderr);
+    let run = dir.grep(&["-E", "--check", "--replace", "$1", "a"]);
+    assert_eq!(
+        (run.status, run.stderr.as_str()),
+        (2, "grep: No group 1 in the pattern.\n")
+    );
+    // Nothing is saved or benchmarked either.
+    dir.write("corpus/a.txt", "a\n");
+    let run = dir.grep(&[
+        "-E",
+        "--check",
+        "--save-compiled",
+        "saved",
+        "--bench",
+        "corpus",
+        "a",
+    ]);
+    assert_eq!(
+        (run.status, run.stdout.as_str(), run.stderr.as_str()),
+        (0, "", "")
+    );
+    assert!(!dir.0.join("saved").exists());
+}
+
+#[test]
+fn label_takes_its_value_either_way() {
+    let dir = Dir::new("label-either-way");
+    for args in [&["--label=in"][..], &["--label", "in"]] {
+        let run = dir.grep_stdin(&[&["-E", "-c"][..], args, &["a", "-", "-"]].concat(), "a\n");
+        assert_eq!(run.stdout, "in:1\nin:0\n", "{args:?}");
+    }
+}
+
+#[test]
+fn binary_files_say_whether_they_match() {
+    let dir = Dir::new("binary");
+    dir.write("bin", "foo\0\nfoo\nbar\n")
+        .write("late", "foo\nbar\0foo\nfoo\n")
+        .write("text.txt", "foo\n");
+    let run = dir.grep(&["-E", "foo", "bin"]);
+    assert_eq!(
+        (run.status, run.stdout.as_str(), run.stderr.as_str()),
+        (0, "", "grep: bin: binary file matches\n")
+    );
+    // A NUL anywhere in the first buffer makes the whole file binary.
+    let run = dir.grep(&["-E", "foo", "late"]);
+    assert_eq!(run.stdout, "");
+    assert_eq!(run.stderr, "grep: late: binary file matches\n");
+    // Lines before a NUL past the first buffer are printed as they come.
+    dir.write("later", format!("foo\n{}\0foo\n", "x\n".repeat(10_000)));
+    let run = dir.grep(&["-E", "foo", "later"]);
+    assert_eq!(run.stdout, "foo\n");
+    assert_eq!(run.stderr, "grep: later: binary file matches\n");
+    assert_eq!(dir.grep(&["-E", "baz", "bin"]).status, 1);
+    // The notices keep their place among the inputs.
+    let run = dir.grep(&["-E", "-c", "foo", "bin", "text.txt"]);
+    assert_eq!(
+        (run.stdout.as_str(), run.stderr.as_str()),
+        ("bin:2\ntext.txt:1\n", "")
+    );
+    let run = dir.grep(&["-E", "-l", "bar", "bin", "text.txt"]);
+    assert_eq!(run.stdout, "bin\n");
+    let run = dir.grep(&["-E", "-n", "-a", "bar", "bin"]);
+    assert_eq!((run.stdout.as_str(), run.stderr.as_str()), ("3:bar\n", ""));
+    let run = dir.grep(&["-E", "--binary-files", "text", "bar", "bin"]);
+    assert_eq!(run.stdout, "bar\n");
+    let run = dir.grep(&["-E", "-I", "foo", "bin", "text.txt"]);
+    assert_eq!(
+        (run.status, run.stdout.as_str(), run.stderr.as_str()),
+        (0, "text.txt:foo\n", "")
+    );
+    let run = dir.grep(&["-E", "--binary-files=without-match", "foo", "bin"]);
+    assert_eq!(run.status, 1);
+    let run = dir.grep(&["-E", "--binary-files=maybe", "foo", "bin"]);
+    assert_eq!(
+        run.stderr,
+        "grep: Invalid value for '--binary-files': 'maybe'.\n"
+    );
+}
+
+#[test]
+fn null_data_splits_lines_on_nul() {
+    let dir = Dir::new("null-data");
+    let run = dir.grep_stdin(&["-E", "-z", "-n", "b"], "a\nb\0c\0b\0");
+    assert_eq!((run.status, run.stdout.as_str()), (0, "1:a\nb\x003:b\0"));
+    // The NULs don't make it binary, and counts still end in a newline.
+    let run = dir.grep_stdin(&["-E", "--null-data", "-c", "^[ab]"], "a\0c\0b");
+    assert_eq!((run.stdout.as_str(), run.stderr.as_str()), ("2\n", ""));
+    let run = dir.grep_stdin(&["-E", "-z", "-A1", "a"], "a\0x\0y\0a\0");
+    assert_eq!(run.stdout, "a\0x\0--\na\0");
+}
+
+#[test]
+fn null_ends_file_names() {
+    let dir = Dir::new("null");
+    dir.write("a.txt", "foo\nbar\n").write("b.txt", "bar\n");
+    let run = dir.grep(&["-E", "-Z", "-l", "bar", "a.txt", "b.txt"]);
+    assert_eq!(run.stdout, "a.txt\0b.txt\0");
+    let run = dir.grep(&["-E", "--null", "-c", "foo", "a.txt", "b.txt"]);
+    assert_eq!(run.stdout, "a.txt\x001\nb.txt\x000\n");
+    let run = dir.grep(&["-E", "-Z", "-n", "-A1", "foo", "a.txt", "b.txt"]);
+    assert_eq!(run.stdout, "a.txt\x001:foo\na.txt\x002-bar\n");
+}
+
+#[test]
+fn byte_offsets_count_from_the_start_of_each_input() {
+    let dir = Dir::new("byte-offset");
+    dir.write("a.txt", "foo\nbar\nfoo bar\n");
+    let run = dir.grep(&["-E", "-b", "bar", "a.txt"]);
+    assert_eq!(run.stdout, "4:bar\n8:foo bar\n");
+    let run = dir.grep(&["-E", "-nb", "-o", "bar", "a.txt", "a.txt"]);
+    assert_eq!(run.stdout, "a.txt:2:4:bar\na.txt:3:12:bar\n".repeat(2));
+    let run = dir.grep(&["-E", "-b", "-B1", "^bar", "a.txt"]);
+    assert_eq!(run.stdout, "0-foo\n4:bar\n");
+}
+
+#[test]
+fn file_names_can_be_forced_on_or_off() {
+    let dir = Dir::new("with-filename");
+    dir.write("a.txt", "foo\n").write("b.txt", "foo\n");
+    assert_eq!(
+        dir.grep(&["-E", "-H", "foo", "a.txt"]).stdout,
+        "a.txt:foo\n"
+    );
+    let run = dir.grep(&["-E", "-h", "foo", "a.txt", "b.txt"]);
+    assert_eq!(run.stdout, "foo\nfoo\n");
+    // The last one given wins.
+    let run = dir.grep(&["-E", "-hH", "-c", "foo", "a.txt"]);
+    assert_eq!(run.stdout, "a.txt:1\n");
+    let run = dir.grep_stdin(&["-E", "-H", "foo"], "foo\n");
+    assert_eq!(run.stdout, "(standard input):foo\n");
+}
+
+#[test]
+fn no_messages_drops_unreadable_files() {
+    let dir = Dir::new("no-messages");
+    dir.write("a.txt", "foo\n");
+    let run = dir.grep(&["-E", "-s", "foo", "missing.txt", "a.txt"]);
+    assert_eq!(
+        (run.status, run.stdout.as_str(), run.stderr.as_str()),
+        (2, "a.txt:foo\n", "")
+    );
+    assert_eq!(dir.grep(&["-E", "-s", "foo", "missing.txt"]).stderr, "");
+    // Only errors reading are dropped.
+    let run = dir.grep_stdin(
+        &["-E", "-s", "--backtrack-limit", "100", r"(a|aa)*(a)\2c"],
+        &format!("{}bc\n", "a".repeat(30)),
+    );
+    assert!(run.stderr.starts_with("grep: searching line 1"), "{run:?}");
+}
+
+#[test]
+fn line_buffered_prints_each_line_as_it_comes() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_codecrafters-grep"))
+        .args(["-E", "--line-buffered", "foo"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    // Each line shows up while the input is still open.
+    for line in ["foo 1\n", "foo 2\n"] {
+        stdin.write_all(format!("bar\n{line}").as_bytes()).unwrap();
+        stdin.flush().unwrap();
+        let mut printed = String::new();
+        stdout.read_line(&mut printed).unwrap();
+        assert_eq!(printed, line);
+    }
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn errors_exit_with_2() {
+    let dir = Dir::new("exit-status");
+    dir.write("a.txt", "foo\n");
+    assert_eq!(dir.grep(&["-E", "foo", "a.txt"]).status, 0);
+    assert_eq!(dir.grep(&["-E", "bar", "a.txt"]).status, 1);
+    assert_eq!(dir.grep(&["-E", "bar", "a.txt", "missing.txt"]).status, 2);
+    assert_eq!(dir.grep(&["-E", "(", "a.txt"]).status, 2);
+    assert_eq!(dir.grep(&["-E", "-m"]).status, 2);
+    assert_eq!(dir.grep(&["-E"]).status, 2);
+    // `-q` needn't have read the input that failed.
+    assert_eq!(
+        dir.grep(&["-E", "-q", "foo", "missing.txt", "a.txt"])
+            .status,
+        0
+    );
+    assert_eq!(
+        dir.grep(&["-E", "-q", "bar", "missing.txt", "a.txt"])
+            .status,
+        2
+    );
+}